@@ -0,0 +1,1307 @@
+use crate::backfill::{Backfill, Progress};
+use crate::block_source::BlockSource;
+use crate::config::{Config, WriterConfig, DEFAULT_CONFIG};
+use crate::entry::Entry;
+use crate::metrics;
+use crate::migration::{self, schema_name};
+use crate::postgres::Postgres;
+use crate::sink::{FileFormat, FileSink, PostgresSink, Sink, SinkKind, SqliteSink};
+use crate::spool::Spool;
+use crate::sqlite::Sqlite;
+use crate::supervisor::Supervisor;
+use crate::topic::{epoch_entry, Connection, EntrySender, Settlements, Topic, TopicRegistry};
+use crate::util::crossbeam_channel_to_tokio_channel;
+use crate::util::ipinfo::set_ipinfo_io_token;
+use crate::util::jsonrpc::BatchRpcClient;
+use crate::util::miners::set_configured_miners;
+use crate::util::scripts::set_configured_scripts;
+use crate::verify::{Store, CHECKED_TABLES, SETTLED_TABLES};
+use ckb_testkit::Node;
+use clap::{crate_version, value_t_or_exit, App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const SHUTDOWN_DRAIN_IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Run CKBAnalyzer with the topics in `registry`
+pub async fn run(registry: TopicRegistry) {
+    let topic_names = registry.names();
+    let backfill_topic_names = registry.backfill_names();
+    let matches = clap_app(&topic_names, &backfill_topic_names).get_matches();
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        if config_matches.subcommand_name() == Some("print-default") {
+            print!("{}", DEFAULT_CONFIG);
+        }
+        return;
+    }
+
+    let _logger_guard = init_logger();
+    log::info!("CKBAnalyzer starting");
+
+    let mut config = match matches.value_of("config") {
+        Some(path) => {
+            Config::load(path).unwrap_or_else(|err| panic!("Invalid config file {}", err))
+        }
+        None => Config::default(),
+    };
+    override_config(&mut config, &matches);
+    set_configured_miners(config.miners.clone());
+    set_configured_scripts(config.scripts.clone());
+
+    if let ("migrate", Some(migrate_matches)) = matches.subcommand() {
+        migrate_command(&config, migrate_matches).await;
+        return;
+    }
+    if let ("backfill", Some(backfill_matches)) = matches.subcommand() {
+        backfill_command(config, &registry, backfill_matches).await;
+        return;
+    }
+    if let ("verify", Some(verify_matches)) = matches.subcommand() {
+        verify_command(config, &registry, verify_matches).await;
+        return;
+    }
+
+    check_rpc_url(&config);
+    if config.topics.is_empty() {
+        config.topics = topic_names.iter().map(ToString::to_string).collect();
+    }
+    let topics = config
+        .topics
+        .iter()
+        .map(|name| {
+            registry.get(name).unwrap_or_else(|| {
+                panic!(
+                    "Unknown topic \"{}\" in config \"topics\", possible topics: {:?}",
+                    name, topic_names
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let requires_subscription = topics
+        .iter()
+        .any(|topic| topic.connections().contains(&Connection::Subscription));
+    let subscription_addr = if !config.ckb.subscription_addr.is_empty() {
+        Some(config.ckb.subscription_addr.clone())
+    } else if requires_subscription {
+        panic!("Miss CKB subscription addr via neither --ckb-subscription-addr, environment variable \"CKB_SUBSCRIPTION_ADDR\" nor config \"ckb.subscription_addr\"");
+    } else {
+        None
+    };
+    log::info!("CKB CKB RPC: \"{}\"", config.ckb.rpc_url);
+    log::info!("CKB Node Subscription: {:?}", subscription_addr);
+    log::info!(
+        "Topics: {:?}",
+        topics.iter().map(|topic| topic.name()).collect::<Vec<_>>()
+    );
+
+    let sinks = topic_sinks(&config, &topics, &topic_names);
+
+    let node = Node::init_from_url(&config.ckb.rpc_url, PathBuf::new());
+    let chain_id = node.consensus().id.clone();
+    let network = schema_name(&chain_id);
+    log::info!("Network: \"{}\", chain id: \"{}\"", network, chain_id);
+    let pg = if sinks.contains(&SinkKind::Postgres) {
+        Some(open_postgres(&config, &network, &chain_id).await)
+    } else {
+        None
+    };
+    let sqlite = if sinks.contains(&SinkKind::Sqlite) {
+        Some(open_sqlite(&config, &network, &chain_id))
+    } else {
+        None
+    };
+    let metrics_addr = if config.metrics.listen_addr.is_empty() {
+        None
+    } else {
+        let metrics_addr = config
+            .metrics
+            .listen_addr
+            .parse::<SocketAddr>()
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Invalid metrics address \"{}\", error: {}",
+                    config.metrics.listen_addr, err
+                )
+            });
+        Some(metrics_addr)
+    };
+    set_ipinfo_io_token(config.ipinfo.token.clone());
+
+    // start a writing task for each used sink, then the topics
+    let config = Arc::new(config);
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let mut entry_senders = HashMap::new();
+    let mut sink_tasks = Vec::new();
+    for kind in SinkKind::ALL {
+        if !sinks.contains(kind) {
+            continue;
+        }
+        let sink = create_sink(*kind, &config, pg.as_ref(), sqlite.as_ref());
+        let data_dir = PathBuf::from(&config.writer.data_dir);
+        let spool = Spool::open(&data_dir, &kind.spool_filename()).unwrap_or_else(|err| {
+            panic!(
+                "failed to open spool under \"{}\", error: {}",
+                data_dir.display(),
+                err
+            )
+        });
+        let (entry_sender, entry_receiver) = crossbeam_channel_to_tokio_channel::channel::<
+            Vec<Entry>,
+        >(config.writer.channel_capacity);
+        sink_tasks.push(tokio::spawn(run_sink(
+            *kind,
+            sink,
+            spool,
+            entry_receiver,
+            entry_sender.clone(),
+            config.writer.clone(),
+            shutdown_receiver.clone(),
+        )));
+        entry_senders.insert(*kind, entry_sender);
+    }
+    let blocks = Arc::new(BlockSource::new(node.clone(), &config));
+    let supervisor = Supervisor::new(
+        node,
+        blocks,
+        pg,
+        sqlite,
+        entry_senders,
+        subscription_addr,
+        Arc::clone(&config),
+    );
+    for (topic, sink) in topics.into_iter().zip(sinks) {
+        supervisor.supervise(topic, sink);
+    }
+    if let Some(metrics_addr) = metrics_addr {
+        tokio::spawn(metrics::serve(metrics_addr));
+    }
+
+    shutdown_signal().await;
+
+    // Stop the topics, so that no more entries are produced, then let the sinks drain their
+    // channels and flush.
+    log::info!("CKBAnalyzer shutting down");
+    supervisor.shutdown().await;
+    drop(supervisor);
+    let _ = shutdown_sender.send(true);
+    let mut flushed = true;
+    for task in sink_tasks {
+        flushed &= task.await.unwrap_or(false);
+    }
+    if flushed {
+        log::info!("CKBAnalyzer shutdown");
+        ::std::process::exit(0);
+    } else {
+        log::error!("CKBAnalyzer shutdown, failed to flush the final entries");
+        ::std::process::exit(1);
+    }
+}
+
+fn check_rpc_url(config: &Config) {
+    if config.ckb.rpc_url.is_empty() {
+        panic!("Miss CKB Rpc url via neither --ckb-rpc-url, environment variable \"CKB_RPC_URL\" nor config \"ckb.rpc_url\"");
+    }
+    let _ = url::Url::parse(&config.ckb.rpc_url).map_err(|err| {
+        panic!(
+            "Invalid CKB RPC url, url: \"{}\", error: {:?}",
+            config.ckb.rpc_url, err
+        )
+    });
+}
+
+// Return the sink of each topic, `sink.topics` or `sink.default`
+fn topic_sinks(
+    config: &Config,
+    topics: &[Arc<dyn Topic>],
+    topic_names: &[&'static str],
+) -> Vec<SinkKind> {
+    for name in config.sink.topics.keys() {
+        if !topic_names.contains(&name.as_str()) {
+            panic!(
+                "Unknown topic \"{}\" in config \"sink.topics\", possible topics: {:?}",
+                name, topic_names
+            );
+        }
+    }
+    let sinks = topics
+        .iter()
+        .map(|topic| {
+            config
+                .sink
+                .topics
+                .get(topic.name())
+                .copied()
+                .unwrap_or(config.sink.default)
+        })
+        .collect::<Vec<_>>();
+    log::info!(
+        "Sinks: {:?}",
+        topics
+            .iter()
+            .zip(sinks.iter())
+            .map(|(topic, sink)| format!("{}={}", topic.name(), sink))
+            .collect::<Vec<_>>()
+    );
+    sinks
+}
+
+// Connect Postgres and migrate the schema. Refuse to start if the schema was migrated by a newer
+// CKBAnalyzer; keep going if Postgres is unavailable, the sink reconnects later.
+async fn open_postgres(config: &Config, network: &str, chain_id: &str) -> Arc<Postgres> {
+    let pg = Arc::new(Postgres::new(
+        pg_config(config),
+        network.to_string(),
+        chain_id.to_string(),
+    ));
+    match pg.client().await {
+        Ok(_) => {}
+        Err(err @ migration::Error::NewerSchema { .. }) => panic!("{}", err),
+        Err(err) => log::warn!("Postgres is unavailable at startup, error: {}", err),
+    }
+    pg
+}
+
+fn open_sqlite(config: &Config, network: &str, chain_id: &str) -> Arc<Sqlite> {
+    let sqlite_path = &config.sink.sqlite_path;
+    let sqlite = Sqlite::open(sqlite_path, network, chain_id).unwrap_or_else(|err| {
+        panic!(
+            "failed to open SQLite database \"{}\", error: {}",
+            sqlite_path, err
+        )
+    });
+    Arc::new(sqlite)
+}
+
+fn create_sink(
+    kind: SinkKind,
+    config: &Config,
+    pg: Option<&Arc<Postgres>>,
+    sqlite: Option<&Arc<Sqlite>>,
+) -> Box<dyn Sink> {
+    match kind {
+        SinkKind::Postgres => Box::new(PostgresSink::new(Arc::clone(
+            pg.expect("connect Postgres for the postgres sink"),
+        ))),
+        SinkKind::Sqlite => Box::new(SqliteSink::new(Arc::clone(
+            sqlite.expect("open SQLite for the sqlite sink"),
+        ))),
+        SinkKind::JsonLines | SinkKind::Csv => {
+            let format = FileFormat::from_kind(kind).expect("file sink");
+            let output_dir = &config.sink.output_dir;
+            let sink = FileSink::new(format, output_dir, config.sink.max_file_size())
+                .unwrap_or_else(|err| {
+                    panic!(
+                        "failed to open output directory \"{}\", error: {}",
+                        output_dir, err
+                    )
+                });
+            Box::new(sink)
+        }
+    }
+}
+
+// Resolve when SIGINT or SIGTERM is received
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate())
+        .unwrap_or_else(|err| panic!("failed to listen SIGTERM, error: {}", err));
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("received SIGINT"),
+        _ = sigterm.recv() => log::info!("received SIGTERM"),
+    }
+}
+
+// Batch the entries received from the topics and write them into the sink, until shutdown.
+//
+// On shutdown, drain the channel and flush the final entries. The channel is closed once all the
+// topics dropped their senders; a topic which does not stop in time still holds its sender, so
+// also stop draining when the channel becomes idle.
+//
+// Return false if some of the final entries were neither written nor spooled.
+async fn run_sink(
+    kind: SinkKind,
+    mut sink: Box<dyn Sink>,
+    mut spool: Spool,
+    mut entry_receiver: mpsc::Receiver<Vec<Entry>>,
+    // Only used for observing the channel depth, dropped before draining the channel
+    channel_probe: EntrySender,
+    writer_config: WriterConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> bool {
+    let label = kind.name();
+    let max_batch_size = writer_config.max_batch_size;
+    let max_batch_timeout = writer_config.max_batch_timeout();
+    let mut batch: Vec<Entry> = Vec::with_capacity(max_batch_size);
+    let mut last_batch_instant = Instant::now();
+    loop {
+        // Wake up at least every `max_batch_timeout`, so that spooled batches are replayed even
+        // though no new entries arrive
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            received = tokio::time::timeout(max_batch_timeout, entry_receiver.recv()) => {
+                match received {
+                    Ok(Some(entries)) => {
+                        log::debug!("new entries: {:?}", entries);
+                        batch.extend(entries);
+                    }
+                    Ok(None) => break,
+                    Err(_elapsed) => {}
+                }
+            }
+        }
+
+        metrics::CHANNEL_DEPTH
+            .with_label_values(&[label])
+            .set(channel_probe.len() as i64);
+        if batch.len() >= max_batch_size || last_batch_instant.elapsed() >= max_batch_timeout {
+            log::debug!("write {} entries into {}", batch.len(), label);
+            if !batch.is_empty() {
+                metrics::BATCH_SIZE
+                    .with_label_values(&[label])
+                    .observe(batch.len() as f64);
+            }
+            flush(sink.as_mut(), &mut spool, batch).await;
+            metrics::SPOOLED_BATCHES
+                .with_label_values(&[label])
+                .set(spool.len() as i64);
+            last_batch_instant = Instant::now();
+            batch = Vec::new();
+        }
+    }
+
+    drop(channel_probe);
+    let drain_deadline = Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+    while Instant::now() < drain_deadline {
+        match tokio::time::timeout(SHUTDOWN_DRAIN_IDLE_TIMEOUT, entry_receiver.recv()).await {
+            Ok(Some(entries)) => batch.extend(entries),
+            Ok(None) => break,
+            Err(_elapsed) => {
+                log::warn!("stop draining the idle channel of {}", label);
+                break;
+            }
+        }
+    }
+
+    log::info!("flush the final {} entries into {}", batch.len(), label);
+    flush(sink.as_mut(), &mut spool, batch).await
+}
+
+// Replay the spooled batches, then write the current batch. Batches are written in the order
+// they were produced, so the current batch is spooled as well while the spool is not drained.
+//
+// Return false if some entries of the current batch were neither written nor spooled.
+async fn flush(sink: &mut dyn Sink, spool: &mut Spool, batch: Vec<Entry>) -> bool {
+    if !spool.is_empty() {
+        replay(sink, spool).await;
+    }
+
+    if batch.is_empty() {
+        return true;
+    }
+    let unwritten = if spool.is_empty() {
+        sink.write(&batch).await
+    } else {
+        batch
+    };
+    if !unwritten.is_empty() {
+        log::warn!("spool {} unwritten entries", unwritten.len());
+        if let Err(err) = spool.append(&unwritten) {
+            log::error!(
+                "failed to spool {} entries, error: {}",
+                unwritten.len(),
+                err
+            );
+            return false;
+        }
+    }
+    true
+}
+
+// Replay the spooled batches in order, until the sink fails. While the sink is unreachable, only
+// the first spooled batch is read and written, as a probe of whether it is back.
+//
+// The batches rejected again while the sink is reachable are moved into the dead-letter file of
+// the spool, together with their cursors.
+async fn replay(sink: &mut dyn Sink, spool: &mut Spool) {
+    let n_spooled = spool.len();
+    loop {
+        let entries = match spool.front() {
+            Ok(Some(entries)) => entries,
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("failed to read spool, error: {}", err);
+                break;
+            }
+        };
+        let unwritten = sink.write(&entries).await;
+        let result = if unwritten.is_empty() {
+            spool.pop_front()
+        } else if sink.is_connected() {
+            log::error!(
+                "move {} spooled entries rejected by the sink into \"{}\"",
+                unwritten.len(),
+                spool.rejected_path().display()
+            );
+            spool.reject(&unwritten).and_then(|_| spool.pop_front())
+        } else {
+            if unwritten.len() < entries.len() {
+                if let Err(err) = spool.replace_front(&unwritten) {
+                    log::error!("failed to rewrite spool, error: {}", err);
+                }
+            }
+            break;
+        };
+        if let Err(err) = result {
+            log::error!("failed to update spool, error: {}", err);
+            break;
+        }
+    }
+    if spool.len() < n_spooled {
+        log::info!(
+            "replayed {} spooled batches, {} remaining",
+            n_spooled - spool.len(),
+            spool.len()
+        );
+    }
+}
+
+// Override the configuration file with command line flags and environment variables. Command
+// line flags take precedence over environment variables.
+fn override_config(config: &mut Config, matches: &ArgMatches) {
+    if let Some(rpc_url) = flag_or_env(matches, "ckb-rpc-url", &["CKB_RPC_URL"]) {
+        config.ckb.rpc_url = rpc_url;
+    }
+    if let Some(subscription_addr) =
+        flag_or_env(matches, "ckb-subscription-addr", &["CKB_SUBSCRIPTION_ADDR"])
+    {
+        config.ckb.subscription_addr = subscription_addr;
+    }
+    if let Some(data_dir) = flag_or_env(matches, "data-dir", &["CKB_ANALYZER_DATA_DIR"]) {
+        config.writer.data_dir = data_dir;
+    }
+    if let Some(metrics_addr) = flag_or_env(matches, "metrics-addr", &["CKB_ANALYZER_METRICS_ADDR"])
+    {
+        config.metrics.listen_addr = metrics_addr;
+    }
+    if let Some(topics) = matches.values_of("topics") {
+        config.topics = topics.map(ToString::to_string).collect();
+    }
+    if let Some(sinks) = matches.values_of("sink") {
+        for sink in sinks {
+            match parse_sink_flag(sink) {
+                Ok((None, kind)) => config.sink.default = kind,
+                Ok((Some(topic), kind)) => {
+                    config.sink.topics.insert(topic, kind);
+                }
+                Err(err) => panic!("Invalid --sink \"{}\", error: {}", sink, err),
+            }
+        }
+    }
+    if let Some(output_dir) = flag_or_env(matches, "output-dir", &["CKB_ANALYZER_OUTPUT_DIR"]) {
+        config.sink.output_dir = output_dir;
+    }
+    if let Some(sqlite_path) = flag_or_env(matches, "sqlite-path", &["CKB_ANALYZER_SQLITE_PATH"]) {
+        config.sink.sqlite_path = sqlite_path;
+    }
+
+    if let Some(host) = env_var(&["PGHOST", "POSTGRES_HOST"]) {
+        config.postgres.host = host;
+    }
+    if let Some(port) = env_var(&["PGPORT", "POSTGRES_PORT"]) {
+        config.postgres.port = port
+            .parse::<u16>()
+            .expect("invalid environment variable \"PGPORT\" or \"POSTGRES_PORT\"");
+    }
+    if let Some(database) = env_var(&["PGDATABASE", "POSTGRES_DB"]) {
+        config.postgres.database = database;
+    }
+    if let Some(user) = env_var(&["PGUSER", "POSTGRES_USER"]) {
+        config.postgres.user = user;
+    }
+    if let Some(password) = env_var(&["PGPASSWORD", "POSTGRES_PASSWORD"]) {
+        config.postgres.password = password;
+    }
+    if let Some(token) = env_var(&["IPINFO_IO_TOKEN"]) {
+        config.ipinfo.token = token;
+    }
+}
+
+// Parse `--sink <SINK>` or `--sink <TOPIC>=<SINK>`
+fn parse_sink_flag(value: &str) -> Result<(Option<String>, SinkKind), String> {
+    match value.find('=') {
+        None => Ok((None, value.parse()?)),
+        Some(i) => Ok((Some(value[..i].to_string()), value[i + 1..].parse()?)),
+    }
+}
+
+fn flag_or_env(matches: &ArgMatches, flag: &str, keys: &[&str]) -> Option<String> {
+    matches
+        .value_of(flag)
+        .map(ToString::to_string)
+        .or_else(|| env_var(keys))
+}
+
+// Return the value of the first set environment variable among `keys`
+fn env_var(keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .find_map(|key| env::var_os(key))
+        .map(|raw| raw.to_string_lossy().to_string())
+}
+
+fn init_logger() -> ckb_logger_service::LoggerInitGuard {
+    let filter = match env::var("RUST_LOG") {
+        Ok(filter) if filter.is_empty() => Some("info".to_string()),
+        Ok(filter) => Some(filter),
+        Err(_) => Some("info".to_string()),
+    };
+    let config = ckb_logger_config::Config {
+        filter,
+        color: false,
+        log_to_file: false,
+        log_to_stdout: true,
+        ..Default::default()
+    };
+    ckb_logger_service::init(None, config)
+        .unwrap_or_else(|err| panic!("failed to init the logger service, error: {}", err))
+}
+
+/// Migrate the schema of the chain id given by `--network`, or the chain id of the CKB node, then
+/// exit
+async fn migrate_command(config: &Config, matches: &ArgMatches<'_>) {
+    let chain_id = match matches.value_of("network") {
+        Some(chain_id) => chain_id.to_string(),
+        None => {
+            if config.ckb.rpc_url.is_empty() {
+                panic!("Miss the network via neither --network, --ckb-rpc-url, environment variable \"CKB_RPC_URL\" nor config \"ckb.rpc_url\"");
+            }
+            let node = Node::init_from_url(&config.ckb.rpc_url, PathBuf::new());
+            node.consensus().id.clone()
+        }
+    };
+    let network = schema_name(&chain_id);
+
+    let pg = Postgres::new(pg_config(config), network.clone(), chain_id);
+    match pg.client().await {
+        Ok(_) => {
+            log::info!(
+                "Schema \"{}\" is at version {}",
+                network,
+                migration::latest_version()
+            );
+        }
+        Err(err) => {
+            log::error!("failed to migrate schema \"{}\", error: {}", network, err);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// Backfill the block topics over `--from` to `--to`, then exit
+async fn backfill_command(mut config: Config, registry: &TopicRegistry, matches: &ArgMatches<'_>) {
+    check_rpc_url(&config);
+    if let Some(concurrency) = matches.value_of("concurrency") {
+        config.backfill.concurrency = concurrency
+            .parse::<usize>()
+            .ok()
+            .filter(|concurrency| *concurrency > 0)
+            .unwrap_or_else(|| panic!("Invalid --concurrency \"{}\"", concurrency));
+    }
+    let topic_names = registry.names();
+    let topics = match matches.values_of("topics") {
+        Some(names) => names
+            .map(|name| registry.get(name).expect("checked by clap"))
+            .collect::<Vec<_>>(),
+        None => registry
+            .backfill_names()
+            .into_iter()
+            .map(|name| registry.get(name).expect("registered"))
+            .collect(),
+    };
+    let sinks = topic_sinks(&config, &topics, &topic_names);
+
+    let node = Node::init_from_url(&config.ckb.rpc_url, PathBuf::new());
+    let chain_id = node.consensus().id.clone();
+    let network = schema_name(&chain_id);
+    log::info!("Network: \"{}\", chain id: \"{}\"", network, chain_id);
+    let from = value_t_or_exit!(matches, "from", u64).max(1);
+    let confirmed_number = node
+        .get_tip_block_number()
+        .saturating_sub(config.chain_crawler.block_confirmation);
+    let to = match matches.value_of("to") {
+        Some(_) => {
+            let to = value_t_or_exit!(matches, "to", u64);
+            if to > confirmed_number {
+                log::warn!(
+                    "--to #{} is beyond the confirmed tip, backfill to #{}",
+                    to,
+                    confirmed_number
+                );
+            }
+            to.min(confirmed_number)
+        }
+        None => confirmed_number,
+    };
+    if from > to {
+        log::info!("Nothing to backfill, #{} is beyond #{}", from, to);
+        return;
+    }
+
+    let pg = if sinks.contains(&SinkKind::Postgres) {
+        Some(open_postgres(&config, &network, &chain_id).await)
+    } else {
+        None
+    };
+    let sqlite = if sinks.contains(&SinkKind::Sqlite) {
+        Some(open_sqlite(&config, &network, &chain_id))
+    } else {
+        None
+    };
+    let mut sink_map = HashMap::new();
+    for kind in sinks.iter() {
+        sink_map
+            .entry(*kind)
+            .or_insert_with(|| create_sink(*kind, &config, pg.as_ref(), sqlite.as_ref()));
+    }
+    let data_dir = PathBuf::from(&config.writer.data_dir);
+    let progress = if matches.is_present("redo") {
+        // Neither skip nor record the chunks, the recorded ones were written by the older rules
+        log::info!(
+            "Backfill #{} to #{} regardless of the completed chunks",
+            from,
+            to
+        );
+        Progress::ephemeral()
+    } else {
+        Progress::open(&data_dir).unwrap_or_else(|err| {
+            panic!(
+                "failed to open backfill progress under \"{}\", error: {}",
+                data_dir.display(),
+                err
+            )
+        })
+    };
+    let blocks = Arc::new(BlockSource::new(node, &config));
+    let backfill = Backfill::new(
+        network,
+        &config.ckb.rpc_url,
+        blocks,
+        topics.into_iter().zip(sinks).collect(),
+        sink_map,
+        progress,
+        config.backfill.clone(),
+        config.writer.max_batch_size,
+    );
+
+    // The completed chunks are recorded, an interrupted backfill resumes from them when run again
+    tokio::select! {
+        n_failed = backfill.run(from, to) => {
+            if n_failed == 0 {
+                log::info!("Backfilled #{} to #{}", from, to);
+            } else {
+                log::error!(
+                    "Backfill #{} to #{}, gave up {} chunks, run again to retry them",
+                    from,
+                    to,
+                    n_failed
+                );
+                ::std::process::exit(1);
+            }
+        }
+        _ = shutdown_signal() => {
+            log::info!("Backfill interrupted, run again to resume");
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// Verify the block and epoch tables, and re-crawl the missing or orphaned rows with `--repair`,
+/// then exit
+async fn verify_command(config: Config, registry: &TopicRegistry, matches: &ArgMatches<'_>) {
+    check_rpc_url(&config);
+    let parse_number = |flag: &str| {
+        matches.value_of(flag).map(|value| {
+            value
+                .parse::<u64>()
+                .unwrap_or_else(|err| panic!("Invalid --{} \"{}\", error: {}", flag, value, err))
+        })
+    };
+    let from = parse_number("from").map(|from| from.max(1));
+    let to = parse_number("to");
+    let repair = matches.is_present("repair");
+    let tables = match matches.values_of("topics") {
+        Some(names) => {
+            let names = names.collect::<Vec<_>>();
+            CHECKED_TABLES
+                .iter()
+                .filter(|table| names.contains(&table.topic))
+                .collect::<Vec<_>>()
+        }
+        None => CHECKED_TABLES.iter().collect(),
+    };
+    let topic_names = registry.names();
+    let topics = tables
+        .iter()
+        .map(|table| {
+            registry
+                .get(table.topic)
+                .unwrap_or_else(|| panic!("topic \"{}\" is not registered", table.topic))
+        })
+        .collect::<Vec<_>>();
+    let sinks = topic_sinks(&config, &topics, &topic_names);
+
+    let node = Node::init_from_url(&config.ckb.rpc_url, PathBuf::new());
+    let chain_id = node.consensus().id.clone();
+    let network = schema_name(&chain_id);
+    log::info!("Network: \"{}\", chain id: \"{}\"", network, chain_id);
+    let pg = if sinks.contains(&SinkKind::Postgres) {
+        Some(open_postgres(&config, &network, &chain_id).await)
+    } else {
+        None
+    };
+    let sqlite = if sinks.contains(&SinkKind::Sqlite) {
+        Some(open_sqlite(&config, &network, &chain_id))
+    } else {
+        None
+    };
+    let rpc = BatchRpcClient::new(&config.ckb.rpc_url);
+
+    let mut reports = Vec::new();
+    for ((table, topic), sink) in tables.into_iter().zip(topics).zip(sinks) {
+        let store = match sink {
+            SinkKind::Postgres => {
+                let client = pg
+                    .as_ref()
+                    .expect("connect Postgres for the postgres sink")
+                    .client()
+                    .await
+                    .unwrap_or_else(|err| panic!("failed to connect Postgres, error: {}", err));
+                Store::Postgres(client)
+            }
+            SinkKind::Sqlite => Store::Sqlite(Arc::clone(
+                sqlite.as_ref().expect("open SQLite for the sqlite sink"),
+            )),
+            SinkKind::JsonLines | SinkKind::Csv => {
+                log::warn!("Skip table \"{}\" in the {} sink", table.table, sink);
+                continue;
+            }
+        };
+        let (from, to) = if table.is_epoch {
+            (None, None)
+        } else {
+            (from, to)
+        };
+        let mut report = store
+            .find_gaps(&network, table, from, to)
+            .await
+            .unwrap_or_else(|err| panic!("failed to verify \"{}\", error: {}", table.table, err));
+        if let (Some((from, to)), "block") = (report.range, table.table) {
+            report.mismatched = store
+                .find_mismatched_blocks(&network, &rpc, config.backfill.batch_size, from, to)
+                .await
+                .unwrap_or_else(|err| panic!("failed to verify block hashes, {}", err));
+        }
+        println!("{}", report);
+        reports.push((table, topic, sink, store, report));
+    }
+    if reports.iter().all(|(_, _, _, _, report)| report.is_ok()) {
+        return;
+    }
+    if !repair {
+        ::std::process::exit(1);
+    }
+
+    // The orphaned rows of the blocks not on the canonical chain are deleted from all the block
+    // tables, then re-crawled with the missing ones. The entries settled across blocks are
+    // settled again rather than re-crawled.
+    let mismatched = reports
+        .iter()
+        .flat_map(|(_, _, _, _, report)| report.mismatched.clone())
+        .collect::<Vec<_>>();
+    let blocks = Arc::new(BlockSource::new(node.clone(), &config));
+    let mut n_failed = 0;
+    for (table, topic, sink, store, report) in reports {
+        if table.is_epoch {
+            let mut sink = create_sink(sink, &config, pg.as_ref(), sqlite.as_ref());
+            for number in report.gaps.iter().flat_map(|(from, to)| *from..=*to) {
+                let entries = match epoch_entry(&node, &blocks, Some(&store), number).await {
+                    Some(entry) => vec![entry.into()],
+                    None => Vec::new(),
+                };
+                if entries.is_empty() || !sink.write(&entries).await.is_empty() {
+                    log::error!("failed to repair epoch {}", number);
+                    n_failed += 1;
+                }
+            }
+            continue;
+        }
+
+        if !mismatched.is_empty() {
+            store
+                .delete_rows(&network, table, &mismatched)
+                .await
+                .unwrap_or_else(|err| {
+                    panic!(
+                        "failed to delete orphaned rows of \"{}\", error: {}",
+                        table.table, err
+                    )
+                });
+        }
+        let ranges = report
+            .gaps
+            .iter()
+            .copied()
+            .chain(mismatched.iter().map(|number| (*number, *number)))
+            .collect::<Vec<_>>();
+        let mut sink_map = HashMap::new();
+        sink_map.insert(
+            sink,
+            create_sink(sink, &config, pg.as_ref(), sqlite.as_ref()),
+        );
+        // Re-crawl regardless of the recorded backfill progress
+        let backfill = Backfill::new(
+            network.clone(),
+            &config.ckb.rpc_url,
+            Arc::clone(&blocks),
+            vec![(topic, sink)],
+            sink_map,
+            Progress::ephemeral(),
+            config.backfill.clone(),
+            config.writer.max_batch_size,
+        );
+        for (from, to) in ranges.iter() {
+            n_failed += backfill.run(*from, *to).await;
+        }
+        if table.table == "block" {
+            let mut sink = create_sink(sink, &config, pg.as_ref(), sqlite.as_ref());
+            n_failed += resettle(
+                &node,
+                &blocks,
+                &store,
+                &mut sink,
+                &network,
+                &ranges,
+                config.writer.max_batch_size,
+            )
+            .await;
+        }
+    }
+    if n_failed == 0 {
+        log::info!("Repaired all the missing and orphaned rows");
+    } else {
+        log::error!(
+            "Repair failed {} chunks or epochs, run again to retry them",
+            n_failed
+        );
+        ::std::process::exit(1);
+    }
+}
+
+// Settle the repaired blocks again, and the following blocks whose settlements depend on them.
+// Return the number of ranges failed to settle.
+async fn resettle(
+    node: &Node,
+    blocks: &Arc<BlockSource>,
+    store: &Store,
+    sink: &mut Box<dyn Sink>,
+    network: &str,
+    ranges: &[(u64, u64)],
+    max_batch_size: usize,
+) -> usize {
+    let mut settlements = Settlements::new(node.clone(), Arc::clone(blocks));
+    let tip_number = blocks.tip_number().await;
+    let mut ranges = ranges
+        .iter()
+        .map(|(from, to)| (*from, (to + settlements.horizon()).min(tip_number)))
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (from, to) in ranges {
+        match merged.last_mut() {
+            Some((_, last_to)) if from <= *last_to + 1 => *last_to = to.max(*last_to),
+            _ => merged.push((from, to)),
+        }
+    }
+
+    let mut n_failed = 0;
+    for (from, to) in merged {
+        for (table, column) in SETTLED_TABLES.iter() {
+            store
+                .delete_range(network, table, column, from, to)
+                .await
+                .unwrap_or_else(|err| {
+                    panic!(
+                        "failed to delete the settled rows of \"{}\", error: {}",
+                        table, err
+                    )
+                });
+        }
+        settlements.restore(from).await;
+        let mut entries = Vec::new();
+        let mut failed = false;
+        for number in from..=to {
+            let block = blocks.get_block(number).await;
+            entries.extend(settlements.settle(&block).await);
+            if entries.len() >= max_batch_size || number == to {
+                failed |= !sink.write(&entries).await.is_empty();
+                entries.clear();
+            }
+        }
+        if failed {
+            log::error!("failed to settle blocks #{} to #{}", from, to);
+            n_failed += 1;
+        } else {
+            log::info!("Settled blocks #{} to #{}", from, to);
+        }
+    }
+    n_failed
+}
+
+fn pg_config(config: &Config) -> tokio_postgres::Config {
+    let postgres = &config.postgres;
+    if postgres.database.is_empty() {
+        panic!("requires environment variable \"PGDATABASE\", \"POSTGRES_DB\" or config \"postgres.database\"");
+    }
+    if postgres.user.is_empty() {
+        panic!("requires environment variable \"PGUSER\", \"POSTGRES_USER\" or config \"postgres.user\"");
+    }
+    if postgres.password.is_empty() {
+        panic!("requires environment variable \"PGPASSWORD\", \"POSTGRES_PASSWORD\" or config \"postgres.password\"");
+    }
+    let mut pg_config = tokio_postgres::Config::new();
+    pg_config
+        .host(&postgres.host)
+        .port(postgres.port)
+        .dbname(&postgres.database)
+        .user(&postgres.user)
+        .password(&postgres.password)
+        .application_name("CKBAnalyzer");
+    pg_config
+}
+
+pub fn clap_app<'a>(possible_topics: &'a [&'a str], backfill_topics: &'a [&'a str]) -> App<'a, 'a> {
+    let verify_topics = CHECKED_TABLES
+        .iter()
+        .map(|checked| checked.topic)
+        .collect::<Vec<_>>();
+    App::new("ckb-analyzer")
+        .version(crate_version!())
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILEPATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("envfile")
+                .long("envfile")
+                .value_name("FILEPATH")
+                .required(false)
+                .takes_value(true)
+                .validator(|filepath| {
+                    dotenv::from_path(&filepath)
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("ckb-rpc-url")
+                .long("ckb-rpc-url")
+                .value_name("URL")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ckb-subscription-addr")
+                .long("ckb-subscription-addr")
+                .value_name("HOST:PORT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .value_name("HOST:PORT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("topics")
+                .long("topics")
+                .value_name("TOPIC")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .possible_values(possible_topics),
+        )
+        .arg(
+            Arg::with_name("sink")
+                .long("sink")
+                .value_name("[TOPIC=]SINK")
+                .help("Write the topics, or the given topic, into postgres, sqlite, jsonl or csv")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .validator(|value| parse_sink_flag(&value).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sqlite-path")
+                .long("sqlite-path")
+                .value_name("FILEPATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Configuration file utilities")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("print-default")
+                        .about("Print the default configuration file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Migrate the database schema to the latest version, then exit")
+                .arg(
+                    Arg::with_name("network")
+                        .long("network")
+                        .value_name("CHAIN_ID")
+                        .help("The chain id whose schema to migrate, defaults to the chain id of the CKB node")
+                        .required(false)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Backfill the block topics over a block range, then exit")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("NUMBER")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("NUMBER")
+                        .help("Defaults to the tip minus chain_crawler.block_confirmation")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("topics")
+                        .long("topics")
+                        .value_name("TOPIC")
+                        .help("Defaults to all the topics which can be backfilled")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .possible_values(backfill_topics),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .long("concurrency")
+                        .value_name("CHUNKS")
+                        .help("The number of chunks backfilled concurrently")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("redo").long("redo").help(
+                        "Backfill the completed chunks again, e.g. to correct the rows written before a schema fix",
+                    ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about(
+                    "Report the missing and orphaned rows of the block and epoch tables, then exit",
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("NUMBER")
+                        .help("Defaults to the first stored block of each table")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("NUMBER")
+                        .help("Defaults to the last stored block of each table")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("topics")
+                        .long("topics")
+                        .value_name("TOPIC")
+                        .help("Defaults to all the topics which can be verified")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .possible_values(&verify_topics),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Re-crawl the missing blocks and epochs, and the orphaned blocks"),
+                ),
+        )
+}
+
+#[test]
+fn test_flush_replays_spool() {
+    use crate::entry::RetentionTransaction;
+    use futures::future::{BoxFuture, FutureExt};
+
+    // Apply up to `capacity` entries, then become unreachable and leave the rest unwritten. The
+    // entries of `rejected` are left unwritten while reachable.
+    struct FlakySink {
+        capacity: usize,
+        rejected: Vec<String>,
+        applied: Vec<String>,
+    }
+    impl Sink for FlakySink {
+        fn write<'a>(&'a mut self, entries: &'a [Entry]) -> BoxFuture<'a, Vec<Entry>> {
+            let mut unwritten = Vec::new();
+            for (i, entry) in entries.iter().enumerate() {
+                if self.capacity == 0 {
+                    unwritten.extend_from_slice(&entries[i..]);
+                    break;
+                }
+                if let Entry::RetentionTransaction(transaction) = entry {
+                    if self.rejected.contains(&transaction.hash) {
+                        unwritten.push(entry.clone());
+                        continue;
+                    }
+                    self.applied.push(transaction.hash.clone());
+                }
+                self.capacity -= 1;
+            }
+            Box::pin(async move { unwritten })
+        }
+
+        fn is_connected(&self) -> bool {
+            self.capacity > 0
+        }
+    }
+    let batch = |hashes: &[&str]| -> Vec<Entry> {
+        hashes
+            .iter()
+            .map(|hash| {
+                RetentionTransaction {
+                    network: "ckb_dev".to_string(),
+                    time: chrono::NaiveDateTime::from_timestamp(0, 0),
+                    hash: hash.to_string(),
+                }
+                .into()
+            })
+            .collect()
+    };
+
+    let data_dir = env::temp_dir().join(format!("ckb-analyzer-spool-{}", std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&data_dir);
+    let mut sink = FlakySink {
+        capacity: 3,
+        rejected: Vec::new(),
+        applied: Vec::new(),
+    };
+    let mut spool = Spool::open(&data_dir, "test.jsonl").unwrap();
+    assert!(flush(&mut sink, &mut spool, batch(&["1"]))
+        .now_or_never()
+        .unwrap());
+
+    // The sink fails in the middle of a batch, the unwritten entries are spooled, and the
+    // following batch is spooled after them
+    assert!(flush(&mut sink, &mut spool, batch(&["2", "3", "4"]))
+        .now_or_never()
+        .unwrap());
+    assert!(flush(&mut sink, &mut spool, batch(&["5"]))
+        .now_or_never()
+        .unwrap());
+    assert_eq!(spool.len(), 2);
+    assert_eq!(sink.applied, vec!["1", "2", "3"]);
+
+    // After restarting, the spooled batches are replayed before the new one
+    drop(spool);
+    let mut spool = Spool::open(&data_dir, "test.jsonl").unwrap();
+    assert_eq!(spool.len(), 2);
+    let mut sink = FlakySink {
+        capacity: 100,
+        rejected: Vec::new(),
+        applied: sink.applied,
+    };
+    assert!(flush(&mut sink, &mut spool, batch(&["6"]))
+        .now_or_never()
+        .unwrap());
+    assert!(spool.is_empty());
+    assert_eq!(sink.applied, vec!["1", "2", "3", "4", "5", "6"]);
+
+    // Nothing is replayed twice
+    drop(spool);
+    let mut spool = Spool::open(&data_dir, "test.jsonl").unwrap();
+    assert!(spool.is_empty());
+    assert!(flush(&mut sink, &mut spool, Vec::new())
+        .now_or_never()
+        .unwrap());
+    assert_eq!(sink.applied.len(), 6);
+
+    // A line truncated by a crash is removed, and does not merge with the next batch
+    let spool_path = data_dir.join("test.jsonl");
+    ::std::fs::write(&spool_path, "[{\"RetentionTransaction\"").unwrap();
+    let mut spool = Spool::open(&data_dir, "test.jsonl").unwrap();
+    assert!(spool.is_empty());
+    let mut sink = FlakySink {
+        capacity: 0,
+        rejected: vec!["8".to_string()],
+        applied: sink.applied,
+    };
+    for hashes in [&["7", "8"][..], &["9"][..]].iter() {
+        assert!(flush(&mut sink, &mut spool, batch(hashes))
+            .now_or_never()
+            .unwrap());
+    }
+    drop(spool);
+    let mut spool = Spool::open(&data_dir, "test.jsonl").unwrap();
+    assert_eq!(spool.len(), 2);
+
+    // The entries rejected while the sink is reachable are moved into the dead-letter file
+    sink.capacity = 100;
+    assert!(flush(&mut sink, &mut spool, Vec::new())
+        .now_or_never()
+        .unwrap());
+    assert!(spool.is_empty());
+    assert_eq!(sink.applied[6..], ["7", "9"]);
+    let rejected = ::std::fs::read_to_string(spool.rejected_path()).unwrap();
+    assert_eq!(rejected.lines().count(), 1);
+    assert!(rejected.contains("\"8\""));
+    let _ = ::std::fs::remove_dir_all(&data_dir);
+}
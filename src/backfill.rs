@@ -0,0 +1,407 @@
+//! Backfill the block topics over an explicit block range.
+//!
+//! The range is split into chunks of `backfill.chunk_size` blocks, which are backfilled
+//! concurrently. The blocks of a chunk are fetched via batch JSON-RPC requests, analyzed by the
+//! [`BlockAnalyzer`] of each topic, then written directly into the topic's sink. The capacities of
+//! the cells spent by a chunk are resolved once per chunk, for the analyzers needing them.
+//!
+//! Once a chunk of a topic is written, it is recorded in `backfill.jsonl` under the data
+//! directory, and skipped when backfilling again. A chunk interrupted midway is backfilled again
+//! from its start; the Postgres and SQLite sinks ignore the rows already written, while the file
+//! sinks may contain duplicated rows.
+//!
+//! [`BlockAnalyzer`]: crate::topic::BlockAnalyzer
+
+use crate::block_source::BlockSource;
+use crate::ckb_types::core::{BlockNumber, BlockView};
+use crate::config::BackfillConfig;
+use crate::entry::Entry;
+use crate::sink::{Sink, SinkKind};
+use crate::topic::{spent_capacities, Topic};
+use crate::util::jsonrpc::BatchRpcClient;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const PROGRESS_FILENAME: &str = "backfill.jsonl";
+const MAX_CHUNK_ATTEMPTS: u64 = 5;
+const MAX_WRITE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A backfilled chunk of a topic, both ends inclusive
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ChunkRecord {
+    network: String,
+    topic: String,
+    from: BlockNumber,
+    to: BlockNumber,
+}
+
+/// Progress is an append-only file recording the backfilled chunks, one JSON object per line.
+pub struct Progress {
+    // Not persisted if `None`
+    path: Option<PathBuf>,
+    records: Vec<ChunkRecord>,
+}
+
+impl Progress {
+    /// Open the progress file under `data_dir`, creating the directory if it does not exist
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> io::Result<Self> {
+        fs::create_dir_all(data_dir.as_ref())?;
+        let path = data_dir.as_ref().join(PROGRESS_FILENAME);
+        let file = match File::open(&path) {
+            Ok(file) => Some(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let mut records = Vec::new();
+        for line in file
+            .into_iter()
+            .flat_map(|file| BufReader::new(file).lines())
+        {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            // A truncated line is left by a crash during appending
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(err) => log::error!(
+                    "skip broken line of backfill progress \"{}\", error: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        Ok(Self {
+            path: Some(path),
+            records,
+        })
+    }
+
+    /// A progress which is neither loaded nor persisted, so that every chunk is backfilled
+    pub fn ephemeral() -> Self {
+        Self {
+            path: None,
+            records: Vec::new(),
+        }
+    }
+
+    /// Return whether the blocks `[from, to]` of `topic` were all backfilled
+    pub fn is_completed(
+        &self,
+        network: &str,
+        topic: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> bool {
+        let mut ranges = self
+            .records
+            .iter()
+            .filter(|record| record.network == network && record.topic == topic)
+            .map(|record| (record.from, record.to))
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        let mut next = from;
+        for (start, end) in ranges {
+            if start > next {
+                break;
+            }
+            if end >= next {
+                next = end + 1;
+            }
+        }
+        next > to
+    }
+
+    /// Record the backfilled blocks `[from, to]` of `topic`, and sync it to disk
+    pub fn record(
+        &mut self,
+        network: &str,
+        topic: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> io::Result<()> {
+        let record = ChunkRecord {
+            network: network.to_string(),
+            topic: topic.to_string(),
+            from,
+            to,
+        };
+        if let Some(path) = self.path.as_ref() {
+            let mut line = serde_json::to_string(&record)?;
+            line.push('\n');
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(line.as_bytes())?;
+            file.sync_data()?;
+        }
+        self.records.push(record);
+        Ok(())
+    }
+}
+
+/// Backfill backfills the topics, each writing into its sink.
+pub struct Backfill {
+    network: String,
+    rpc: BatchRpcClient,
+    // Resolves the capacities of the spent cells
+    blocks: Arc<BlockSource>,
+    topics: Vec<(Arc<dyn Topic>, SinkKind)>,
+    sinks: HashMap<SinkKind, Mutex<Box<dyn Sink>>>,
+    progress: ::std::sync::Mutex<Progress>,
+    config: BackfillConfig,
+    max_batch_size: usize,
+}
+
+impl Backfill {
+    /// Panic if a topic has no [`BlockAnalyzer`](crate::topic::BlockAnalyzer) or its sink is
+    /// missing.
+    pub fn new(
+        network: String,
+        rpc_url: &str,
+        blocks: Arc<BlockSource>,
+        topics: Vec<(Arc<dyn Topic>, SinkKind)>,
+        sinks: HashMap<SinkKind, Box<dyn Sink>>,
+        progress: Progress,
+        config: BackfillConfig,
+        max_batch_size: usize,
+    ) -> Self {
+        for (topic, sink) in topics.iter() {
+            assert!(
+                topic.block_analyzer().is_some(),
+                "topic \"{}\" cannot be backfilled",
+                topic.name()
+            );
+            assert!(sinks.contains_key(sink), "miss the sink {}", sink);
+        }
+        Self {
+            network,
+            rpc: BatchRpcClient::new(rpc_url),
+            blocks,
+            topics,
+            sinks: sinks
+                .into_iter()
+                .map(|(kind, sink)| (kind, Mutex::new(sink)))
+                .collect(),
+            progress: ::std::sync::Mutex::new(progress),
+            config,
+            max_batch_size,
+        }
+    }
+
+    /// Backfill the blocks `[from, to]`, `from` must be greater than 0. Return the number of
+    /// chunks given up.
+    pub async fn run(&self, from: BlockNumber, to: BlockNumber) -> usize {
+        assert!(from > 0, "the genesis block cannot be backfilled");
+        let chunks = split(from, to, self.config.chunk_size);
+        let n_chunks = chunks.len();
+        let n_finished = AtomicUsize::new(0);
+        log::info!(
+            "Backfill #{} to #{}, {} chunks of {} blocks",
+            from,
+            to,
+            n_chunks,
+            self.config.chunk_size
+        );
+        stream::iter(chunks)
+            .map(|(from, to)| {
+                let n_finished = &n_finished;
+                async move {
+                    let succeeded = self.backfill_chunk_with_retries(from, to).await;
+                    let n_finished = n_finished.fetch_add(1, Ordering::SeqCst) + 1;
+                    log::info!(
+                        "Backfilled chunk [{}, {}], {}/{} chunks",
+                        from,
+                        to,
+                        n_finished,
+                        n_chunks
+                    );
+                    succeeded
+                }
+            })
+            .buffer_unordered(self.config.concurrency)
+            .filter(|succeeded| futures::future::ready(!succeeded))
+            .count()
+            .await
+    }
+
+    async fn backfill_chunk_with_retries(&self, from: BlockNumber, to: BlockNumber) -> bool {
+        for attempt in 1..=MAX_CHUNK_ATTEMPTS {
+            match self.backfill_chunk(from, to).await {
+                Ok(()) => return true,
+                Err(err) => {
+                    log::warn!(
+                        "backfill chunk [{}, {}], attempt {}/{}, error: {}",
+                        from,
+                        to,
+                        attempt,
+                        MAX_CHUNK_ATTEMPTS,
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_secs(attempt)).await;
+                }
+            }
+        }
+        log::error!("give up backfilling chunk [{}, {}]", from, to);
+        false
+    }
+
+    async fn backfill_chunk(&self, from: BlockNumber, to: BlockNumber) -> Result<(), String> {
+        let pending = self
+            .topics
+            .iter()
+            .filter(|(topic, _)| {
+                !self.progress.lock().expect("acquire lock").is_completed(
+                    &self.network,
+                    topic.name(),
+                    from,
+                    to,
+                )
+            })
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        // Fetch the parent of the first block as well
+        let blocks = self.fetch_blocks(from - 1, to).await?;
+        let needs_spent_capacities = pending.iter().any(|(topic, _)| {
+            topic
+                .block_analyzer()
+                .map(|analyzer| analyzer.needs_spent_capacities())
+                .unwrap_or(false)
+        });
+        let spent = if needs_spent_capacities {
+            spent_capacities(&self.blocks, &blocks[1..]).await
+        } else {
+            HashMap::new()
+        };
+        for (topic, sink) in pending {
+            let analyzer = topic.block_analyzer().expect("checked in Backfill::new");
+            let mut batch = Vec::new();
+            for pair in blocks.windows(2) {
+                batch.extend(analyzer.analyze(&self.network, &pair[1], &pair[0].header(), &spent));
+                if batch.len() >= self.max_batch_size {
+                    self.write(*sink, &batch).await?;
+                    batch.clear();
+                }
+            }
+            self.write(*sink, &batch).await?;
+            self.progress
+                .lock()
+                .expect("acquire lock")
+                .record(&self.network, topic.name(), from, to)
+                .map_err(|err| format!("failed to record progress, error: {}", err))?;
+        }
+        Ok(())
+    }
+
+    // Fetch the blocks `[from, to]`, which must be on one chain
+    async fn fetch_blocks(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockView>, String> {
+        let numbers = (from..=to).collect::<Vec<_>>();
+        let mut blocks = Vec::with_capacity(numbers.len());
+        for batch in numbers.chunks(self.config.batch_size) {
+            let fetched = self.rpc.get_blocks_by_number(batch).await.map_err(|err| {
+                format!(
+                    "fetch blocks #{} to #{}, error: {}",
+                    batch[0],
+                    batch[batch.len() - 1],
+                    err
+                )
+            })?;
+            blocks.extend(fetched);
+        }
+        for pair in blocks.windows(2) {
+            if pair[1].parent_hash() != pair[0].hash() {
+                return Err(format!(
+                    "block #{} is not a child of #{}, the chain reorganized during fetching",
+                    pair[1].number(),
+                    pair[0].number()
+                ));
+            }
+        }
+        Ok(blocks)
+    }
+
+    // Write the entries, waiting for the sink while it is unavailable. Return an error if some
+    // entries are rejected.
+    async fn write(&self, kind: SinkKind, entries: &[Entry]) -> Result<(), String> {
+        let mut entries = entries.to_vec();
+        let mut backoff = Duration::from_secs(1);
+        while !entries.is_empty() {
+            let mut sink = self.sinks[&kind].lock().await;
+            let unwritten = sink.write(&entries).await;
+            if unwritten.is_empty() {
+                break;
+            }
+            if sink.is_connected() {
+                return Err(format!("{} entries rejected by {}", unwritten.len(), kind));
+            }
+            drop(sink);
+            log::warn!(
+                "{} is unavailable, retry writing {} entries in {:?}",
+                kind,
+                unwritten.len(),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = min(backoff * 2, MAX_WRITE_BACKOFF);
+            entries = unwritten;
+        }
+        Ok(())
+    }
+}
+
+/// Split the blocks `[from, to]` into chunks of at most `chunk_size` blocks
+fn split(from: BlockNumber, to: BlockNumber, chunk_size: u64) -> Vec<(BlockNumber, BlockNumber)> {
+    let mut chunks = Vec::new();
+    let mut start = from;
+    while start <= to {
+        let end = min(to, start.saturating_add(chunk_size - 1));
+        chunks.push((start, end));
+        if end == to {
+            break;
+        }
+        start = end + 1;
+    }
+    chunks
+}
+
+#[test]
+fn test_split() {
+    assert_eq!(split(1, 10, 4), vec![(1, 4), (5, 8), (9, 10)]);
+    assert_eq!(split(1, 8, 4), vec![(1, 4), (5, 8)]);
+    assert_eq!(split(5, 5, 4), vec![(5, 5)]);
+    assert!(split(6, 5, 4).is_empty());
+}
+
+#[test]
+fn test_progress() {
+    let dir = ::std::env::temp_dir().join(format!("ckb-analyzer-backfill-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut progress = Progress::open(&dir).unwrap();
+    progress.record("ckb_dev", "CellCrawler", 1, 10).unwrap();
+    progress.record("ckb_dev", "CellCrawler", 21, 30).unwrap();
+    progress.record("ckb_dev", "CellCrawler", 11, 20).unwrap();
+    assert!(progress.is_completed("ckb_dev", "CellCrawler", 1, 30));
+    assert!(progress.is_completed("ckb_dev", "CellCrawler", 5, 25));
+    assert!(!progress.is_completed("ckb_dev", "CellCrawler", 25, 35));
+    assert!(!progress.is_completed("ckb_dev", "ChainCrawler", 1, 10));
+    assert!(!progress.is_completed("ckb", "CellCrawler", 1, 10));
+
+    // Resume from the recorded chunks
+    let progress = Progress::open(&dir).unwrap();
+    assert!(progress.is_completed("ckb_dev", "CellCrawler", 1, 30));
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,342 @@
+use crate::ckb_jsonrpc_types::BlockEconomicState;
+use crate::ckb_types::core::{BlockNumber, BlockView, HeaderView};
+use crate::ckb_types::{packed, prelude::*, H256};
+use crate::config::Config;
+use crate::metrics;
+use crate::util::jsonrpc::BatchRpcClient;
+use ckb_testkit::Node;
+use lru::LruCache;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OnceCell};
+
+// The tip is shared by the block topics, refreshed at most once per `TIP_TTL`
+const TIP_TTL: Duration = Duration::from_secs(1);
+
+/// BlockSource fetches the blocks for the block topics, so that a block is downloaded once no
+/// matter how many topics analyze it.
+///
+/// Every topic still keeps its own cursor and asks for the blocks it needs. On a cache miss, the
+/// following confirmed blocks are fetched in the same batch JSON-RPC request and cached, so the
+/// topics behind get them from the cache, as long as they lag within `block_source.cache_size`
+/// blocks.
+///
+/// The lock of the cache is never held across RPC calls, so the topics do not wait for each
+/// other's requests, except that a block being fetched is not requested again: the topics asking
+/// for it wait for the batch in flight. Whenever the tip is refreshed, the highest cached block is compared with the
+/// canonical chain, and the cache is cleared if it was reorganized.
+pub struct BlockSource {
+    node: Node,
+    rpc: BatchRpcClient,
+    batch_size: usize,
+    // Blocks above `tip - block_confirmation` are not prefetched, they may be reorganized
+    block_confirmation: BlockNumber,
+    // Whether fetching the cycles ever failed
+    cycles_unavailable: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    tip_number: BlockNumber,
+    tip_refreshed_at: Option<Instant>,
+    cache: LruCache<BlockNumber, BlockView>,
+    // The batches being fetched, keyed by each of their block numbers
+    in_flight: HashMap<BlockNumber, Arc<Batch>>,
+}
+
+// A batch of blocks in flight, shared by the callers asking for any of its blocks. If the caller
+// fetching it is cancelled, the next one waiting takes over.
+struct Batch {
+    numbers: Vec<BlockNumber>,
+    blocks: OnceCell<Vec<BlockView>>,
+}
+
+impl BlockSource {
+    pub fn new(node: Node, config: &Config) -> Self {
+        let block_confirmation = [
+            config.chain_crawler.block_confirmation,
+            config.chain_transaction_crawler.block_confirmation,
+            config.cell_crawler.block_confirmation,
+        ]
+        .iter()
+        .copied()
+        .min()
+        .unwrap_or_default();
+        Self {
+            node,
+            rpc: BatchRpcClient::new(&config.ckb.rpc_url),
+            batch_size: config.block_source.batch_size,
+            block_confirmation,
+            cycles_unavailable: AtomicBool::new(false),
+            state: Mutex::new(State {
+                tip_number: 0,
+                tip_refreshed_at: None,
+                cache: LruCache::new(config.block_source.cache_size),
+                in_flight: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Return the tip block number of CKB node
+    pub async fn tip_number(&self) -> BlockNumber {
+        let highest = {
+            let state = self.state.lock().await;
+            let expired = state
+                .tip_refreshed_at
+                .map(|refreshed_at| refreshed_at.elapsed() >= TIP_TTL)
+                .unwrap_or(true);
+            if !expired {
+                return state.tip_number;
+            }
+            state
+                .cache
+                .iter()
+                .max_by_key(|(number, _)| **number)
+                .map(|(number, block)| (*number, block.hash()))
+        };
+
+        let tip_number = self.node.get_tip_block_number();
+        let stale = match highest.as_ref() {
+            Some((number, hash)) => match self.rpc.get_block_hashes(&[*number]).await {
+                Ok(hashes) => {
+                    hashes.into_iter().next().flatten().map(|hash| hash.pack())
+                        != Some(hash.clone())
+                }
+                Err(err) => {
+                    log::warn!("fetch the hash of block #{}, error: {}", number, err);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        let mut state = self.state.lock().await;
+        state.tip_number = tip_number;
+        state.tip_refreshed_at = Some(Instant::now());
+        if stale {
+            if let Some((number, _)) = highest {
+                log::info!(
+                    "cached block #{} was reorganized, clear the block cache",
+                    number
+                );
+            }
+            state.cache.clear();
+        }
+        tip_number
+    }
+
+    /// Return the block of `number`, which must exist
+    pub async fn get_block(&self, number: BlockNumber) -> BlockView {
+        let tip_number = self.tip_number().await;
+        loop {
+            let batch = {
+                let mut state = self.state.lock().await;
+                if let Some(block) = state.cache.get(&number) {
+                    metrics::BLOCK_SOURCE_CACHE_HITS.inc();
+                    return block.clone();
+                }
+                match state.in_flight.get(&number) {
+                    Some(batch) => Arc::clone(batch),
+                    None => {
+                        let confirmed_number = tip_number.saturating_sub(self.block_confirmation);
+                        let to = min(
+                            number + self.batch_size as BlockNumber - 1,
+                            max(number, confirmed_number),
+                        );
+                        let numbers = (number..=to)
+                            .filter(|number| {
+                                !state.cache.contains(number)
+                                    && !state.in_flight.contains_key(number)
+                            })
+                            .collect::<Vec<_>>();
+                        let batch = Arc::new(Batch {
+                            numbers,
+                            blocks: OnceCell::new(),
+                        });
+                        for number in batch.numbers.iter() {
+                            state.in_flight.insert(*number, Arc::clone(&batch));
+                        }
+                        batch
+                    }
+                }
+            };
+
+            let blocks = batch.blocks.get_or_init(|| self.fetch(&batch)).await;
+            // Missed if the batch fell back to fetching its first block only, fetch it again
+            if let Some(block) = blocks.iter().find(|block| block.number() == number) {
+                return block.clone();
+            }
+        }
+    }
+
+    // Fetch the blocks of `batch` and cache them
+    async fn fetch(&self, batch: &Arc<Batch>) -> Vec<BlockView> {
+        let number = batch.numbers[0];
+        let to = batch.numbers[batch.numbers.len() - 1];
+        let blocks = match self.rpc.get_blocks_by_number(&batch.numbers).await {
+            Ok(blocks) if blocks.first().map(BlockView::number) == Some(number) => blocks,
+            Ok(_) => {
+                log::warn!(
+                    "fetch blocks #{} to #{} in batch, fall back to fetching #{}, the responses miss it",
+                    number,
+                    to,
+                    number,
+                );
+                vec![self.node.get_block_by_number(number)]
+            }
+            Err(err) => {
+                log::warn!(
+                    "fetch blocks #{} to #{} in batch, fall back to fetching #{}, error: {}",
+                    number,
+                    to,
+                    number,
+                    err
+                );
+                vec![self.node.get_block_by_number(number)]
+            }
+        };
+        metrics::BLOCK_SOURCE_FETCHED_BLOCKS.inc_by(blocks.len() as u64);
+
+        // A cached parent not linking to the fetched block was reorganized, the cache is stale
+        let mut state = self.state.lock().await;
+        let first = blocks.first().expect("the block of `number` is fetched");
+        let stale = state
+            .cache
+            .peek(&first.number().saturating_sub(1))
+            .map(|parent| parent.hash() != first.parent_hash())
+            .unwrap_or(false);
+        if stale {
+            log::info!(
+                "block #{} does not link to the cached parent, clear the block cache",
+                first.number()
+            );
+            state.cache.clear();
+        }
+        for block in blocks.iter() {
+            state.cache.put(block.number(), block.clone());
+        }
+        state
+            .in_flight
+            .retain(|_, in_flight| !Arc::ptr_eq(in_flight, batch));
+        blocks
+    }
+
+    /// Return the total cycles of the transactions of the block `number`, `None` if the node does
+    /// not return them
+    pub async fn get_block_cycles(&self, number: BlockNumber) -> Option<u64> {
+        match self.rpc.get_block_cycles(&[number]).await {
+            Ok(cycles) => cycles.into_iter().next().flatten(),
+            Err(err) => {
+                // Older nodes reject the request every time, warn once
+                if !self.cycles_unavailable.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "fetch the cycles of block #{}, leave the cycles of blocks empty, error: {}",
+                        number,
+                        err
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Return the economic state of the block `hash`, `None` if its reward is not finalized yet or
+    /// the request failed
+    pub async fn get_block_economic_state(
+        &self,
+        hash: &packed::Byte32,
+    ) -> Option<BlockEconomicState> {
+        match self.rpc.get_block_economic_states(&[hash.unpack()]).await {
+            Ok(states) => states.into_iter().next().flatten(),
+            Err(err) => {
+                log::warn!(
+                    "fetch the economic state of block {:#x}, error: {}",
+                    hash,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Return the capacities of the cells `out_points`, looking up their transactions in batches.
+    /// The cells whose transactions the node does not return are absent.
+    pub async fn get_output_capacities(
+        &self,
+        out_points: &[packed::OutPoint],
+    ) -> HashMap<packed::OutPoint, u64> {
+        let mut tx_hashes = out_points
+            .iter()
+            .map(|out_point| out_point.tx_hash().unpack())
+            .collect::<Vec<H256>>();
+        tx_hashes.sort();
+        tx_hashes.dedup();
+        // #{ tx hash => output capacities }
+        let mut outputs = HashMap::new();
+        for chunk in tx_hashes.chunks(self.batch_size) {
+            match self.rpc.get_transactions(chunk).await {
+                Ok(txs) => {
+                    for (tx_hash, tx) in chunk.iter().zip(txs) {
+                        if let Some(tx) = tx {
+                            let capacities = tx
+                                .inner
+                                .outputs
+                                .iter()
+                                .map(|output| output.capacity.value())
+                                .collect::<Vec<_>>();
+                            outputs.insert(tx_hash.clone(), capacities);
+                        }
+                    }
+                }
+                Err(err) => log::warn!(
+                    "fetch {} transactions of the spent cells in batch, error: {}",
+                    chunk.len(),
+                    err
+                ),
+            }
+        }
+        out_points
+            .iter()
+            .filter_map(|out_point| {
+                let tx_hash: H256 = out_point.tx_hash().unpack();
+                let index = Unpack::<u32>::unpack(&out_point.index()) as usize;
+                let capacity = outputs.get(&tx_hash)?.get(index)?;
+                Some((out_point.clone(), *capacity))
+            })
+            .collect()
+    }
+
+    /// Return the parent header of `block`, from the cache if present
+    pub async fn get_parent_header(&self, block: &BlockView) -> HeaderView {
+        let state = self.state.lock().await;
+        if let Some(parent) = state.cache.peek(&block.number().saturating_sub(1)) {
+            if parent.hash() == block.parent_hash() {
+                return parent.header();
+            }
+        }
+        drop(state);
+        self.node
+            .rpc_client()
+            .get_header(block.parent_hash())
+            .unwrap()
+            .into()
+    }
+
+    /// Evict the cached blocks above `fork_number`, which were reorganized
+    pub async fn invalidate(&self, fork_number: BlockNumber) {
+        let mut state = self.state.lock().await;
+        let orphans = state
+            .cache
+            .iter()
+            .map(|(number, _)| *number)
+            .filter(|number| *number > fork_number)
+            .collect::<Vec<_>>();
+        for number in orphans {
+            state.cache.pop(&number);
+        }
+        state.in_flight.retain(|number, _| *number <= fork_number);
+    }
+}
@@ -0,0 +1,567 @@
+//! CKBAnalyzer configuration, loaded from a TOML file via `--config`.
+//!
+//! Every item has a default value, see [`DEFAULT_CONFIG`]. Command line flags and environment
+//! variables take precedence over the file; the overriding is done in [`crate::app`].
+
+use crate::sink::SinkKind;
+use p2p::multiaddr::Multiaddr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// The default configuration file, with every item commented
+pub const DEFAULT_CONFIG: &str = include_str!("ckb-analyzer.toml");
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub topics: Vec<String>,
+    pub ckb: CkbConfig,
+    pub postgres: PostgresConfig,
+    pub writer: WriterConfig,
+    pub sink: SinkConfig,
+    pub backfill: BackfillConfig,
+    pub block_source: BlockSourceConfig,
+    pub metrics: MetricsConfig,
+    pub ipinfo: IpInfoConfig,
+    /// #{ chain id => bootnode multiaddrs }
+    pub bootnodes: HashMap<String, Vec<String>>,
+    /// The miners preceding the bundled registry, see [`crate::util::miners`]
+    pub miners: Vec<MinerConfig>,
+    /// #{ chain id => well-known scripts }, see [`crate::util::scripts`]
+    pub scripts: HashMap<String, Vec<ScriptConfig>>,
+    pub chain_crawler: ChainCrawlerConfig,
+    pub chain_transaction_crawler: BlockCrawlerConfig,
+    pub cell_crawler: BlockCrawlerConfig,
+    pub epoch_crawler: EpochCrawlerConfig,
+    pub pool_crawler: PoolCrawlerConfig,
+    pub retention_transaction_crawler: RetentionTransactionCrawlerConfig,
+    pub network_crawler: NetworkCrawlerConfig,
+    pub compact_block_crawler: CompactBlockCrawlerConfig,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CkbConfig {
+    pub rpc_url: String,
+    pub subscription_addr: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresConfig {
+    pub host: String,
+    pub port: u16,
+    pub database: String,
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriterConfig {
+    pub max_batch_size: usize,
+    pub max_batch_timeout_secs: u64,
+    pub channel_capacity: usize,
+    pub data_dir: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinkConfig {
+    /// The sink of the topics not listed in `topics`
+    pub default: SinkKind,
+    /// #{ topic name => sink }
+    pub topics: HashMap<String, SinkKind>,
+    /// The directory of the file sinks
+    pub output_dir: String,
+    pub max_file_size_mb: u64,
+    /// The database file of the sqlite sink
+    pub sqlite_path: String,
+}
+
+/// The configuration of the `backfill` subcommand
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackfillConfig {
+    /// The number of blocks of a chunk, the unit of progress
+    pub chunk_size: u64,
+    /// The number of chunks backfilled concurrently
+    pub concurrency: usize,
+    /// The number of blocks fetched in one batch JSON-RPC request
+    pub batch_size: usize,
+}
+
+/// The configuration of the blocks shared by the block topics
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockSourceConfig {
+    /// The number of recently fetched blocks kept for the topics behind
+    pub cache_size: usize,
+    /// The number of blocks fetched in one batch JSON-RPC request
+    pub batch_size: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen_addr: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpInfoConfig {
+    pub token: String,
+}
+
+/// A mining pool of the miner registry
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MinerConfig {
+    pub name: String,
+    /// The args of the miner locks of any lock script, without the molecule length header, e.g.
+    /// "0x..."
+    pub lock_args: Vec<String>,
+    /// The regular expression matching the cellbase messages, ignored if empty
+    pub cellbase_message: String,
+}
+
+/// A well-known script of the script catalogue
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptConfig {
+    /// e.g. "sudt"
+    pub kind: String,
+    pub code_hash: String,
+    /// "data", "type" or "data1"
+    pub hash_type: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainCrawlerConfig {
+    pub block_confirmation: u64,
+    pub reorg_window: usize,
+    pub poll_interval_secs: u64,
+}
+
+/// The configuration of the topics crawling confirmed blocks
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockCrawlerConfig {
+    pub block_confirmation: u64,
+    pub reorg_window: usize,
+    pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EpochCrawlerConfig {
+    pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolCrawlerConfig {
+    pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionTransactionCrawlerConfig {
+    pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkCrawlerConfig {
+    pub dial_interval_secs: u64,
+    pub disconnect_timeout_session_interval_secs: u64,
+    pub session_timeout_secs: u64,
+    pub store_online_addresses_interval_secs: u64,
+    pub address_timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactBlockCrawlerConfig {
+    pub dial_interval_secs: u64,
+    pub compact_block_cache_size: usize,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 5432,
+            database: String::new(),
+            user: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 200,
+            max_batch_timeout_secs: 3,
+            channel_capacity: 5000,
+            data_dir: "data".to_string(),
+        }
+    }
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        Self {
+            default: SinkKind::Postgres,
+            topics: HashMap::new(),
+            output_dir: "output".to_string(),
+            max_file_size_mb: 256,
+            sqlite_path: "ckb-analyzer.sqlite".to_string(),
+        }
+    }
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1000,
+            concurrency: 4,
+            batch_size: 100,
+        }
+    }
+}
+
+impl Default for BlockSourceConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 2000,
+            batch_size: 20,
+        }
+    }
+}
+
+impl Default for ChainCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            block_confirmation: 10,
+            reorg_window: 1000,
+            poll_interval_secs: 1,
+        }
+    }
+}
+
+impl Default for BlockCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            block_confirmation: 10,
+            reorg_window: 1000,
+            poll_interval_secs: 1,
+        }
+    }
+}
+
+impl Default for EpochCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 10,
+        }
+    }
+}
+
+impl Default for PoolCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 5,
+        }
+    }
+}
+
+impl Default for RetentionTransactionCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 10 * 60,
+        }
+    }
+}
+
+impl Default for NetworkCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            dial_interval_secs: 1,
+            disconnect_timeout_session_interval_secs: 10,
+            session_timeout_secs: 10,
+            store_online_addresses_interval_secs: 60,
+            address_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for CompactBlockCrawlerConfig {
+    fn default() -> Self {
+        Self {
+            dial_interval_secs: 1,
+            compact_block_cache_size: 2000,
+        }
+    }
+}
+
+impl Config {
+    /// Load and validate the configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = ::std::fs::read_to_string(path.as_ref())
+            .map_err(|err| format!("failed to read \"{}\": {}", path.as_ref().display(), err))?;
+        Self::parse(&content).map_err(|err| format!("\"{}\": {}", path.as_ref().display(), err))
+    }
+
+    /// Parse and validate the configuration
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(content).map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.ckb.rpc_url.is_empty() {
+            url::Url::parse(&self.ckb.rpc_url)
+                .map_err(|err| format!("invalid ckb.rpc_url \"{}\": {}", self.ckb.rpc_url, err))?;
+        }
+
+        if !self.metrics.listen_addr.is_empty() {
+            self.metrics
+                .listen_addr
+                .parse::<SocketAddr>()
+                .map_err(|err| {
+                    format!(
+                        "invalid metrics.listen_addr \"{}\": {}",
+                        self.metrics.listen_addr, err
+                    )
+                })?;
+        }
+
+        for (network, addresses) in self.bootnodes.iter() {
+            for address in addresses {
+                address.parse::<Multiaddr>().map_err(|err| {
+                    format!("invalid bootnodes.{} \"{}\": {}", network, address, err)
+                })?;
+            }
+        }
+
+        for miner in self.miners.iter() {
+            if miner.name.is_empty() {
+                return Err("miners.name must not be empty".to_string());
+            }
+            if !miner.cellbase_message.is_empty() {
+                regex::Regex::new(&miner.cellbase_message).map_err(|err| {
+                    format!(
+                        "invalid cellbase_message \"{}\" of miner \"{}\": {}",
+                        miner.cellbase_message, miner.name, err
+                    )
+                })?;
+            }
+        }
+
+        for (network, scripts) in self.scripts.iter() {
+            for script in scripts {
+                if script.kind.is_empty() {
+                    return Err(format!("scripts.{}.kind must not be empty", network));
+                }
+                let is_hex = script.code_hash.len() == 66
+                    && script.code_hash.starts_with("0x")
+                    && script.code_hash[2..].chars().all(|c| c.is_ascii_hexdigit());
+                if !is_hex {
+                    return Err(format!(
+                        "invalid scripts.{}.code_hash \"{}\"",
+                        network, script.code_hash
+                    ));
+                }
+                if crate::util::scripts::parse_hash_type(&script.hash_type).is_none() {
+                    return Err(format!(
+                        "invalid scripts.{}.hash_type \"{}\", expect \"data\", \"type\" or \"data1\"",
+                        network, script.hash_type
+                    ));
+                }
+            }
+        }
+
+        let positives = [
+            ("writer.max_batch_size", self.writer.max_batch_size as u64),
+            (
+                "writer.max_batch_timeout_secs",
+                self.writer.max_batch_timeout_secs,
+            ),
+            (
+                "writer.channel_capacity",
+                self.writer.channel_capacity as u64,
+            ),
+            ("sink.max_file_size_mb", self.sink.max_file_size_mb),
+            ("backfill.chunk_size", self.backfill.chunk_size),
+            ("backfill.concurrency", self.backfill.concurrency as u64),
+            ("backfill.batch_size", self.backfill.batch_size as u64),
+            (
+                "block_source.cache_size",
+                self.block_source.cache_size as u64,
+            ),
+            (
+                "block_source.batch_size",
+                self.block_source.batch_size as u64,
+            ),
+            (
+                "chain_crawler.reorg_window",
+                self.chain_crawler.reorg_window as u64,
+            ),
+            (
+                "chain_crawler.poll_interval_secs",
+                self.chain_crawler.poll_interval_secs,
+            ),
+            (
+                "chain_transaction_crawler.reorg_window",
+                self.chain_transaction_crawler.reorg_window as u64,
+            ),
+            (
+                "chain_transaction_crawler.poll_interval_secs",
+                self.chain_transaction_crawler.poll_interval_secs,
+            ),
+            (
+                "cell_crawler.reorg_window",
+                self.cell_crawler.reorg_window as u64,
+            ),
+            (
+                "cell_crawler.poll_interval_secs",
+                self.cell_crawler.poll_interval_secs,
+            ),
+            (
+                "epoch_crawler.poll_interval_secs",
+                self.epoch_crawler.poll_interval_secs,
+            ),
+            (
+                "pool_crawler.poll_interval_secs",
+                self.pool_crawler.poll_interval_secs,
+            ),
+            (
+                "retention_transaction_crawler.poll_interval_secs",
+                self.retention_transaction_crawler.poll_interval_secs,
+            ),
+            (
+                "network_crawler.dial_interval_secs",
+                self.network_crawler.dial_interval_secs,
+            ),
+            (
+                "network_crawler.disconnect_timeout_session_interval_secs",
+                self.network_crawler
+                    .disconnect_timeout_session_interval_secs,
+            ),
+            (
+                "network_crawler.store_online_addresses_interval_secs",
+                self.network_crawler.store_online_addresses_interval_secs,
+            ),
+            (
+                "compact_block_crawler.dial_interval_secs",
+                self.compact_block_crawler.dial_interval_secs,
+            ),
+            (
+                "compact_block_crawler.compact_block_cache_size",
+                self.compact_block_crawler.compact_block_cache_size as u64,
+            ),
+        ];
+        for (key, value) in positives.iter() {
+            if *value == 0 {
+                return Err(format!("{} must be greater than 0", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WriterConfig {
+    pub fn max_batch_timeout(&self) -> Duration {
+        Duration::from_secs(self.max_batch_timeout_secs)
+    }
+}
+
+impl SinkConfig {
+    /// The size in bytes at which the output files are rotated
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size_mb * 1024 * 1024
+    }
+}
+
+impl ChainCrawlerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl BlockCrawlerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl EpochCrawlerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl PoolCrawlerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl RetentionTransactionCrawlerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl NetworkCrawlerConfig {
+    pub fn dial_interval(&self) -> Duration {
+        Duration::from_secs(self.dial_interval_secs)
+    }
+
+    pub fn disconnect_timeout_session_interval(&self) -> Duration {
+        Duration::from_secs(self.disconnect_timeout_session_interval_secs)
+    }
+
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout_secs)
+    }
+
+    pub fn store_online_addresses_interval(&self) -> Duration {
+        Duration::from_secs(self.store_online_addresses_interval_secs)
+    }
+
+    pub fn address_timeout(&self) -> Duration {
+        Duration::from_secs(self.address_timeout_secs)
+    }
+}
+
+impl CompactBlockCrawlerConfig {
+    pub fn dial_interval(&self) -> Duration {
+        Duration::from_secs(self.dial_interval_secs)
+    }
+}
+
+#[test]
+fn test_default_config() {
+    assert_eq!(Config::parse(DEFAULT_CONFIG).unwrap(), Config::default());
+    assert_eq!(Config::parse("").unwrap(), Config::default());
+}
+
+#[test]
+fn test_invalid_config() {
+    let err = Config::parse("[chain_crawler]\npoll_interval_secs = 0").unwrap_err();
+    assert_eq!(
+        err,
+        "chain_crawler.poll_interval_secs must be greater than 0"
+    );
+    assert!(Config::parse("[chain_crawler]\nunknown = 1").is_err());
+    assert!(Config::parse("[ckb]\nrpc_url = \"not a url\"").is_err());
+    assert!(Config::parse("[sink]\ndefault = \"parquet\"").is_err());
+}
@@ -1,11 +1,14 @@
-use crate::ckb_types::{
-    bytes::Bytes,
-    core::ScriptHashType,
-    packed::{Byte32, OutPoint, ProposalShortId},
-};
+//! Entries are typed rows. Topics send entries through the channel and the writer inserts them
+//! into the corresponding tables. The entries sent together in one message are written together,
+//! atomically if they are closed by an [`AnalyzerCursor`].
+//!
+//! Entries are serializable so that they can be spooled to disk while Postgres is unavailable.
+//! The file sinks write [`Entry::row`] into the file named by [`Entry::table`].
+
+use serde::{Deserialize, Serialize, Serializer};
 
 /// Peer info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Peer {
     pub network: String,
     pub time: chrono::NaiveDateTime,
@@ -15,7 +18,7 @@ pub struct Peer {
 }
 
 /// IP info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IpInfo {
     pub network: String,
     pub ip: String,
@@ -26,7 +29,7 @@ pub struct IpInfo {
 }
 
 /// Block Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub network: String,
     pub time: chrono::NaiveDateTime,
@@ -37,25 +40,153 @@ pub struct Block {
     pub cellbase_client_version: String,
     pub cellbase_miner_source: String,
     pub miner_lock_args: String,
+    pub miner_lock_hash: String,
     pub interval: i64, // ms
-    pub hash: Byte32,
+    pub hash: String,
+    pub size: i64, // bytes, counted against `max_block_bytes`, i.e. without the uncle proposals
+    pub cycles: Option<i64>, // the total cycles of the transactions, `None` if unknown
+    pub compact_target: i64,
+    pub difficulty: String,
+    // The DAO field: C, the total issuance; AR, the accumulated rate; S, the total unissued
+    // secondary issuance; U, the total occupied capacity. All in shannons except AR.
+    pub dao_c: i64,
+    pub dao_ar: i64,
+    pub dao_s: i64,
+    pub dao_u: i64,
+    pub version: i32,
+    pub epoch_number: i64,
+    pub epoch_index: i32,
+    pub epoch_length: i32,
+    pub has_extension: bool,
+    pub miner: Option<String>, // the pool name in the miner registry, `None` if unknown
+}
+
+/// Miner seen in the blocks, keyed by the hash of the miner lock
+///
+/// Note: The first and last blocks are merged with the stored ones, so the blocks can be written
+/// in any order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Miner {
+    pub network: String,
+    pub miner_lock_hash: String,
+    pub miner_lock_args: String,
+    pub miner: Option<String>,
+    pub first_number: i64,
+    pub first_time: chrono::NaiveDateTime,
+    pub last_number: i64,
+    pub last_time: chrono::NaiveDateTime,
+}
+
+/// Uncle included by a block of the main chain
+///
+/// Note: The miner fields are `None` if the node does not have the uncle block, whose cellbase
+/// tells the miner.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Uncle {
+    pub network: String,
+    pub time: chrono::NaiveDateTime,
+    pub number: i64,
+    pub hash: String,
+    pub block_number: i64,
+    pub distance: i64, // block_number - number
+    pub miner_lock_args: Option<String>,
+    pub cellbase_client_version: Option<String>,
+    pub cellbase_miner_source: Option<String>,
+}
+
+/// Proposal of a transaction, recorded once its proposal window is over
+///
+/// Note: `committed_number` and `commit_distance` are `None` if the proposal expired without
+/// being committed. The window of the proposal in block `number` is settled at block
+/// `settled_number`, i.e. `number + tx_proposal_window.farthest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Proposal {
+    pub network: String,
+    pub time: chrono::NaiveDateTime,
+    pub number: i64,
+    pub proposal_id: String,
+    pub committed_number: Option<i64>,
+    pub commit_distance: Option<i64>,
+    pub settled_number: i64,
+}
+
+/// Reward paid by the cellbase of block `number` for mining block `target_number`, in shannons
+///
+/// Note: The reward of a block is finalized and paid `finalization_delay_length` blocks later, to
+/// the lock of its cellbase witness. `lock_hash` is the hash of that lock.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockReward {
+    pub network: String,
+    pub time: chrono::NaiveDateTime,
+    pub number: i64,
+    pub target_number: i64,
+    pub lock_hash: String,
+    pub primary_reward: i64,
+    pub secondary_reward: i64,
+    pub commit_reward: i64,
+    pub proposal_reward: i64,
+}
+
+/// Chain reorganization detected by ChainCrawler
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reorg {
+    pub network: String,
+    pub time: chrono::NaiveDateTime,
+    pub fork_number: i64,
+    pub depth: i64,
+    pub old_tip_hash: String,
+    pub new_tip_hash: String,
+}
+
+/// Rollback the rows of `topic` above `fork_number`
+///
+/// Note: This is not a row. Each block topic detects the reorgs by itself, and deletes the orphaned
+/// rows of its own tables, see [`Rollback::tables`], and moves its own cursor back to
+/// `fork_number`. The file sinks, which cannot delete, record it into `rollback` instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rollback {
+    pub network: String,
+    pub topic: String,
+    pub fork_number: i64,
+}
+
+impl Rollback {
+    /// The tables written by `topic`, with their columns of the block number. The miners of
+    /// ChainCrawler are corrected after its blocks are deleted.
+    pub fn tables(&self) -> &'static [(&'static str, &'static str)] {
+        match self.topic.as_str() {
+            "ChainCrawler" => &[
+                ("block", "number"),
+                ("uncle", "block_number"),
+                ("proposal", "settled_number"),
+                ("block_reward", "number"),
+                ("miner", "first_number"),
+            ],
+            "ChainTransactionCrawler" => &[("block_transaction", "number")],
+            "CellCrawler" => &[
+                ("created_cell", "block_number"),
+                ("spent_cell", "block_number"),
+            ],
+            _ => &[],
+        }
+    }
 }
 
 /// Epoch Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Epoch {
     pub network: String,
     pub start_time: chrono::NaiveDateTime,
     pub end_time: chrono::NaiveDateTime,
-    pub number: u64,
-    pub length: u64,
-    pub start_number: u64,
+    pub number: i64,
+    pub length: i64,
+    pub start_number: i64,
     pub n_uncles: i32,
     pub difficulty: String,
 }
 
 /// Tx Pool Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TxPoolInfo {
     pub network: String,
     pub time: chrono::NaiveDateTime,
@@ -67,119 +198,306 @@ pub struct TxPoolInfo {
 }
 
 /// Block Transaction Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BlockTransaction {
     pub network: String,
     pub time: chrono::NaiveDateTime,
     pub number: i64,
-    pub size: i32,
+    pub tx_index: i32,
+    pub size: i64,
     pub n_inputs: i32,
     pub n_outputs: i32,
     pub n_header_deps: i32,
     pub n_cell_deps: i32,
-    pub total_data_size: i32,
+    pub total_data_size: i64,
     pub proposal_id: String,
     pub hash: String,
+    // The kinds of the well-known scripts of the outputs, sorted and separated by commas, see
+    // `crate::util::scripts`
+    pub script_kinds: String,
 }
 
 /// Subscribed New Transaction Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SubscribedNewTransaction {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub size: u64,
-    pub cycles: u64,
-    pub fee: u64,
-    pub n_inputs: usize,
-    pub n_outputs: usize,
-    pub n_header_deps: usize,
-    pub n_cell_deps: usize,
-    pub proposal_id: ProposalShortId,
-    pub hash: Byte32,
+    pub size: i64,
+    pub cycles: i64,
+    pub fee: i64,
+    pub n_inputs: i32,
+    pub n_outputs: i32,
+    pub n_header_deps: i32,
+    pub n_cell_deps: i32,
+    pub proposal_id: String,
+    pub hash: String,
 }
 
 /// Subscribed Proposed Transaction Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SubscribedProposedTransaction {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub size: u64,
-    pub cycles: u64,
-    pub fee: u64,
-    pub n_inputs: usize,
-    pub n_outputs: usize,
-    pub n_header_deps: usize,
-    pub n_cell_deps: usize,
-    pub proposal_id: ProposalShortId,
-    pub hash: Byte32,
+    pub size: i64,
+    pub cycles: i64,
+    pub fee: i64,
+    pub n_inputs: i32,
+    pub n_outputs: i32,
+    pub n_header_deps: i32,
+    pub n_cell_deps: i32,
+    pub proposal_id: String,
+    pub hash: String,
 }
 
 /// Subscribed Rejected Transaction Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SubscribedRejectedTransaction {
     pub network: String,
     pub time: chrono::NaiveDateTime,
     pub reason: String,
-    pub size: u64,
-    pub cycles: u64,
-    pub fee: u64,
-    pub n_inputs: usize,
-    pub n_outputs: usize,
-    pub n_header_deps: usize,
-    pub n_cell_deps: usize,
-    pub proposal_id: ProposalShortId,
-    pub hash: Byte32,
+    pub size: i64,
+    pub cycles: i64,
+    pub fee: i64,
+    pub n_inputs: i32,
+    pub n_outputs: i32,
+    pub n_header_deps: i32,
+    pub n_cell_deps: i32,
+    pub proposal_id: String,
+    pub hash: String,
 }
 
 /// Retention Transaction Info
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RetentionTransaction {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub hash: Byte32,
+    pub hash: String,
 }
 
 /// Cell Info
-#[derive(Clone, Debug)]
+///
+/// Note: `type_hash_type` is 255 and `type_code_hash` is empty when the cell has no type script.
+/// The capacities are in shannons, `occupied_capacity` counts the capacity, the scripts and the
+/// data the cell occupies.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CreatedCell {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub block_number: u64,
-    pub tx_index: usize,
-    pub out_point: OutPoint,
+    pub block_number: i64,
+    pub tx_index: i32,
+    pub tx_hash: String,
+    pub index: i32,
+
+    pub lock_hash_type: i32,
+    pub lock_code_hash: String,
+    pub lock_args: String, // hex, stored as BYTEA in Postgres
+    pub lock_hash: String,
+    pub type_hash_type: i32,
+    pub type_code_hash: String,
+    pub type_args: Option<String>, // hex, stored as BYTEA in Postgres, `None` without type script
+    pub type_hash: Option<String>,
 
-    pub lock_hash_type: ScriptHashType,
-    pub lock_code_hash: Byte32,
-    pub lock_args: Option<Bytes>,
-    pub type_hash_type: Option<ScriptHashType>,
-    pub type_code_hash: Option<Byte32>,
+    pub capacity: i64,
+    pub occupied_capacity: i64,
+    pub data_size: i32,              // bytes
+    pub script_kind: Option<String>, // see `crate::util::scripts::ScriptCatalogue::cell_kind`
 }
 
-#[derive(Clone, Debug)]
+/// Note: `capacity` is the capacity of the spent cell in shannons, `None` if the spent cell was
+/// not found, e.g. it was created before the backfilled block.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpentCell {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub block_number: u64,
-    pub out_point: OutPoint,
+    pub block_number: i64,
+    pub tx_hash: String,
+    pub index: i64,
+    pub capacity: Option<i64>,
 }
 
 /// Compact block first received from
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CompactBlockFirstSeen {
     pub network: String,
     pub time: chrono::NaiveDateTime,
-    pub block_number: u64,
+    pub block_number: i64,
     pub ip: String,
 }
 
 /// Peer's last sent compact block
 ///
 /// Note: This table is not time-serie. It should be indexed by ip and keep update in place.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PeerLastCompactBlock {
     pub network: String,
     pub ip: String,
-    pub block_hash: Byte32,
-    pub block_number: u64,
+    pub block_hash: String,
+    pub block_number: i64,
     pub time: chrono::NaiveDateTime,
 }
+
+/// The resume cursor of a topic, the last block or epoch number it committed
+///
+/// Note: A topic appends it to the entries of a unit of work, e.g. a block, and the sink writes
+/// it atomically with the `n_entries` entries before it, see [`units`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnalyzerCursor {
+    pub network: String,
+    pub topic: String,
+    pub cursor: i64,
+    /// The hash of the cursor block, for detecting the reorgs happened while stopped. `None` for
+    /// the topics not crawling blocks.
+    pub block_hash: Option<String>,
+    pub updated_at: chrono::NaiveDateTime,
+    pub n_entries: usize,
+}
+
+/// Any kind of entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Entry {
+    Peer(Peer),
+    IpInfo(IpInfo),
+    Block(Block),
+    Miner(Miner),
+    Uncle(Uncle),
+    Proposal(Proposal),
+    BlockReward(BlockReward),
+    Reorg(Reorg),
+    Rollback(Rollback),
+    Epoch(Epoch),
+    TxPoolInfo(TxPoolInfo),
+    BlockTransaction(BlockTransaction),
+    SubscribedNewTransaction(SubscribedNewTransaction),
+    SubscribedProposedTransaction(SubscribedProposedTransaction),
+    SubscribedRejectedTransaction(SubscribedRejectedTransaction),
+    RetentionTransaction(RetentionTransaction),
+    CreatedCell(CreatedCell),
+    SpentCell(SpentCell),
+    CompactBlockFirstSeen(CompactBlockFirstSeen),
+    PeerLastCompactBlock(PeerLastCompactBlock),
+    AnalyzerCursor(AnalyzerCursor),
+}
+
+/// The entry as a row of its table, serialized as the inner struct, used by the file sinks
+pub struct Row<'a>(&'a Entry);
+
+impl Entry {
+    pub fn row(&self) -> Row<'_> {
+        Row(self)
+    }
+}
+
+macro_rules! impl_entry {
+    ($($name:ident => $table:expr),* $(,)?) => {
+        $(
+            impl From<$name> for Entry {
+                fn from(entry: $name) -> Self {
+                    Entry::$name(entry)
+                }
+            }
+        )*
+
+        impl Entry {
+            /// The table the entry is written into
+            pub fn table(&self) -> &'static str {
+                match self {
+                    $(Entry::$name(_) => $table,)*
+                }
+            }
+
+            /// The network, i.e. the schema, the entry is written into
+            pub fn network(&self) -> &str {
+                match self {
+                    $(Entry::$name(entry) => &entry.network,)*
+                }
+            }
+        }
+
+        impl Serialize for Row<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self.0 {
+                    $(Entry::$name(entry) => entry.serialize(serializer),)*
+                }
+            }
+        }
+    };
+}
+
+impl_entry!(
+    Peer => "peer",
+    IpInfo => "ipinfo",
+    Block => "block",
+    Miner => "miner",
+    Uncle => "uncle",
+    Proposal => "proposal",
+    BlockReward => "block_reward",
+    Reorg => "reorg",
+    Rollback => "rollback",
+    Epoch => "epoch",
+    TxPoolInfo => "tx_pool_info",
+    BlockTransaction => "block_transaction",
+    SubscribedNewTransaction => "subscribed_new_transaction",
+    SubscribedProposedTransaction => "subscribed_proposed_transaction",
+    SubscribedRejectedTransaction => "subscribed_rejected_transaction",
+    RetentionTransaction => "retention_transaction",
+    CreatedCell => "created_cell",
+    SpentCell => "spent_cell",
+    CompactBlockFirstSeen => "compact_block_first_seen",
+    PeerLastCompactBlock => "peer_last_compact_block",
+    AnalyzerCursor => "analyzer_cursor",
+);
+
+/// Split the entries into the units which are written atomically. An [`AnalyzerCursor`] closes a
+/// unit together with the `n_entries` entries before it; any other entry is a unit alone.
+pub fn units(entries: &[Entry]) -> Vec<&[Entry]> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        if let Entry::AnalyzerCursor(cursor) = entry {
+            let unit_start = ::std::cmp::max(start, i.saturating_sub(cursor.n_entries));
+            units.extend((start..unit_start).map(|j| &entries[j..=j]));
+            units.push(&entries[unit_start..=i]);
+            start = i + 1;
+        }
+    }
+    units.extend((start..entries.len()).map(|j| &entries[j..=j]));
+    units
+}
+
+#[test]
+fn test_units() {
+    let entry = |n: i64| -> Entry {
+        RetentionTransaction {
+            network: "ckb_dev".to_string(),
+            time: chrono::NaiveDateTime::from_timestamp(n, 0),
+            hash: n.to_string(),
+        }
+        .into()
+    };
+    let cursor = |n_entries: usize| -> Entry {
+        AnalyzerCursor {
+            network: "ckb_dev".to_string(),
+            topic: "ChainCrawler".to_string(),
+            cursor: 1,
+            block_hash: None,
+            updated_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+            n_entries,
+        }
+        .into()
+    };
+    let entries = vec![
+        entry(1),
+        entry(2),
+        entry(3),
+        cursor(2),
+        cursor(0),
+        entry(4),
+        cursor(5),
+        entry(5),
+    ];
+    let units = units(&entries)
+        .into_iter()
+        .map(|unit| unit.len())
+        .collect::<Vec<_>>();
+    assert_eq!(units, vec![1, 3, 1, 2, 1]);
+}
@@ -0,0 +1,32 @@
+//! CKBAnalyzer collects the data of CKB network and stores them into Postgres, or into JSON Lines
+//! and CSV files.
+//!
+//! Besides the built-in topics, a binary can register its own topics:
+//!
+//! ```ignore
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut registry = ckb_analyzer::topic::TopicRegistry::builtin();
+//!     registry.register(MyTopic);
+//!     ckb_analyzer::app::run(registry).await;
+//! }
+//! ```
+
+pub use ckb_testkit::ckb_jsonrpc_types;
+pub use ckb_testkit::ckb_types;
+
+pub mod app;
+pub mod backfill;
+pub mod block_source;
+pub mod config;
+pub mod entry;
+pub mod metrics;
+pub mod migration;
+pub mod postgres;
+pub mod sink;
+pub mod spool;
+pub mod sqlite;
+pub mod supervisor;
+pub mod topic;
+pub mod util;
+pub mod verify;
@@ -0,0 +1,119 @@
+//! Prometheus metrics of CKBAnalyzer itself, served at `/metrics` when `--metrics-addr` is given.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+lazy_static! {
+    pub static ref CHANNEL_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "ckb_analyzer_channel_depth",
+        "The number of messages waiting in the entry channel of the sink",
+        &["sink"]
+    )
+    .unwrap();
+    pub static ref BATCH_SIZE: HistogramVec = register_histogram_vec!(
+        "ckb_analyzer_batch_size",
+        "The number of entries per written batch",
+        &["sink"],
+        exponential_buckets(1.0, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref WRITE_DURATION: HistogramVec = register_histogram_vec!(
+        "ckb_analyzer_write_duration_seconds",
+        "The duration of writing a batch into the sink",
+        &["sink"]
+    )
+    .unwrap();
+    pub static ref WRITE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "ckb_analyzer_write_errors_total",
+        "The number of entries failed to write into the sink",
+        &["sink"]
+    )
+    .unwrap();
+    pub static ref SPOOLED_BATCHES: IntGaugeVec = register_int_gauge_vec!(
+        "ckb_analyzer_spooled_batches",
+        "The number of batches waiting in the spool of the sink",
+        &["sink"]
+    )
+    .unwrap();
+    pub static ref TOPIC_CURSOR: IntGaugeVec = register_int_gauge_vec!(
+        "ckb_analyzer_topic_cursor",
+        "The position the topic has processed, a block number or epoch number",
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref TOPIC_TIP: IntGaugeVec = register_int_gauge_vec!(
+        "ckb_analyzer_topic_tip",
+        "The node tip observed by the topic, in the same unit as ckb_analyzer_topic_cursor",
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref TOPIC_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "ckb_analyzer_topic_restarts_total",
+        "The number of times the topic was restarted by the supervisor",
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref BLOCK_SOURCE_FETCHED_BLOCKS: IntCounter = register_int_counter!(
+        "ckb_analyzer_block_source_fetched_blocks_total",
+        "The number of blocks fetched from CKB node for the block topics"
+    )
+    .unwrap();
+    pub static ref BLOCK_SOURCE_CACHE_HITS: IntCounter = register_int_counter!(
+        "ckb_analyzer_block_source_cache_hits_total",
+        "The number of blocks the block topics got from the block cache"
+    )
+    .unwrap();
+    pub static ref NETWORK_CRAWLER_SESSIONS: IntGauge = register_int_gauge!(
+        "ckb_analyzer_network_crawler_sessions",
+        "The number of p2p sessions held by NetworkCrawler"
+    )
+    .unwrap();
+}
+
+/// Serve `/metrics` on `addr` until the server fails
+pub async fn serve(addr: SocketAddr) {
+    let make_service =
+        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(err) => {
+            log::error!("failed to bind metrics server on {}, error: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    if let Err(err) = server.await {
+        log::error!("metrics server error: {}", err);
+    }
+}
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("failed to encode metrics, error: {}", err);
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+    let mut response = Response::new(Body::from(buffer));
+    if let Ok(content_type) = encoder.format_type().parse() {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    Ok(response)
+}
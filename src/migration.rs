@@ -0,0 +1,336 @@
+//! Versioned schema migrations.
+//!
+//! Migrations are embedded SQL templates under `src/migrations/<postgres|sqlite>/`, numbered from
+//! 1. Each network has its own schema, named by the chain id, see [`schema_name`]; `{network}` in
+//! the templates is replaced with it. The chain id itself is recorded in `{network}.chain`.
+//!
+//! The applied version of each Postgres schema is recorded in `public.analyzer_schema_version`.
+//! SQLite attaches the database file as the network and records the version in its
+//! `user_version`.
+
+use std::fmt;
+use tokio_postgres::Client;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    template: &'static str,
+}
+
+macro_rules! migration {
+    ($backend:expr, $version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            template: include_str!(concat!("migrations/", $backend, "/", $name, ".sql")),
+        }
+    };
+}
+
+// Append only, never modify the applied migrations
+const MIGRATIONS: &[Migration] = &[
+    migration!("postgres", 1, "0001_init"),
+    migration!("postgres", 2, "0002_normalize_keys"),
+    migration!("postgres", 3, "0003_hypertables"),
+    migration!("postgres", 4, "0004_analyzer_cursor"),
+    migration!("postgres", 5, "0005_block_transaction_tx_index"),
+    migration!("postgres", 6, "0006_block_details"),
+    migration!("postgres", 7, "0007_uncle"),
+    migration!("postgres", 8, "0008_proposal"),
+    migration!("postgres", 9, "0009_block_reward"),
+    migration!("postgres", 10, "0010_miner"),
+    migration!("postgres", 11, "0011_cell_scripts"),
+    migration!("postgres", 12, "0012_cell_capacity"),
+    migration!("postgres", 13, "0013_script_kind"),
+    migration!("postgres", 14, "0014_miner_lock_hash"),
+    migration!("postgres", 15, "0015_chain"),
+    migration!("postgres", 16, "0016_cursor_block_hash"),
+    migration!("postgres", 17, "0017_epoch_unique"),
+];
+
+// Append only, never modify the applied migrations
+const SQLITE_MIGRATIONS: &[Migration] = &[
+    migration!("sqlite", 1, "0001_init"),
+    migration!("sqlite", 2, "0002_analyzer_cursor"),
+    migration!("sqlite", 3, "0003_block_transaction_tx_index"),
+    migration!("sqlite", 4, "0004_block_details"),
+    migration!("sqlite", 5, "0005_uncle"),
+    migration!("sqlite", 6, "0006_proposal"),
+    migration!("sqlite", 7, "0007_block_reward"),
+    migration!("sqlite", 8, "0008_miner"),
+    migration!("sqlite", 9, "0009_cell_scripts"),
+    migration!("sqlite", 10, "0010_cell_capacity"),
+    migration!("sqlite", 11, "0011_script_kind"),
+    migration!("sqlite", 12, "0012_miner_lock_hash"),
+    migration!("sqlite", 13, "0013_chain"),
+    migration!("sqlite", 14, "0014_cursor_block_hash"),
+    migration!("sqlite", 15, "0015_epoch_unique"),
+];
+
+const CREATE_VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS public.analyzer_schema_version (
+    network             VARCHAR ( 63 )  PRIMARY KEY NOT NULL,
+    version             INT             NOT NULL,
+    migrated_at         TIMESTAMP       NOT NULL
+)";
+
+const RECORD_CHAIN: &str =
+    "INSERT INTO {network}.chain(chain_id) VALUES ($1) ON CONFLICT (chain_id) DO NOTHING";
+
+#[derive(Debug)]
+pub enum Error {
+    Postgres(tokio_postgres::Error),
+    Sqlite(rusqlite::Error),
+    /// The database was migrated by a newer CKBAnalyzer
+    NewerSchema {
+        network: String,
+        version: i32,
+        supported: i32,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Postgres(err) => write!(f, "{}", err),
+            Error::Sqlite(err) => write!(f, "{}", err),
+            Error::NewerSchema {
+                network,
+                version,
+                supported,
+            } => write!(
+                f,
+                "the schema \"{}\" is at version {}, newer than the supported version {}, upgrade CKBAnalyzer",
+                network, version, supported
+            ),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(err: tokio_postgres::Error) -> Self {
+        Error::Postgres(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+/// The Postgres schema version this binary works with
+pub fn latest_version() -> i32 {
+    last_version(MIGRATIONS)
+}
+
+fn last_version(migrations: &[Migration]) -> i32 {
+    migrations
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or(0)
+}
+
+/// Return the schema name of the chain, which is also the network of the entries.
+///
+/// The chain id is used as is if it can be a schema name without quoting, e.g. "ckb" and
+/// "ckb_testnet". Otherwise, the chain id is lowercased, the other characters are replaced with
+/// underscores, and the hash of the chain id is appended so that distinct chain ids never share a
+/// schema, e.g. "ckb-dev" becomes "ckb_dev_" followed by 8 hex digits.
+pub fn schema_name(chain_id: &str) -> String {
+    if is_valid_network(chain_id) {
+        return chain_id.to_string();
+    }
+
+    // FNV-1a, stable across builds unlike the std hasher
+    let hash = chain_id.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    let mut name = String::from("_");
+    name.extend(chain_id.chars().take(53).map(|c| {
+        let c = c.to_ascii_lowercase();
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            c
+        } else {
+            '_'
+        }
+    }));
+    // Keep the leading underscore only if the sanitized chain id does not start with a letter
+    if name[1..].starts_with(|c: char| c.is_ascii_lowercase()) {
+        name.remove(0);
+    }
+    format!("{}_{:08x}", name, hash)
+}
+
+// Return whether `network` can be used as a schema name without quoting. The network is
+// formatted into statements directly.
+fn is_valid_network(network: &str) -> bool {
+    let mut chars = network.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() || c == '_' => {}
+        _ => return false,
+    }
+    network.len() <= 63 && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Apply the pending migrations of `network` in one transaction, and record `chain_id` as the
+/// chain of the schema.
+///
+/// Return the version before migrating. Return [`Error::NewerSchema`] without touching the
+/// database if the recorded version is newer than [`latest_version`].
+pub async fn migrate(client: &mut Client, network: &str, chain_id: &str) -> Result<i32, Error> {
+    client.batch_execute(CREATE_VERSION_TABLE).await?;
+
+    let transaction = client.transaction().await?;
+    // Serialize the concurrent migrations
+    transaction
+        .batch_execute("LOCK TABLE public.analyzer_schema_version IN EXCLUSIVE MODE")
+        .await?;
+    let version: i32 = transaction
+        .query_opt(
+            "SELECT version FROM public.analyzer_schema_version WHERE network = $1",
+            &[&network],
+        )
+        .await?
+        .map(|row| row.get(0))
+        .unwrap_or(0);
+    let latest = latest_version();
+    if version > latest {
+        return Err(Error::NewerSchema {
+            network: network.to_string(),
+            version,
+            supported: latest,
+        });
+    }
+    if version == latest {
+        transaction
+            .execute(&render(RECORD_CHAIN, network), &[&chain_id])
+            .await?;
+        transaction.commit().await?;
+        return Ok(version);
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        log::info!(
+            "Apply migration {} on schema \"{}\"",
+            migration.name,
+            network
+        );
+        transaction
+            .batch_execute(&render(migration.template, network))
+            .await?;
+    }
+    transaction
+        .execute(
+            "INSERT INTO public.analyzer_schema_version(network, version, migrated_at) \
+            VALUES ($1, $2, $3) \
+            ON CONFLICT (network) DO UPDATE SET version = $2, migrated_at = $3",
+            &[&network, &latest, &chrono::Utc::now().naive_utc()],
+        )
+        .await?;
+    transaction
+        .execute(&render(RECORD_CHAIN, network), &[&chain_id])
+        .await?;
+    transaction.commit().await?;
+    log::info!(
+        "Migrated schema \"{}\" from version {} to {}",
+        network,
+        version,
+        latest
+    );
+    Ok(version)
+}
+
+/// Apply the pending SQLite migrations of `network`, the database attached as `network`, in one
+/// transaction, and record `chain_id` as the chain of the database.
+///
+/// Return the version before migrating, or [`Error::NewerSchema`] if the database was migrated
+/// by a newer CKBAnalyzer.
+pub fn migrate_sqlite(
+    conn: &mut rusqlite::Connection,
+    network: &str,
+    chain_id: &str,
+) -> Result<i32, Error> {
+    // Serialize the concurrent migrations
+    let transaction = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let version: i32 =
+        transaction.query_row(&format!("PRAGMA {}.user_version", network), [], |row| {
+            row.get(0)
+        })?;
+    let latest = last_version(SQLITE_MIGRATIONS);
+    if version > latest {
+        return Err(Error::NewerSchema {
+            network: network.to_string(),
+            version,
+            supported: latest,
+        });
+    }
+    if version == latest {
+        transaction.execute(&render(RECORD_CHAIN, network), [chain_id])?;
+        transaction.commit()?;
+        return Ok(version);
+    }
+
+    for migration in SQLITE_MIGRATIONS.iter().filter(|m| m.version > version) {
+        log::info!(
+            "Apply SQLite migration {} on \"{}\"",
+            migration.name,
+            network
+        );
+        transaction.execute_batch(&render(migration.template, network))?;
+    }
+    transaction.execute_batch(&format!("PRAGMA {}.user_version = {}", network, latest))?;
+    transaction.execute(&render(RECORD_CHAIN, network), [chain_id])?;
+    transaction.commit()?;
+    log::info!(
+        "Migrated SQLite \"{}\" from version {} to {}",
+        network,
+        version,
+        latest
+    );
+    Ok(version)
+}
+
+fn render(template: &str, network: &str) -> String {
+    template.replace("{network}", network)
+}
+
+#[test]
+fn test_migrations() {
+    for (i, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .chain(SQLITE_MIGRATIONS.iter().enumerate())
+    {
+        assert_eq!(migration.version, i as i32 + 1);
+        assert!(migration
+            .name
+            .starts_with(&format!("{:04}_", migration.version)));
+
+        let rendered = render(migration.template, "ckb_dev");
+        assert!(!rendered.contains("{network}"));
+    }
+}
+
+#[test]
+fn test_schema_name() {
+    assert_eq!(schema_name("ckb"), "ckb");
+    assert_eq!(schema_name("ckb_testnet"), "ckb_testnet");
+    assert_eq!(schema_name("_ckb_dev2"), "_ckb_dev2");
+
+    for chain_id in &[
+        "",
+        "2ckb",
+        "ckb-dev",
+        "CKB-Dev",
+        "ckb; DROP TABLE x",
+        &"x".repeat(64),
+    ] {
+        let name = schema_name(chain_id);
+        assert!(is_valid_network(&name), "{:?} => {:?}", chain_id, name);
+        assert_eq!(schema_name(chain_id), name);
+    }
+    assert!(schema_name("ckb-dev").starts_with("ckb_dev_"));
+    assert!(schema_name("2ckb").starts_with("_2ckb_"));
+    assert_ne!(schema_name("ckb-dev"), schema_name("ckb.dev"));
+    assert_ne!(schema_name("ckb-dev"), schema_name("CKB-Dev"));
+}
@@ -0,0 +1,141 @@
+-- Create the schema of the network and its tables
+
+CREATE SCHEMA IF NOT EXISTS {network};
+
+CREATE TABLE IF NOT EXISTS {network}.peer (
+    id                  SERIAL,
+    time                TIMESTAMP       NOT NULL,
+    version             VARCHAR ( 200 ) NOT NULL,
+    ip                  VARCHAR ( 46 )  NOT NULL,
+    n_reachable         INT             NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS {network}.ipinfo (
+    ip                  VARCHAR ( 46 )  PRIMARY KEY NOT NULL,
+    country             VARCHAR ( 20 )  NULL,
+    city                VARCHAR ( 100 )  NULL,
+    loc                 VARCHAR ( 100 )  NULL,
+    region              VARCHAR ( 100 )  NULL,
+    company             VARCHAR ( 100 )  NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.block (
+    time                        TIMESTAMP       NOT NULL,
+    number                      BIGINT          NOT NULL,
+    n_transactions              INT             NOT NULL,
+    n_proposals                 INT             NOT NULL,
+    n_uncles                    INT             NOT NULL,
+    miner_lock_args             VARCHAR ( 100 ) NULL,
+    cellbase_client_version     VARCHAR ( 50 )  NULL,
+    cellbase_miner_source       VARCHAR ( 50 )  NULL,
+    interval                    BIGINT          NOT NULL,
+    hash                        VARCHAR ( 66 )  NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE TABLE IF NOT EXISTS {network}.reorg (
+    time                        TIMESTAMP       NOT NULL,
+    fork_number                 BIGINT          NOT NULL,
+    depth                       BIGINT          NOT NULL,
+    old_tip_hash                VARCHAR ( 66 )  NOT NULL,
+    new_tip_hash                VARCHAR ( 66 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.tx_pool_info (
+    time                TIMESTAMP       NOT NULL,
+    total_tx_cycles     BIGINT          NOT NULL,
+    total_tx_size       BIGINT          NOT NULL,
+    pending             BIGINT          NOT NULL,
+    proposed            BIGINT          NOT NULL,
+    orphan              BIGINT          NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.block_transaction (
+    time                TIMESTAMP       NOT NULL,
+    number              BIGINT          NOT NULL,
+    size                BIGINT          NOT NULL,
+    n_inputs            INT             NOT NULL,
+    n_outputs           INT             NOT NULL,
+    n_header_deps       INT             NOT NULL,
+    n_cell_deps         INT             NOT NULL,
+    total_data_size     BIGINT          NOT NULL,
+    proposal_id         VARCHAR ( 66 )  NOT NULL,
+    hash                VARCHAR ( 66 )  NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_new_transaction (
+    time                TIMESTAMP       NOT NULL,
+    size                BIGINT          NOT NULL,
+    cycles              BIGINT          NOT NULL,
+    fee                 BIGINT          NOT NULL,
+    n_inputs            INT             NOT NULL,
+    n_outputs           INT             NOT NULL,
+    n_header_deps       INT             NOT NULL,
+    n_cell_deps         INT             NOT NULL,
+    proposal_id         VARCHAR ( 66 )  NOT NULL,
+    hash                VARCHAR ( 66 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_proposed_transaction (
+    time                TIMESTAMP       NOT NULL,
+    size                BIGINT          NOT NULL,
+    cycles              BIGINT          NOT NULL,
+    fee                 BIGINT          NOT NULL,
+    n_inputs            INT             NOT NULL,
+    n_outputs           INT             NOT NULL,
+    n_header_deps       INT             NOT NULL,
+    n_cell_deps         INT             NOT NULL,
+    proposal_id         VARCHAR ( 66 )  NOT NULL,
+    hash                VARCHAR ( 66 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_rejected_transaction (
+    time                TIMESTAMP       NOT NULL,
+    reason              VARCHAR ( 60 )  NOT NULL,
+    size                BIGINT          NOT NULL,
+    cycles              BIGINT          NOT NULL,
+    fee                 BIGINT          NOT NULL,
+    n_inputs            INT             NOT NULL,
+    n_outputs           INT             NOT NULL,
+    n_header_deps       INT             NOT NULL,
+    n_cell_deps         INT             NOT NULL,
+    proposal_id         VARCHAR ( 66 )  NOT NULL,
+    hash                VARCHAR ( 66 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.epoch (
+    start_time          TIMESTAMP       NOT NULL,
+    end_time            TIMESTAMP       NOT NULL,
+    number              BIGINT          NOT NULL,
+    length              BIGINT          NOT NULL,
+    start_number        BIGINT          NOT NULL,
+    n_uncles            INT             NOT NULL,
+    difficulty          NUMERIC         NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.retention_transaction (
+    time                TIMESTAMP       NOT NULL,
+    hash                VARCHAR ( 66 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.created_cell (
+    time                   TIMESTAMP       NOT NULL,
+    block_number           BIGINT          NOT NULL,
+    tx_index               INT             NOT NULL,
+    tx_hash                VARCHAR ( 66 )  NOT NULL,
+    index                  INT             NOT NULL,
+    lock_hash_type         INT             NOT NULL,
+    lock_code_hash         VARCHAR ( 66 )  NOT NULL,
+    lock_args              VARCHAR ( 100 ),
+    type_hash_type         INT,
+    type_code_hash         VARCHAR ( 66 ),
+    PRIMARY KEY (time, tx_hash, index)
+);
+CREATE TABLE IF NOT EXISTS {network}.spent_cell (
+    time                   TIMESTAMP       NOT NULL,
+    block_number           BIGINT          NOT NULL,
+    tx_hash                VARCHAR ( 66 )  NOT NULL,
+    index                  BIGINT          NOT NULL,
+    PRIMARY KEY (time, tx_hash, index)
+);
+CREATE TABLE IF NOT EXISTS {network}.compact_block_first_seen (
+    time                        TIMESTAMP       NOT NULL,
+    block_number                BIGINT          NOT NULL,
+    ip                          VARCHAR ( 46 )  NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.peer_last_compact_block (
+    ip                          VARCHAR ( 46 )  PRIMARY KEY NOT NULL,
+    time                        TIMESTAMP       NOT NULL,
+    block_number                BIGINT          NOT NULL,
+    block_hash                  VARCHAR ( 66 )  NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Normalize the schemas created by the old `schema.sql`, which drifted between networks. It is
+-- a no-op for the schemas created by 0001.
+
+-- Blocks crawled by old versions may have no hash. They are kept, and reported by `verify` as
+-- mismatched, so that `verify --repair` crawls them again with their hashes. Only the new rows are
+-- checked; validate the existing ones after repairing via
+-- `ALTER TABLE {network}.block VALIDATE CONSTRAINT block_hash_not_null`.
+ALTER TABLE {network}.block ADD CONSTRAINT block_hash_not_null CHECK (hash IS NOT NULL) NOT VALID;
+
+-- The primary keys of hypertables must contain the time column
+ALTER TABLE {network}.created_cell DROP CONSTRAINT IF EXISTS created_cell_pkey;
+ALTER TABLE {network}.created_cell ADD PRIMARY KEY (time, tx_hash, index);
+ALTER TABLE {network}.spent_cell DROP CONSTRAINT IF EXISTS spent_cell_pkey;
+ALTER TABLE {network}.spent_cell ADD PRIMARY KEY (time, tx_hash, index);
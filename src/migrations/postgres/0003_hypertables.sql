@@ -0,0 +1,19 @@
+-- Convert the time-series tables into hypertables when TimescaleDB is installed. "block" and
+-- "block_transaction" are plain tables, since their primary keys do not contain the time column.
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('{network}.peer', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.reorg', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.epoch', 'start_time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.tx_pool_info', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.subscribed_new_transaction', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.subscribed_proposed_transaction', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.subscribed_rejected_transaction', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.retention_transaction', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.compact_block_first_seen', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.created_cell', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+        PERFORM create_hypertable('{network}.spent_cell', 'time', if_not_exists => TRUE, migrate_data => TRUE);
+    END IF;
+END
+$$;
@@ -0,0 +1,8 @@
+-- The resume cursor of each topic, i.e. the last block or epoch number it committed. It is
+-- written in the same transaction as the rows of the topic. The schema is the network, so the
+-- cursors are keyed by (network, topic).
+CREATE TABLE IF NOT EXISTS {network}.analyzer_cursor (
+    topic               VARCHAR ( 63 )  PRIMARY KEY NOT NULL,
+    cursor              BIGINT          NOT NULL,
+    updated_at          TIMESTAMP       NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Key "block_transaction" by (number, tx_index). It was keyed by number, so only the cellbase,
+-- the first transaction, of each block was stored. Run `backfill --topics
+-- ChainTransactionCrawler --redo` to crawl the other transactions of the stored blocks.
+ALTER TABLE {network}.block_transaction ADD COLUMN IF NOT EXISTS tx_index INT NOT NULL DEFAULT 0;
+ALTER TABLE {network}.block_transaction ALTER COLUMN tx_index DROP DEFAULT;
+ALTER TABLE {network}.block_transaction DROP CONSTRAINT IF EXISTS block_transaction_pkey;
+ALTER TABLE {network}.block_transaction ADD PRIMARY KEY (number, tx_index);
@@ -0,0 +1,16 @@
+-- The size, cycles, difficulty, DAO field, version, epoch and extension of blocks. They are NULL
+-- for the blocks crawled before.
+ALTER TABLE {network}.block
+    ADD COLUMN IF NOT EXISTS size               BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS cycles             BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS compact_target     BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS difficulty         NUMERIC         NULL,
+    ADD COLUMN IF NOT EXISTS dao_c              BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS dao_ar             BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS dao_s              BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS dao_u              BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS version            INT             NULL,
+    ADD COLUMN IF NOT EXISTS epoch_number       BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS epoch_index        INT             NULL,
+    ADD COLUMN IF NOT EXISTS epoch_length       INT             NULL,
+    ADD COLUMN IF NOT EXISTS has_extension      BOOLEAN         NULL;
@@ -0,0 +1,14 @@
+-- Uncles included by the blocks of the main chain. The miner is known only if the node has the
+-- uncle block.
+CREATE TABLE IF NOT EXISTS {network}.uncle (
+    time                        TIMESTAMP       NOT NULL,
+    number                      BIGINT          NOT NULL,
+    hash                        VARCHAR ( 66 )  NOT NULL,
+    block_number                BIGINT          NOT NULL,
+    distance                    BIGINT          NOT NULL,
+    miner_lock_args             VARCHAR ( 100 ) NULL,
+    cellbase_client_version     VARCHAR ( 50 )  NULL,
+    cellbase_miner_source       VARCHAR ( 50 )  NULL,
+    PRIMARY KEY (block_number, hash)
+);
+CREATE INDEX IF NOT EXISTS uncle_time_idx ON {network}.uncle (time);
@@ -0,0 +1,12 @@
+-- Proposals of transactions, recorded once their proposal windows are over. The proposal expired
+-- without being committed if `committed_number` is NULL.
+CREATE TABLE IF NOT EXISTS {network}.proposal (
+    time                TIMESTAMP       NOT NULL,
+    number              BIGINT          NOT NULL,
+    proposal_id         VARCHAR ( 22 )  NOT NULL,
+    committed_number    BIGINT          NULL,
+    commit_distance     BIGINT          NULL,
+    settled_number      BIGINT          NOT NULL,
+    PRIMARY KEY (number, proposal_id)
+);
+CREATE INDEX IF NOT EXISTS proposal_time_idx ON {network}.proposal (time);
@@ -0,0 +1,15 @@
+-- Rewards paid by the cellbases, in shannons. The cellbase of block `number` pays the reward of
+-- block `target_number` to the lock of `lock_hash`.
+CREATE TABLE IF NOT EXISTS {network}.block_reward (
+    time                TIMESTAMP       NOT NULL,
+    number              BIGINT          NOT NULL,
+    target_number       BIGINT          NOT NULL,
+    lock_hash           VARCHAR ( 66 )  NOT NULL,
+    primary_reward      BIGINT          NOT NULL,
+    secondary_reward    BIGINT          NOT NULL,
+    commit_reward       BIGINT          NOT NULL,
+    proposal_reward     BIGINT          NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE INDEX IF NOT EXISTS block_reward_time_idx ON {network}.block_reward (time);
+CREATE INDEX IF NOT EXISTS block_reward_lock_hash_idx ON {network}.block_reward (lock_hash);
@@ -0,0 +1,16 @@
+-- The mining pool of blocks, named by the miner registry. It is NULL for the blocks of unknown
+-- miners and the blocks crawled before.
+ALTER TABLE {network}.block
+    ADD COLUMN IF NOT EXISTS miner              VARCHAR ( 50 )  NULL;
+
+-- The miners seen, keyed by the args of the miner lock. `miner` is the pool name, NULL if the
+-- miner is not in the registry.
+CREATE TABLE IF NOT EXISTS {network}.miner (
+    miner_lock_args     VARCHAR ( 100 ) NOT NULL,
+    miner               VARCHAR ( 50 )  NULL,
+    first_number        BIGINT          NOT NULL,
+    first_time          TIMESTAMP       NOT NULL,
+    last_number         BIGINT          NOT NULL,
+    last_time           TIMESTAMP       NOT NULL,
+    PRIMARY KEY (miner_lock_args)
+);
@@ -0,0 +1,11 @@
+-- The full lock and type scripts of created cells. `lock_args` was only kept for the default
+-- secp256k1 lock, it becomes NULL for the other cells crawled before, the same as the new
+-- columns.
+ALTER TABLE {network}.created_cell
+    ALTER COLUMN lock_args TYPE BYTEA USING decode(NULLIF(regexp_replace(lock_args, '^0x', ''), ''), 'hex');
+ALTER TABLE {network}.created_cell
+    ADD COLUMN IF NOT EXISTS lock_hash          VARCHAR ( 66 )  NULL,
+    ADD COLUMN IF NOT EXISTS type_hash          VARCHAR ( 66 )  NULL,
+    ADD COLUMN IF NOT EXISTS type_args          BYTEA           NULL;
+CREATE INDEX IF NOT EXISTS created_cell_lock_hash_idx ON {network}.created_cell (lock_hash, time DESC);
+CREATE INDEX IF NOT EXISTS created_cell_type_hash_idx ON {network}.created_cell (type_hash, time DESC);
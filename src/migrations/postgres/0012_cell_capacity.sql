@@ -0,0 +1,8 @@
+-- The capacity, occupied capacity and data size of created cells, and the capacity of spent
+-- cells, in shannons. They are NULL for the cells crawled before.
+ALTER TABLE {network}.created_cell
+    ADD COLUMN IF NOT EXISTS capacity           BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS occupied_capacity  BIGINT          NULL,
+    ADD COLUMN IF NOT EXISTS data_size          INT             NULL;
+ALTER TABLE {network}.spent_cell
+    ADD COLUMN IF NOT EXISTS capacity           BIGINT          NULL;
@@ -0,0 +1,7 @@
+-- The kinds of the well-known scripts of created cells and transactions, such as "sudt" or
+-- "nervos_dao_deposit". They are NULL for the rows crawled before.
+ALTER TABLE {network}.created_cell
+    ADD COLUMN IF NOT EXISTS script_kind        VARCHAR ( 50 )  NULL;
+ALTER TABLE {network}.block_transaction
+    ADD COLUMN IF NOT EXISTS script_kinds       TEXT            NULL;
+CREATE INDEX IF NOT EXISTS created_cell_script_kind_idx ON {network}.created_cell (script_kind, time DESC);
@@ -0,0 +1,6 @@
+-- The chain ids of the schema. The schema is named by the chain id, or a sanitized name for the
+-- chain ids that cannot be a schema name, so the chain id is recorded as is.
+CREATE TABLE IF NOT EXISTS {network}.chain (
+    chain_id            VARCHAR         NOT NULL,
+    PRIMARY KEY (chain_id)
+);
@@ -0,0 +1,4 @@
+-- The hash of the cursor block of the block topics, so that a reorg happened while CKBAnalyzer was
+-- stopped is detected on restart. NULL for the other topics and the cursors committed before.
+ALTER TABLE {network}.analyzer_cursor
+    ADD COLUMN IF NOT EXISTS block_hash         VARCHAR ( 66 )  NULL;
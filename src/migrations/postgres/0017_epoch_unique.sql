@@ -0,0 +1,6 @@
+-- Each epoch is recorded once, EpochCrawler restarting from an older cursor no longer duplicates
+-- the epochs. The unique key of a hypertable must include its time column, and the start time of
+-- an epoch is determined by its number.
+DELETE FROM {network}.epoch a USING {network}.epoch b
+    WHERE a.number = b.number AND a.start_time = b.start_time AND a.ctid < b.ctid;
+CREATE UNIQUE INDEX IF NOT EXISTS epoch_number_idx ON {network}.epoch (number, start_time);
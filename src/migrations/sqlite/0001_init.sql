@@ -0,0 +1,148 @@
+-- Create the tables of the network, in the database attached as the network
+--
+-- The layout follows the Postgres schema. Timestamps are stored as ISO-8601 text, and
+-- `epoch.difficulty` as decimal text to keep its precision.
+
+CREATE TABLE IF NOT EXISTS {network}.peer (
+    id                  INTEGER         PRIMARY KEY AUTOINCREMENT,
+    time                TEXT            NOT NULL,
+    version             TEXT            NOT NULL,
+    ip                  TEXT            NOT NULL,
+    n_reachable         INTEGER         NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS {network}.ipinfo (
+    ip                  TEXT            PRIMARY KEY NOT NULL,
+    country             TEXT            NULL,
+    city                TEXT            NULL,
+    loc                 TEXT            NULL,
+    region              TEXT            NULL,
+    company             TEXT            NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.block (
+    time                        TEXT            NOT NULL,
+    number                      INTEGER         NOT NULL,
+    n_transactions              INTEGER         NOT NULL,
+    n_proposals                 INTEGER         NOT NULL,
+    n_uncles                    INTEGER         NOT NULL,
+    miner_lock_args             TEXT            NULL,
+    cellbase_client_version     TEXT            NULL,
+    cellbase_miner_source       TEXT            NULL,
+    interval                    INTEGER         NOT NULL,
+    hash                        TEXT            NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE TABLE IF NOT EXISTS {network}.reorg (
+    time                        TEXT            NOT NULL,
+    fork_number                 INTEGER         NOT NULL,
+    depth                       INTEGER         NOT NULL,
+    old_tip_hash                TEXT            NOT NULL,
+    new_tip_hash                TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.tx_pool_info (
+    time                TEXT            NOT NULL,
+    total_tx_cycles     INTEGER         NOT NULL,
+    total_tx_size       INTEGER         NOT NULL,
+    pending             INTEGER         NOT NULL,
+    proposed            INTEGER         NOT NULL,
+    orphan              INTEGER         NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.block_transaction (
+    time                TEXT            NOT NULL,
+    number              INTEGER         NOT NULL,
+    size                INTEGER         NOT NULL,
+    n_inputs            INTEGER         NOT NULL,
+    n_outputs           INTEGER         NOT NULL,
+    n_header_deps       INTEGER         NOT NULL,
+    n_cell_deps         INTEGER         NOT NULL,
+    total_data_size     INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    hash                TEXT            NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_new_transaction (
+    time                TEXT            NOT NULL,
+    size                INTEGER         NOT NULL,
+    cycles              INTEGER         NOT NULL,
+    fee                 INTEGER         NOT NULL,
+    n_inputs            INTEGER         NOT NULL,
+    n_outputs           INTEGER         NOT NULL,
+    n_header_deps       INTEGER         NOT NULL,
+    n_cell_deps         INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    hash                TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_proposed_transaction (
+    time                TEXT            NOT NULL,
+    size                INTEGER         NOT NULL,
+    cycles              INTEGER         NOT NULL,
+    fee                 INTEGER         NOT NULL,
+    n_inputs            INTEGER         NOT NULL,
+    n_outputs           INTEGER         NOT NULL,
+    n_header_deps       INTEGER         NOT NULL,
+    n_cell_deps         INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    hash                TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.subscribed_rejected_transaction (
+    time                TEXT            NOT NULL,
+    reason              TEXT            NOT NULL,
+    size                INTEGER         NOT NULL,
+    cycles              INTEGER         NOT NULL,
+    fee                 INTEGER         NOT NULL,
+    n_inputs            INTEGER         NOT NULL,
+    n_outputs           INTEGER         NOT NULL,
+    n_header_deps       INTEGER         NOT NULL,
+    n_cell_deps         INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    hash                TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.epoch (
+    start_time          TEXT            NOT NULL,
+    end_time            TEXT            NOT NULL,
+    number              INTEGER         NOT NULL,
+    length              INTEGER         NOT NULL,
+    start_number        INTEGER         NOT NULL,
+    n_uncles            INTEGER         NOT NULL,
+    difficulty          TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.retention_transaction (
+    time                TEXT            NOT NULL,
+    hash                TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.created_cell (
+    time                   TEXT            NOT NULL,
+    block_number           INTEGER         NOT NULL,
+    tx_index               INTEGER         NOT NULL,
+    tx_hash                TEXT            NOT NULL,
+    "index"                INTEGER         NOT NULL,
+    lock_hash_type         INTEGER         NOT NULL,
+    lock_code_hash         TEXT            NOT NULL,
+    lock_args              TEXT,
+    type_hash_type         INTEGER,
+    type_code_hash         TEXT,
+    PRIMARY KEY (time, tx_hash, "index")
+);
+CREATE TABLE IF NOT EXISTS {network}.spent_cell (
+    time                   TEXT            NOT NULL,
+    block_number           INTEGER         NOT NULL,
+    tx_hash                TEXT            NOT NULL,
+    "index"                INTEGER         NOT NULL,
+    PRIMARY KEY (time, tx_hash, "index")
+);
+CREATE TABLE IF NOT EXISTS {network}.compact_block_first_seen (
+    time                        TEXT            NOT NULL,
+    block_number                INTEGER         NOT NULL,
+    ip                          TEXT            NOT NULL
+);
+CREATE TABLE IF NOT EXISTS {network}.peer_last_compact_block (
+    ip                          TEXT            PRIMARY KEY NOT NULL,
+    time                        TEXT            NOT NULL,
+    block_number                INTEGER         NOT NULL,
+    block_hash                  TEXT            NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS {network}.block_time_idx ON block (time);
+CREATE INDEX IF NOT EXISTS {network}.block_transaction_time_idx ON block_transaction (time);
+CREATE INDEX IF NOT EXISTS {network}.epoch_start_time_idx ON epoch (start_time);
+CREATE INDEX IF NOT EXISTS {network}.created_cell_block_number_idx ON created_cell (block_number);
+CREATE INDEX IF NOT EXISTS {network}.spent_cell_block_number_idx ON spent_cell (block_number);
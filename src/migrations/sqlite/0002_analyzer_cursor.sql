@@ -0,0 +1,7 @@
+-- The resume cursor of each topic, i.e. the last block or epoch number it committed. It is
+-- written in the same transaction as the rows of the topic.
+CREATE TABLE IF NOT EXISTS {network}.analyzer_cursor (
+    topic               TEXT            PRIMARY KEY NOT NULL,
+    cursor              INTEGER         NOT NULL,
+    updated_at          TEXT            NOT NULL
+);
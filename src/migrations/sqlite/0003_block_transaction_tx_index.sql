@@ -0,0 +1,25 @@
+-- Key "block_transaction" by (number, tx_index). It was keyed by number, so only the cellbase,
+-- the first transaction, of each block was stored. Run `backfill --topics
+-- ChainTransactionCrawler --redo` to crawl the other transactions of the stored blocks.
+--
+-- SQLite cannot alter the primary key, the table is rebuilt.
+CREATE TABLE {network}.block_transaction_rebuilt (
+    time                TEXT            NOT NULL,
+    number              INTEGER         NOT NULL,
+    tx_index            INTEGER         NOT NULL,
+    size                INTEGER         NOT NULL,
+    n_inputs            INTEGER         NOT NULL,
+    n_outputs           INTEGER         NOT NULL,
+    n_header_deps       INTEGER         NOT NULL,
+    n_cell_deps         INTEGER         NOT NULL,
+    total_data_size     INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    hash                TEXT            NOT NULL,
+    PRIMARY KEY (number, tx_index)
+);
+INSERT INTO {network}.block_transaction_rebuilt
+    SELECT time, number, 0, size, n_inputs, n_outputs, n_header_deps, n_cell_deps, total_data_size, proposal_id, hash
+    FROM {network}.block_transaction;
+DROP TABLE {network}.block_transaction;
+ALTER TABLE {network}.block_transaction_rebuilt RENAME TO block_transaction;
+CREATE INDEX IF NOT EXISTS {network}.block_transaction_time_idx ON block_transaction (time);
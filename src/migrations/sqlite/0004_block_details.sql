@@ -0,0 +1,15 @@
+-- The size, cycles, difficulty, DAO field, version, epoch and extension of blocks. They are NULL
+-- for the blocks crawled before.
+ALTER TABLE {network}.block ADD COLUMN size INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN cycles INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN compact_target INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN difficulty TEXT NULL;
+ALTER TABLE {network}.block ADD COLUMN dao_c INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN dao_ar INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN dao_s INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN dao_u INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN version INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN epoch_number INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN epoch_index INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN epoch_length INTEGER NULL;
+ALTER TABLE {network}.block ADD COLUMN has_extension INTEGER NULL;
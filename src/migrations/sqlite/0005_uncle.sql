@@ -0,0 +1,14 @@
+-- Uncles included by the blocks of the main chain. The miner is known only if the node has the
+-- uncle block.
+CREATE TABLE IF NOT EXISTS {network}.uncle (
+    time                        TEXT            NOT NULL,
+    number                      INTEGER         NOT NULL,
+    hash                        TEXT            NOT NULL,
+    block_number                INTEGER         NOT NULL,
+    distance                    INTEGER         NOT NULL,
+    miner_lock_args             TEXT            NULL,
+    cellbase_client_version     TEXT            NULL,
+    cellbase_miner_source       TEXT            NULL,
+    PRIMARY KEY (block_number, hash)
+);
+CREATE INDEX IF NOT EXISTS {network}.uncle_time_idx ON uncle (time);
@@ -0,0 +1,12 @@
+-- Proposals of transactions, recorded once their proposal windows are over. The proposal expired
+-- without being committed if `committed_number` is NULL.
+CREATE TABLE IF NOT EXISTS {network}.proposal (
+    time                TEXT            NOT NULL,
+    number              INTEGER         NOT NULL,
+    proposal_id         TEXT            NOT NULL,
+    committed_number    INTEGER         NULL,
+    commit_distance     INTEGER         NULL,
+    settled_number      INTEGER         NOT NULL,
+    PRIMARY KEY (number, proposal_id)
+);
+CREATE INDEX IF NOT EXISTS {network}.proposal_time_idx ON proposal (time);
@@ -0,0 +1,15 @@
+-- Rewards paid by the cellbases, in shannons. The cellbase of block `number` pays the reward of
+-- block `target_number` to the lock of `lock_hash`.
+CREATE TABLE IF NOT EXISTS {network}.block_reward (
+    time                TEXT            NOT NULL,
+    number              INTEGER         NOT NULL,
+    target_number       INTEGER         NOT NULL,
+    lock_hash           TEXT            NOT NULL,
+    primary_reward      INTEGER         NOT NULL,
+    secondary_reward    INTEGER         NOT NULL,
+    commit_reward       INTEGER         NOT NULL,
+    proposal_reward     INTEGER         NOT NULL,
+    PRIMARY KEY (number)
+);
+CREATE INDEX IF NOT EXISTS {network}.block_reward_time_idx ON block_reward (time);
+CREATE INDEX IF NOT EXISTS {network}.block_reward_lock_hash_idx ON block_reward (lock_hash);
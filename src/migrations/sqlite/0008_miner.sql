@@ -0,0 +1,15 @@
+-- The mining pool of blocks, named by the miner registry. It is NULL for the blocks of unknown
+-- miners and the blocks crawled before.
+ALTER TABLE {network}.block ADD COLUMN miner TEXT NULL;
+
+-- The miners seen, keyed by the args of the miner lock. `miner` is the pool name, NULL if the
+-- miner is not in the registry.
+CREATE TABLE IF NOT EXISTS {network}.miner (
+    miner_lock_args     TEXT            NOT NULL,
+    miner               TEXT            NULL,
+    first_number        INTEGER         NOT NULL,
+    first_time          TEXT            NOT NULL,
+    last_number         INTEGER         NOT NULL,
+    last_time           TEXT            NOT NULL,
+    PRIMARY KEY (miner_lock_args)
+);
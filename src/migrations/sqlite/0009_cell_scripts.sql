@@ -0,0 +1,7 @@
+-- The full lock and type scripts of created cells. The args are hex strings, `lock_args` was only
+-- kept for the default secp256k1 lock before.
+ALTER TABLE {network}.created_cell ADD COLUMN lock_hash TEXT NULL;
+ALTER TABLE {network}.created_cell ADD COLUMN type_hash TEXT NULL;
+ALTER TABLE {network}.created_cell ADD COLUMN type_args TEXT NULL;
+CREATE INDEX IF NOT EXISTS {network}.created_cell_lock_hash_idx ON created_cell (lock_hash);
+CREATE INDEX IF NOT EXISTS {network}.created_cell_type_hash_idx ON created_cell (type_hash);
@@ -0,0 +1,6 @@
+-- The capacity, occupied capacity and data size of created cells, and the capacity of spent
+-- cells, in shannons. They are NULL for the cells crawled before.
+ALTER TABLE {network}.created_cell ADD COLUMN capacity INTEGER NULL;
+ALTER TABLE {network}.created_cell ADD COLUMN occupied_capacity INTEGER NULL;
+ALTER TABLE {network}.created_cell ADD COLUMN data_size INTEGER NULL;
+ALTER TABLE {network}.spent_cell ADD COLUMN capacity INTEGER NULL;
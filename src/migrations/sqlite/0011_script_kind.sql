@@ -0,0 +1,5 @@
+-- The kinds of the well-known scripts of created cells and transactions, such as "sudt" or
+-- "nervos_dao_deposit". They are NULL for the rows crawled before.
+ALTER TABLE {network}.created_cell ADD COLUMN script_kind TEXT NULL;
+ALTER TABLE {network}.block_transaction ADD COLUMN script_kinds TEXT NULL;
+CREATE INDEX IF NOT EXISTS {network}.created_cell_script_kind_idx ON created_cell (script_kind);
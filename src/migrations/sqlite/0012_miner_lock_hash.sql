@@ -0,0 +1,18 @@
+-- The hash of the miner lock of blocks, NULL for the blocks crawled before. The `miner_lock_args`
+-- of the blocks crawled before include the 4-byte length header of the args.
+ALTER TABLE {network}.block ADD COLUMN miner_lock_hash TEXT NULL;
+
+-- The miners are keyed by the hash of the miner lock, since the miners not using the default
+-- secp256k1 lock shared the same `miner_lock_args`, "-". The miners are derived from the blocks,
+-- they are recorded again by crawling, or by `backfill --redo` for the blocks crawled before.
+DROP TABLE IF EXISTS {network}.miner;
+CREATE TABLE IF NOT EXISTS {network}.miner (
+    miner_lock_hash     TEXT            NOT NULL,
+    miner_lock_args     TEXT            NOT NULL,
+    miner               TEXT            NULL,
+    first_number        INTEGER         NOT NULL,
+    first_time          TEXT            NOT NULL,
+    last_number         INTEGER         NOT NULL,
+    last_time           TEXT            NOT NULL,
+    PRIMARY KEY (miner_lock_hash)
+);
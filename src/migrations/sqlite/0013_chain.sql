@@ -0,0 +1,6 @@
+-- The chain ids of the database. The database is attached by the chain id, or a sanitized name
+-- for the chain ids that cannot be a schema name, so the chain id is recorded as is.
+CREATE TABLE IF NOT EXISTS {network}.chain (
+    chain_id            TEXT            NOT NULL,
+    PRIMARY KEY (chain_id)
+);
@@ -0,0 +1,3 @@
+-- The hash of the cursor block of the block topics, so that a reorg happened while CKBAnalyzer was
+-- stopped is detected on restart. NULL for the other topics and the cursors committed before.
+ALTER TABLE {network}.analyzer_cursor ADD COLUMN block_hash TEXT NULL;
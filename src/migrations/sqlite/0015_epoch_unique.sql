@@ -0,0 +1,4 @@
+-- Each epoch is recorded once, EpochCrawler restarting from an older cursor no longer duplicates
+-- the epochs.
+DELETE FROM {network}.epoch WHERE rowid NOT IN (SELECT MIN(rowid) FROM {network}.epoch GROUP BY number);
+CREATE UNIQUE INDEX IF NOT EXISTS {network}.epoch_number_idx ON epoch (number);
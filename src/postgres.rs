@@ -0,0 +1,59 @@
+use crate::migration::{self, migrate};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, Config, NoTls};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Postgres is a shared handle of the Postgres client, it reconnects when the connection is lost.
+///
+/// It does not connect until the client is requested, so CKBAnalyzer can start while Postgres
+/// is unavailable. Once connected, it migrates the schema of `network` to the latest version and
+/// records `chain_id` as its chain.
+pub struct Postgres {
+    config: Config,
+    network: String,
+    chain_id: String,
+    client: Mutex<Option<Arc<Client>>>,
+}
+
+impl Postgres {
+    pub fn new(mut config: Config, network: String, chain_id: String) -> Self {
+        config.connect_timeout(CONNECT_TIMEOUT);
+        Self {
+            config,
+            network,
+            chain_id,
+            client: Mutex::new(None),
+        }
+    }
+
+    /// Return the connected client, reconnect if the previous connection was closed
+    pub async fn client(&self) -> Result<Arc<Client>, migration::Error> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref() {
+            if !client.is_closed() {
+                return Ok(Arc::clone(client));
+            }
+        }
+
+        let connected = Arc::new(self.connect().await?);
+        *client = Some(Arc::clone(&connected));
+        Ok(connected)
+    }
+
+    /// Open a new connection owned by the caller, such as the postgres sink running its own
+    /// transactions
+    pub async fn connect(&self) -> Result<Client, migration::Error> {
+        log::info!("Connecting to Postgres, {:?}", self.config);
+        let (mut connected, conn) = self.config.connect(NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                log::error!("postgres connection error: {}", err);
+            }
+        });
+        migrate(&mut connected, &self.network, &self.chain_id).await?;
+        Ok(connected)
+    }
+}
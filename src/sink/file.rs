@@ -0,0 +1,365 @@
+use crate::entry::{AnalyzerCursor, Entry};
+use crate::metrics;
+use crate::sink::{Sink, SinkKind};
+use chrono::{NaiveDate, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The file formats supported by [`FileSink`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    JsonLines,
+    Csv,
+}
+
+// The committed cursors, #{ network => #{ topic => cursor } }
+type Cursors = BTreeMap<String, BTreeMap<String, FileCursor>>;
+
+/// The cursor a topic committed into the files, see [`read_cursor`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCursor {
+    pub cursor: u64,
+    pub block_hash: Option<String>,
+}
+
+impl FileFormat {
+    /// Return the format of the file sink `kind`, `None` for the database sinks
+    pub fn from_kind(kind: SinkKind) -> Option<Self> {
+        match kind {
+            SinkKind::JsonLines => Some(FileFormat::JsonLines),
+            SinkKind::Csv => Some(FileFormat::Csv),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> SinkKind {
+        match self {
+            FileFormat::JsonLines => SinkKind::JsonLines,
+            FileFormat::Csv => SinkKind::Csv,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        self.kind().name()
+    }
+
+    // The file of the committed cursors. The formats share the directory, so each has its own.
+    fn cursor_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("analyzer_cursor.{}.json", self.extension()))
+    }
+
+    // Encode the entry as a line, preceded by the header line if `with_header`
+    fn encode(&self, entry: &Entry, with_header: bool) -> io::Result<Vec<u8>> {
+        match self {
+            FileFormat::JsonLines => {
+                let mut line = serde_json::to_vec(&entry.row())?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            FileFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(with_header)
+                    .from_writer(Vec::new());
+                writer.serialize(entry.row())?;
+                writer.into_inner().map_err(|err| err.into_error())
+            }
+        }
+    }
+}
+
+/// FileSink appends entries to files under `dir`, one file per table, named
+/// `<table>.<date>.<seq>.<extension>`.
+///
+/// A file is rotated when the UTC date changes or its size reaches `max_file_size` bytes. The
+/// files left by the previous runs are appended until they are full, and a new CSV file starts
+/// with the header line.
+///
+/// The cursors of the topics are not rows. They are kept in `analyzer_cursor.<extension>.json`,
+/// replaced after the entries of each batch are written, so that the topics resume from them.
+pub struct FileSink {
+    format: FileFormat,
+    dir: PathBuf,
+    max_file_size: u64,
+    files: HashMap<&'static str, TableFile>,
+    cursors: Cursors,
+    healthy: bool,
+}
+
+struct TableFile {
+    date: NaiveDate,
+    seq: u32,
+    size: u64,
+    file: File,
+}
+
+impl FileSink {
+    /// Create the sink writing into `dir`, creating the directory if it does not exist
+    pub fn new<P: AsRef<Path>>(format: FileFormat, dir: P, max_file_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let cursors = read_cursors(format, dir.as_ref())?;
+        Ok(Self {
+            format,
+            dir: dir.as_ref().to_path_buf(),
+            max_file_size,
+            files: Default::default(),
+            cursors,
+            healthy: true,
+        })
+    }
+
+    fn write_entries(&mut self, entries: &[Entry]) -> Vec<Entry> {
+        let label = self.format.extension();
+        let _timer = metrics::WRITE_DURATION
+            .with_label_values(&[label])
+            .start_timer();
+        let mut committed = Vec::new();
+        let mut unwritten = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if let Entry::AnalyzerCursor(cursor) = entry {
+                self.commit_cursor(cursor);
+                committed.push(entry.clone());
+                continue;
+            }
+            if let Err(err) = self.write_entry(entry) {
+                metrics::WRITE_ERRORS
+                    .with_label_values(&[label])
+                    .inc_by((entries.len() - i) as u64);
+                log::error!(
+                    "failed to write {:?} into {} file, error: {}",
+                    entry,
+                    label,
+                    err
+                );
+                self.healthy = false;
+                unwritten = entries[i..].to_vec();
+                break;
+            }
+        }
+        if !committed.is_empty() {
+            // The cursors are committed after their entries, retry them if not persisted
+            if let Err(err) = self.write_cursors() {
+                log::error!(
+                    "failed to write the cursors into \"{}\", error: {}",
+                    self.format.cursor_path(&self.dir).display(),
+                    err
+                );
+                self.healthy = false;
+                committed.extend(unwritten);
+                return committed;
+            }
+        }
+        if unwritten.is_empty() {
+            self.healthy = true;
+        }
+        unwritten
+    }
+
+    fn commit_cursor(&mut self, cursor: &AnalyzerCursor) {
+        self.cursors
+            .entry(cursor.network.clone())
+            .or_default()
+            .insert(
+                cursor.topic.clone(),
+                FileCursor {
+                    cursor: cursor.cursor as u64,
+                    block_hash: cursor.block_hash.clone(),
+                },
+            );
+    }
+
+    // Replace the cursor file, via a temporary file so that it is never partially written
+    fn write_cursors(&self) -> io::Result<()> {
+        let path = self.format.cursor_path(&self.dir);
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.cursors)?)?;
+        fs::rename(&temporary, &path)
+    }
+
+    fn write_entry(&mut self, entry: &Entry) -> io::Result<()> {
+        let table = entry.table();
+        let today = Utc::now().naive_utc().date();
+        let next_seq = match self.files.get(table) {
+            None => Some(0),
+            Some(opened) if opened.date != today => Some(0),
+            Some(opened) if opened.size >= self.max_file_size => Some(opened.seq + 1),
+            Some(_) => None,
+        };
+        if let Some(seq) = next_seq {
+            let opened = self.open(table, today, seq)?;
+            self.files.insert(table, opened);
+        }
+
+        let opened = self.files.get_mut(table).expect("opened above");
+        let line = self.format.encode(entry, opened.size == 0)?;
+        opened.file.write_all(&line)?;
+        opened.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Open the first file of `table` which is not full, starting from `seq`
+    fn open(&self, table: &str, date: NaiveDate, mut seq: u32) -> io::Result<TableFile> {
+        loop {
+            let path = self.dir.join(format!(
+                "{}.{}.{}.{}",
+                table,
+                date,
+                seq,
+                self.format.extension()
+            ));
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err),
+            };
+            if size < self.max_file_size {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                log::info!("Write {} into \"{}\"", table, path.display());
+                return Ok(TableFile {
+                    date,
+                    seq,
+                    size,
+                    file,
+                });
+            }
+            seq += 1;
+        }
+    }
+}
+
+fn read_cursors(format: FileFormat, dir: &Path) -> io::Result<Cursors> {
+    match fs::read(format.cursor_path(dir)) {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Cursors::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Return the cursor `topic` committed into the files of `format` under `dir`, `None` if it
+/// has not committed any
+pub fn read_cursor(
+    format: FileFormat,
+    dir: &Path,
+    network: &str,
+    topic: &str,
+) -> io::Result<Option<FileCursor>> {
+    Ok(read_cursors(format, dir)?
+        .get(network)
+        .and_then(|cursors| cursors.get(topic))
+        .cloned())
+}
+
+impl Sink for FileSink {
+    fn write<'a>(&'a mut self, entries: &'a [Entry]) -> BoxFuture<'a, Vec<Entry>> {
+        let unwritten = self.write_entries(entries);
+        Box::pin(async move { unwritten })
+    }
+
+    fn is_connected(&self) -> bool {
+        self.healthy
+    }
+}
+
+#[test]
+fn test_file_sink_rotation() {
+    use crate::entry::Reorg;
+
+    let dir = ::std::env::temp_dir().join(format!("ckb-analyzer-file-sink-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let reorg: Entry = Reorg {
+        network: "ckb_dev".to_string(),
+        time: chrono::NaiveDateTime::from_timestamp(0, 0),
+        fork_number: 1,
+        depth: 2,
+        old_tip_hash: "0x01".to_string(),
+        new_tip_hash: "0x02".to_string(),
+    }
+    .into();
+
+    // Every file is full after one row
+    let mut sink = FileSink::new(FileFormat::Csv, &dir, 1).unwrap();
+    assert!(sink
+        .write_entries(&[reorg.clone(), reorg.clone()])
+        .is_empty());
+    let today = Utc::now().naive_utc().date();
+    for seq in 0..2 {
+        let path = dir.join(format!("reorg.{}.{}.csv", today, seq));
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "network,time,fork_number,depth,old_tip_hash,new_tip_hash\n\
+            ckb_dev,1970-01-01T00:00:00,1,2,0x01,0x02\n"
+        );
+    }
+
+    // Continue after the full files left by the previous run
+    let mut sink = FileSink::new(FileFormat::Csv, &dir, 1).unwrap();
+    assert!(sink.write_entries(&[reorg.clone()]).is_empty());
+    let path = dir.join(format!("reorg.{}.2.csv", today));
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+    let mut sink = FileSink::new(FileFormat::JsonLines, &dir, 1024).unwrap();
+    assert!(sink.write_entries(&[reorg.clone(), reorg]).is_empty());
+    let path = dir.join(format!("reorg.{}.0.jsonl", today));
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_file_sink_cursor() {
+    use crate::entry::RetentionTransaction;
+
+    let dir =
+        ::std::env::temp_dir().join(format!("ckb-analyzer-file-cursor-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let transaction: Entry = RetentionTransaction {
+        network: "ckb_dev".to_string(),
+        time: chrono::NaiveDateTime::from_timestamp(0, 0),
+        hash: "0x01".to_string(),
+    }
+    .into();
+    let cursor = |cursor: i64| -> Entry {
+        AnalyzerCursor {
+            network: "ckb_dev".to_string(),
+            topic: "ChainCrawler".to_string(),
+            cursor,
+            block_hash: Some(format!("{:#x}", cursor)),
+            updated_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+            n_entries: 1,
+        }
+        .into()
+    };
+
+    let mut sink = FileSink::new(FileFormat::JsonLines, &dir, 1024).unwrap();
+    let entries = vec![transaction.clone(), cursor(1), transaction, cursor(2)];
+    assert!(sink.write_entries(&entries).is_empty());
+    let read = |format| read_cursor(format, &dir, "ckb_dev", "ChainCrawler").unwrap();
+    assert_eq!(
+        read(FileFormat::JsonLines),
+        Some(FileCursor {
+            cursor: 2,
+            block_hash: Some("0x2".to_string()),
+        })
+    );
+    assert_eq!(read(FileFormat::Csv), None);
+    assert_eq!(
+        read_cursor(FileFormat::JsonLines, &dir, "ckb_dev", "CellCrawler").unwrap(),
+        None
+    );
+
+    // The cursors of the other topics are kept across runs
+    let mut sink = FileSink::new(FileFormat::JsonLines, &dir, 1024).unwrap();
+    let mut other = cursor(5);
+    if let Entry::AnalyzerCursor(cursor) = &mut other {
+        cursor.topic = "CellCrawler".to_string();
+        cursor.n_entries = 0;
+    }
+    assert!(sink.write_entries(&[other]).is_empty());
+    assert_eq!(read(FileFormat::JsonLines).unwrap().cursor, 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,112 @@
+//! Sinks are where the entries go. Each topic sends its entries to one sink, selected by
+//! `--sink` or the config `sink`, so that CKBAnalyzer can run without Postgres, e.g. for an
+//! ad-hoc investigation, or as a self-contained collector writing into a SQLite file.
+
+use crate::entry::Entry;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+mod file;
+mod postgres;
+mod sqlite;
+
+pub use file::{read_cursor, FileCursor, FileFormat, FileSink};
+pub use postgres::PostgresSink;
+pub use sqlite::SqliteSink;
+
+/// The kinds of sinks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum SinkKind {
+    #[serde(rename = "postgres")]
+    Postgres,
+    /// A SQLite database file, with the same tables as Postgres
+    #[serde(rename = "sqlite")]
+    Sqlite,
+    /// JSON Lines files, one file per table
+    #[serde(rename = "jsonl")]
+    JsonLines,
+    /// CSV files with header, one file per table
+    #[serde(rename = "csv")]
+    Csv,
+}
+
+impl SinkKind {
+    pub const ALL: &'static [SinkKind] = &[
+        SinkKind::Postgres,
+        SinkKind::Sqlite,
+        SinkKind::JsonLines,
+        SinkKind::Csv,
+    ];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Postgres => "postgres",
+            SinkKind::Sqlite => "sqlite",
+            SinkKind::JsonLines => "jsonl",
+            SinkKind::Csv => "csv",
+        }
+    }
+
+    /// The filename of the spool of this sink under the data directory
+    pub fn spool_filename(&self) -> String {
+        match self {
+            // Keep the filename used before the sinks were introduced
+            SinkKind::Postgres => "spool.jsonl".to_string(),
+            kind => format!("spool.{}.jsonl", kind.name()),
+        }
+    }
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SinkKind::ALL
+            .iter()
+            .find(|kind| kind.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown sink \"{}\", possible sinks: {:?}",
+                    s,
+                    SinkKind::ALL
+                        .iter()
+                        .map(|kind| kind.name())
+                        .collect::<Vec<_>>()
+                )
+            })
+    }
+}
+
+/// Sink writes entries into a storage
+pub trait Sink: Send {
+    /// Write the entries, return the ones which were not written.
+    ///
+    /// The entries are written in order; a bad entry only affects the entries of its unit, see
+    /// [`crate::entry::units`].
+    fn write<'a>(&'a mut self, entries: &'a [Entry]) -> BoxFuture<'a, Vec<Entry>>;
+
+    /// Return whether the storage was reachable during the last write. Entries unwritten while
+    /// reachable were rejected, and retrying them is pointless.
+    fn is_connected(&self) -> bool;
+}
+
+// The statement correcting the last blocks of the miners surviving a rollback to the fork point
+// `$1`, after the orphaned blocks are deleted. SQLite binds `$1` as the first parameter too.
+fn rollback_miners(network: &str) -> String {
+    format!(
+        "UPDATE {0}.miner SET \
+        last_number = COALESCE((SELECT MAX(number) FROM {0}.block WHERE block.miner_lock_hash = miner.miner_lock_hash), first_number), \
+        last_time = COALESCE((SELECT MAX(time) FROM {0}.block WHERE block.miner_lock_hash = miner.miner_lock_hash), first_time) \
+        WHERE last_number > $1",
+        network
+    )
+}
@@ -0,0 +1,519 @@
+use crate::entry::{self, Entry};
+use crate::metrics;
+use crate::migration;
+use crate::postgres::Postgres;
+use crate::sink::{rollback_miners, Sink, SinkKind};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_postgres::{types::ToSql, Client, Statement, Transaction};
+
+const LABEL: &str = SinkKind::Postgres.name();
+
+type Params<'a> = Vec<&'a (dyn ToSql + Sync)>;
+
+macro_rules! params {
+    ($($param:expr),* $(,)?) => {
+        vec![$($param as &(dyn ToSql + Sync)),*]
+    };
+}
+
+/// PostgresSink inserts entries into Postgres via prepared statements, one transaction per batch
+/// and one savepoint per unit, see [`entry::units`].
+///
+/// Values are always passed as statement parameters, never formatted into the SQL, so hostile
+/// strings (such as peer client versions) cannot break the statements.
+///
+/// The sink writes over its own connection, so that the transactions it holds open do not
+/// capture the queries of the others sharing [`Postgres::client`].
+pub struct PostgresSink {
+    pg: Arc<Postgres>,
+    client: Option<Client>,
+    // The statements prepared on `client`
+    statements: HashMap<String, Statement>,
+}
+
+impl PostgresSink {
+    pub fn new(pg: Arc<Postgres>) -> Self {
+        Self {
+            pg,
+            client: None,
+            statements: Default::default(),
+        }
+    }
+
+    // Return the entries which were not written, either rejected by Postgres or not committed
+    // because the connection was lost.
+    async fn write_entries(&mut self, entries: &[Entry]) -> Vec<Entry> {
+        if let Err(err) = self.connect().await {
+            log::error!("connect to Postgres, error: {}", err);
+            metrics::WRITE_ERRORS
+                .with_label_values(&[LABEL])
+                .inc_by(entries.len() as u64);
+            return entries.to_vec();
+        }
+
+        let _timer = metrics::WRITE_DURATION
+            .with_label_values(&[LABEL])
+            .start_timer();
+        let client = self.client.as_mut().expect("connected above");
+        match write_units(client, &mut self.statements, entries).await {
+            Ok(unwritten) => unwritten,
+            Err(err) => {
+                log::error!(
+                    "write {} entries into Postgres, error: {}",
+                    entries.len(),
+                    err
+                );
+                metrics::WRITE_ERRORS
+                    .with_label_values(&[LABEL])
+                    .inc_by(entries.len() as u64);
+                entries.to_vec()
+            }
+        }
+    }
+
+    // Reconnect if the previous connection was closed
+    async fn connect(&mut self) -> Result<(), migration::Error> {
+        if self.is_connected() {
+            return Ok(());
+        }
+        // Statements are bound to the connection they were prepared on
+        self.client = None;
+        self.statements.clear();
+        self.client = Some(self.pg.connect().await?);
+        Ok(())
+    }
+}
+
+impl Sink for PostgresSink {
+    fn write<'a>(&'a mut self, entries: &'a [Entry]) -> BoxFuture<'a, Vec<Entry>> {
+        Box::pin(self.write_entries(entries))
+    }
+
+    // Return whether the connection used by the last write is still open
+    fn is_connected(&self) -> bool {
+        self.client
+            .as_ref()
+            .map(|client| !client.is_closed())
+            .unwrap_or(false)
+    }
+}
+
+// Write the entries in one transaction, each unit within a savepoint, so that a bad entry rolls
+// back its unit as a whole, including the resume cursor, without affecting the others.
+//
+// Return the entries of the rejected units. Fail if the transaction cannot be committed.
+async fn write_units(
+    client: &mut Client,
+    prepared: &mut HashMap<String, Statement>,
+    entries: &[Entry],
+) -> Result<Vec<Entry>, tokio_postgres::Error> {
+    let mut transaction = client.transaction().await?;
+    let mut unwritten = Vec::new();
+    for unit in entry::units(entries) {
+        let savepoint = transaction.transaction().await?;
+        match write_unit(&savepoint, prepared, unit).await {
+            Ok(()) => savepoint.commit().await?,
+            Err(_) => {
+                // Fails as well if the connection was lost
+                savepoint.rollback().await?;
+                metrics::WRITE_ERRORS
+                    .with_label_values(&[LABEL])
+                    .inc_by(unit.len() as u64);
+                unwritten.extend_from_slice(unit);
+            }
+        }
+    }
+    transaction.commit().await?;
+    Ok(unwritten)
+}
+
+async fn write_unit(
+    transaction: &Transaction<'_>,
+    prepared: &mut HashMap<String, Statement>,
+    unit: &[Entry],
+) -> Result<(), tokio_postgres::Error> {
+    for entry in unit {
+        for (query, params) in statements(entry) {
+            let result = match prepare(transaction, prepared, &query).await {
+                Ok(statement) => transaction.execute(&statement, &params).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                log::error!("execute(\"{}\", {:?}), error: {}", query, entry, err);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn prepare(
+    transaction: &Transaction<'_>,
+    prepared: &mut HashMap<String, Statement>,
+    query: &str,
+) -> Result<Statement, tokio_postgres::Error> {
+    if let Some(statement) = prepared.get(query) {
+        return Ok(statement.clone());
+    }
+    let statement = transaction.prepare(query).await?;
+    prepared.insert(query.to_string(), statement.clone());
+    Ok(statement)
+}
+
+// Return the parameterized queries and their parameters for writing the entry
+fn statements(entry: &Entry) -> Vec<(String, Params)> {
+    match entry {
+        Entry::Peer(e) => vec![(
+            format!(
+                "INSERT INTO {}.peer(time, version, ip, n_reachable) VALUES ($1, $2, $3, $4)",
+                e.network
+            ),
+            params![&e.time, &e.version, &e.ip, &e.n_reachable],
+        )],
+        Entry::IpInfo(e) => vec![(
+            format!(
+                "INSERT INTO {}.ipinfo(ip, country, city, region, company) \
+                VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+                e.network
+            ),
+            params![&e.ip, &e.country, &e.city, &e.region, &e.company],
+        )],
+        Entry::Block(e) => vec![(
+            format!(
+                "INSERT INTO {}.block(time, number, n_transactions, n_proposals, n_uncles, miner_lock_args, cellbase_client_version, cellbase_miner_source, interval, hash, \
+                size, cycles, compact_target, difficulty, dao_c, dao_ar, dao_s, dao_u, version, epoch_number, epoch_index, epoch_length, has_extension, miner, miner_lock_hash) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14::TEXT::NUMERIC, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25) \
+                ON CONFLICT (number) DO UPDATE SET time = excluded.time, n_transactions = excluded.n_transactions, \
+                n_proposals = excluded.n_proposals, n_uncles = excluded.n_uncles, miner_lock_args = excluded.miner_lock_args, \
+                cellbase_client_version = excluded.cellbase_client_version, cellbase_miner_source = excluded.cellbase_miner_source, \
+                interval = excluded.interval, hash = excluded.hash, size = excluded.size, cycles = excluded.cycles, \
+                compact_target = excluded.compact_target, difficulty = excluded.difficulty, \
+                dao_c = excluded.dao_c, dao_ar = excluded.dao_ar, dao_s = excluded.dao_s, dao_u = excluded.dao_u, \
+                version = excluded.version, epoch_number = excluded.epoch_number, epoch_index = excluded.epoch_index, \
+                epoch_length = excluded.epoch_length, has_extension = excluded.has_extension, \
+                miner = excluded.miner, miner_lock_hash = excluded.miner_lock_hash",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.number,
+                &e.n_transactions,
+                &e.n_proposals,
+                &e.n_uncles,
+                &e.miner_lock_args,
+                &e.cellbase_client_version,
+                &e.cellbase_miner_source,
+                &e.interval,
+                &e.hash,
+                &e.size,
+                &e.cycles,
+                &e.compact_target,
+                &e.difficulty,
+                &e.dao_c,
+                &e.dao_ar,
+                &e.dao_s,
+                &e.dao_u,
+                &e.version,
+                &e.epoch_number,
+                &e.epoch_index,
+                &e.epoch_length,
+                &e.has_extension,
+                &e.miner,
+                &e.miner_lock_hash,
+            ],
+        )],
+        Entry::Miner(e) => vec![(
+            format!(
+                "INSERT INTO {0}.miner(miner_lock_hash, miner_lock_args, miner, first_number, first_time, last_number, last_time) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                ON CONFLICT (miner_lock_hash) DO UPDATE SET miner = COALESCE(excluded.miner, {0}.miner.miner), \
+                first_number = LEAST({0}.miner.first_number, excluded.first_number), \
+                first_time = LEAST({0}.miner.first_time, excluded.first_time), \
+                last_number = GREATEST({0}.miner.last_number, excluded.last_number), \
+                last_time = GREATEST({0}.miner.last_time, excluded.last_time)",
+                e.network
+            ),
+            params![
+                &e.miner_lock_hash,
+                &e.miner_lock_args,
+                &e.miner,
+                &e.first_number,
+                &e.first_time,
+                &e.last_number,
+                &e.last_time,
+            ],
+        )],
+        Entry::Uncle(e) => vec![(
+            format!(
+                "INSERT INTO {}.uncle(time, number, hash, block_number, distance, miner_lock_args, cellbase_client_version, cellbase_miner_source) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+                ON CONFLICT DO NOTHING",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.number,
+                &e.hash,
+                &e.block_number,
+                &e.distance,
+                &e.miner_lock_args,
+                &e.cellbase_client_version,
+                &e.cellbase_miner_source,
+            ],
+        )],
+        Entry::Proposal(e) => vec![(
+            format!(
+                "INSERT INTO {}.proposal(time, number, proposal_id, committed_number, commit_distance, settled_number) \
+                VALUES ($1, $2, $3, $4, $5, $6) \
+                ON CONFLICT DO NOTHING",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.number,
+                &e.proposal_id,
+                &e.committed_number,
+                &e.commit_distance,
+                &e.settled_number,
+            ],
+        )],
+        Entry::BlockReward(e) => vec![(
+            format!(
+                "INSERT INTO {}.block_reward(time, number, target_number, lock_hash, primary_reward, secondary_reward, commit_reward, proposal_reward) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+                ON CONFLICT DO NOTHING",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.number,
+                &e.target_number,
+                &e.lock_hash,
+                &e.primary_reward,
+                &e.secondary_reward,
+                &e.commit_reward,
+                &e.proposal_reward,
+            ],
+        )],
+        Entry::Reorg(e) => vec![(
+            format!(
+                "INSERT INTO {}.reorg(time, fork_number, depth, old_tip_hash, new_tip_hash) \
+                VALUES ($1, $2, $3, $4, $5)",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.fork_number,
+                &e.depth,
+                &e.old_tip_hash,
+                &e.new_tip_hash
+            ],
+        )],
+        Entry::Rollback(e) => e
+            .tables()
+            .iter()
+            .map(|(table, column)| {
+                (
+                    format!("DELETE FROM {}.{} WHERE {} > $1", e.network, table, column),
+                    params![&e.fork_number],
+                )
+            })
+            .chain(
+                Some((rollback_miners(&e.network), params![&e.fork_number]))
+                    .filter(|_| e.topic == "ChainCrawler"),
+            )
+            .chain(::std::iter::once((
+                format!(
+                    "UPDATE {}.analyzer_cursor SET (cursor, block_hash) = ($1, NULL) WHERE cursor > $1 AND topic = $2",
+                    e.network
+                ),
+                params![&e.fork_number, &e.topic],
+            )))
+            .collect(),
+        Entry::Epoch(e) => vec![(
+            format!(
+                "INSERT INTO {}.epoch(start_time, end_time, number, length, start_number, n_uncles, difficulty) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7::TEXT::NUMERIC) \
+                ON CONFLICT DO NOTHING",
+                e.network
+            ),
+            params![
+                &e.start_time,
+                &e.end_time,
+                &e.number,
+                &e.length,
+                &e.start_number,
+                &e.n_uncles,
+                &e.difficulty,
+            ],
+        )],
+        Entry::TxPoolInfo(e) => vec![(
+            format!(
+                "INSERT INTO {}.tx_pool_info(time, total_tx_cycles, total_tx_size, pending, proposed, orphan) \
+                VALUES ($1, $2, $3, $4, $5, $6)",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.total_tx_cycles,
+                &e.total_tx_size,
+                &e.pending,
+                &e.proposed,
+                &e.orphan,
+            ],
+        )],
+        Entry::BlockTransaction(e) => vec![(
+            format!(
+                "INSERT INTO {}.block_transaction(time, number, tx_index, size, n_inputs, n_outputs, n_header_deps, n_cell_deps, total_data_size, proposal_id, hash, script_kinds) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+                ON CONFLICT (number, tx_index) DO UPDATE SET script_kinds = excluded.script_kinds",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.number,
+                &e.tx_index,
+                &e.size,
+                &e.n_inputs,
+                &e.n_outputs,
+                &e.n_header_deps,
+                &e.n_cell_deps,
+                &e.total_data_size,
+                &e.proposal_id,
+                &e.hash,
+                &e.script_kinds,
+            ],
+        )],
+        Entry::SubscribedNewTransaction(e) => vec![(
+            format!(
+                "INSERT INTO {}.subscribed_new_transaction(time, size, cycles, fee, n_inputs, n_outputs, n_cell_deps, n_header_deps, hash, proposal_id) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.size,
+                &e.cycles,
+                &e.fee,
+                &e.n_inputs,
+                &e.n_outputs,
+                &e.n_cell_deps,
+                &e.n_header_deps,
+                &e.hash,
+                &e.proposal_id,
+            ],
+        )],
+        Entry::SubscribedProposedTransaction(e) => vec![(
+            format!(
+                "INSERT INTO {}.subscribed_proposed_transaction(time, size, cycles, fee, n_inputs, n_outputs, n_cell_deps, n_header_deps, hash, proposal_id) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.size,
+                &e.cycles,
+                &e.fee,
+                &e.n_inputs,
+                &e.n_outputs,
+                &e.n_cell_deps,
+                &e.n_header_deps,
+                &e.hash,
+                &e.proposal_id,
+            ],
+        )],
+        Entry::SubscribedRejectedTransaction(e) => vec![(
+            format!(
+                "INSERT INTO {}.subscribed_rejected_transaction(time, reason, size, cycles, fee, n_inputs, n_outputs, n_cell_deps, n_header_deps, hash, proposal_id) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.reason,
+                &e.size,
+                &e.cycles,
+                &e.fee,
+                &e.n_inputs,
+                &e.n_outputs,
+                &e.n_cell_deps,
+                &e.n_header_deps,
+                &e.hash,
+                &e.proposal_id,
+            ],
+        )],
+        Entry::RetentionTransaction(e) => vec![(
+            format!(
+                "INSERT INTO {}.retention_transaction(time, hash) VALUES ($1, $2)",
+                e.network
+            ),
+            params![&e.time, &e.hash],
+        )],
+        Entry::CreatedCell(e) => vec![(
+            format!(
+                "INSERT INTO {}.created_cell(time, block_number, tx_index, tx_hash, index, lock_hash_type, lock_code_hash, lock_args, lock_hash, \
+                type_hash_type, type_code_hash, type_args, type_hash, capacity, occupied_capacity, data_size, script_kind) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, decode(substr($8, 3), 'hex'), $9, $10, $11, decode(substr($12, 3), 'hex'), $13, $14, $15, $16, $17) \
+                ON CONFLICT (time, tx_hash, index) DO UPDATE SET lock_args = excluded.lock_args, lock_hash = excluded.lock_hash, \
+                type_args = excluded.type_args, type_hash = excluded.type_hash, \
+                capacity = excluded.capacity, occupied_capacity = excluded.occupied_capacity, data_size = excluded.data_size, \
+                script_kind = excluded.script_kind",
+                e.network
+            ),
+            params![
+                &e.time,
+                &e.block_number,
+                &e.tx_index,
+                &e.tx_hash,
+                &e.index,
+                &e.lock_hash_type,
+                &e.lock_code_hash,
+                &e.lock_args,
+                &e.lock_hash,
+                &e.type_hash_type,
+                &e.type_code_hash,
+                &e.type_args,
+                &e.type_hash,
+                &e.capacity,
+                &e.occupied_capacity,
+                &e.data_size,
+                &e.script_kind,
+            ],
+        )],
+        Entry::SpentCell(e) => vec![(
+            format!(
+                "INSERT INTO {0}.spent_cell(time, block_number, tx_hash, index, capacity) VALUES ($1, $2, $3, $4, $5) \
+                ON CONFLICT (time, tx_hash, index) DO UPDATE SET capacity = COALESCE(excluded.capacity, {0}.spent_cell.capacity)",
+                e.network
+            ),
+            params![&e.time, &e.block_number, &e.tx_hash, &e.index, &e.capacity],
+        )],
+        Entry::CompactBlockFirstSeen(e) => vec![(
+            format!(
+                "INSERT INTO {}.compact_block_first_seen(time, block_number, ip) VALUES ($1, $2, $3)",
+                e.network
+            ),
+            params![&e.time, &e.block_number, &e.ip],
+        )],
+        Entry::PeerLastCompactBlock(e) => vec![(
+            format!(
+                "INSERT INTO {}.peer_last_compact_block(ip, block_number, block_hash, time) \
+                VALUES ($1, $2, $3, $4) \
+                ON CONFLICT ( ip ) \
+                DO UPDATE SET (block_number, block_hash, time) = (EXCLUDED.block_number, EXCLUDED.block_hash, EXCLUDED.time)",
+                e.network
+            ),
+            params![&e.ip, &e.block_number, &e.block_hash, &e.time],
+        )],
+        Entry::AnalyzerCursor(e) => vec![(
+            format!(
+                "INSERT INTO {}.analyzer_cursor(topic, cursor, block_hash, updated_at) VALUES ($1, $2, $3, $4) \
+                ON CONFLICT (topic) DO UPDATE SET (cursor, block_hash, updated_at) = (EXCLUDED.cursor, EXCLUDED.block_hash, EXCLUDED.updated_at)",
+                e.network
+            ),
+            params![&e.topic, &e.cursor, &e.block_hash, &e.updated_at],
+        )],
+    }
+}
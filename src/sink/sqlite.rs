@@ -0,0 +1,387 @@
+use crate::entry::{self, Entry};
+use crate::metrics;
+use crate::sink::{rollback_miners, Sink, SinkKind};
+use crate::sqlite::Sqlite;
+use futures::future::BoxFuture;
+use rusqlite::types::Value;
+use std::sync::Arc;
+
+const LABEL: &str = SinkKind::Sqlite.name();
+
+/// SqliteSink inserts entries into the SQLite database, one transaction per batch and one
+/// savepoint per unit, see [`entry::units`].
+///
+/// The inserted columns are the fields of the entries, which are named after the columns of the
+/// Postgres tables.
+pub struct SqliteSink {
+    sqlite: Arc<Sqlite>,
+    healthy: bool,
+}
+
+impl SqliteSink {
+    pub fn new(sqlite: Arc<Sqlite>) -> Self {
+        Self {
+            sqlite,
+            healthy: true,
+        }
+    }
+
+    fn write_entries(&mut self, entries: &[Entry]) -> Vec<Entry> {
+        let _timer = metrics::WRITE_DURATION
+            .with_label_values(&[LABEL])
+            .start_timer();
+        let mut conn = self.sqlite.connection();
+        let mut transaction = match conn.transaction() {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                log::error!("begin SQLite transaction, error: {}", err);
+                metrics::WRITE_ERRORS
+                    .with_label_values(&[LABEL])
+                    .inc_by(entries.len() as u64);
+                self.healthy = false;
+                return entries.to_vec();
+            }
+        };
+
+        let mut unwritten = Vec::new();
+        for unit in entry::units(entries) {
+            // Each unit is written within a savepoint, so a bad entry rolls back its unit as a
+            // whole, including the resume cursor
+            let result = transaction.savepoint().and_then(|savepoint| {
+                for entry in unit {
+                    for (query, params) in statements(entry) {
+                        savepoint
+                            .execute(&query, rusqlite::params_from_iter(params))
+                            .map_err(|err| {
+                                log::error!("write {:?} into SQLite, error: {}", entry, err);
+                                err
+                            })?;
+                    }
+                }
+                savepoint.commit()
+            });
+            if result.is_err() {
+                metrics::WRITE_ERRORS
+                    .with_label_values(&[LABEL])
+                    .inc_by(unit.len() as u64);
+                unwritten.extend_from_slice(unit);
+            }
+        }
+        if let Err(err) = transaction.commit() {
+            log::error!("commit SQLite transaction, error: {}", err);
+            metrics::WRITE_ERRORS
+                .with_label_values(&[LABEL])
+                .inc_by(entries.len() as u64);
+            self.healthy = false;
+            return entries.to_vec();
+        }
+        self.healthy = true;
+        unwritten
+    }
+}
+
+impl Sink for SqliteSink {
+    fn write<'a>(&'a mut self, entries: &'a [Entry]) -> BoxFuture<'a, Vec<Entry>> {
+        let unwritten = self.write_entries(entries);
+        Box::pin(async move { unwritten })
+    }
+
+    fn is_connected(&self) -> bool {
+        self.healthy
+    }
+}
+
+// Return the queries and their parameters for writing the entry
+fn statements(entry: &Entry) -> Vec<(String, Vec<Value>)> {
+    match entry {
+        Entry::Rollback(e) => e
+            .tables()
+            .iter()
+            .map(|(table, column)| {
+                (
+                    format!("DELETE FROM {}.{} WHERE {} > ?1", e.network, table, column),
+                    vec![Value::Integer(e.fork_number)],
+                )
+            })
+            .chain(
+                Some((
+                    rollback_miners(&e.network),
+                    vec![Value::Integer(e.fork_number)],
+                ))
+                .filter(|_| e.topic == "ChainCrawler"),
+            )
+            .chain(::std::iter::once((
+                format!(
+                    "UPDATE {}.analyzer_cursor SET cursor = ?1, block_hash = NULL WHERE cursor > ?1 AND topic = ?2",
+                    e.network
+                ),
+                vec![Value::Integer(e.fork_number), Value::Text(e.topic.clone())],
+            )))
+            .collect(),
+        Entry::AnalyzerCursor(e) => vec![(
+            format!(
+                "INSERT INTO {}.analyzer_cursor(topic, cursor, block_hash, updated_at) VALUES (?1, ?2, ?3, ?4) \
+                ON CONFLICT (topic) DO UPDATE SET cursor = excluded.cursor, block_hash = excluded.block_hash, \
+                updated_at = excluded.updated_at",
+                e.network
+            ),
+            vec![
+                Value::Text(e.topic.clone()),
+                Value::Integer(e.cursor),
+                e.block_hash.clone().map(Value::Text).unwrap_or(Value::Null),
+                to_sqlite_value(serde_json::to_value(&e.updated_at).unwrap_or_default()),
+            ],
+        )],
+        Entry::Miner(e) => vec![(
+            format!(
+                "INSERT INTO {0}.miner(miner_lock_hash, miner_lock_args, miner, first_number, first_time, last_number, last_time) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+                ON CONFLICT (miner_lock_hash) DO UPDATE SET miner = COALESCE(excluded.miner, miner), \
+                first_number = MIN(first_number, excluded.first_number), \
+                first_time = MIN(first_time, excluded.first_time), \
+                last_number = MAX(last_number, excluded.last_number), \
+                last_time = MAX(last_time, excluded.last_time)",
+                e.network
+            ),
+            vec![
+                Value::Text(e.miner_lock_hash.clone()),
+                Value::Text(e.miner_lock_args.clone()),
+                e.miner.clone().map(Value::Text).unwrap_or(Value::Null),
+                Value::Integer(e.first_number),
+                to_sqlite_value(serde_json::to_value(&e.first_time).unwrap_or_default()),
+                Value::Integer(e.last_number),
+                to_sqlite_value(serde_json::to_value(&e.last_time).unwrap_or_default()),
+            ],
+        )],
+        // The capacity is filled by redoing the spent cells crawled before it was recorded
+        Entry::SpentCell(e) => vec![(
+            format!(
+                "INSERT INTO {}.spent_cell(time, block_number, tx_hash, \"index\", capacity) VALUES (?1, ?2, ?3, ?4, ?5) \
+                ON CONFLICT (time, tx_hash, \"index\") DO UPDATE SET capacity = COALESCE(excluded.capacity, capacity)",
+                e.network
+            ),
+            vec![
+                to_sqlite_value(serde_json::to_value(&e.time).unwrap_or_default()),
+                Value::Integer(e.block_number),
+                Value::Text(e.tx_hash.clone()),
+                Value::Integer(e.index),
+                e.capacity.map(Value::Integer).unwrap_or(Value::Null),
+            ],
+        )],
+        _ => vec![insert(entry)],
+    }
+}
+
+fn insert(entry: &Entry) -> (String, Vec<Value>) {
+    let mut row = match serde_json::to_value(entry.row()) {
+        Ok(serde_json::Value::Object(row)) => row,
+        _ => unreachable!("entries are serialized as structs"),
+    };
+    row.remove("network");
+
+    // Same as the conflict handling of the postgres sink
+    let insert = match entry.table() {
+        "uncle" | "proposal" | "block_reward" | "epoch" | "ipinfo" => "INSERT OR IGNORE",
+        "block" | "block_transaction" | "created_cell" | "peer_last_compact_block" => {
+            "INSERT OR REPLACE"
+        }
+        _ => "INSERT",
+    };
+    let (columns, params): (Vec<_>, Vec<_>) = row
+        .into_iter()
+        .map(|(column, value)| (format!("\"{}\"", column), to_sqlite_value(value)))
+        .unzip();
+    let placeholders = (1..=params.len())
+        .map(|i| format!("?{}", i))
+        .collect::<Vec<_>>();
+    let query = format!(
+        "{} INTO {}.{}({}) VALUES ({})",
+        insert,
+        entry.network(),
+        entry.table(),
+        columns.join(", "),
+        placeholders.join(", ")
+    );
+    (query, params)
+}
+
+fn to_sqlite_value(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(boolean) => Value::Integer(boolean as i64),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Real(number.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(string) => Value::Text(string),
+        value => Value::Text(value.to_string()),
+    }
+}
+
+#[test]
+fn test_sqlite_sink() {
+    use crate::entry::{AnalyzerCursor, Block, Miner, Rollback};
+    use futures::FutureExt;
+
+    let path = ::std::env::temp_dir().join(format!("ckb-analyzer-{}.sqlite", std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let sqlite = Arc::new(Sqlite::open(&path, "ckb_dev", "ckb_dev").unwrap());
+    let mut sink = SqliteSink::new(Arc::clone(&sqlite));
+    let block = |number: i64| -> Entry {
+        Block {
+            network: "ckb_dev".to_string(),
+            time: chrono::NaiveDateTime::from_timestamp(number, 0),
+            number,
+            n_transactions: 1,
+            n_proposals: 0,
+            n_uncles: 0,
+            cellbase_client_version: String::new(),
+            cellbase_miner_source: String::new(),
+            miner_lock_args: String::new(),
+            miner_lock_hash: String::new(),
+            interval: 0,
+            hash: format!("{:#x}", number),
+            size: 0,
+            cycles: None,
+            compact_target: 0,
+            difficulty: "0".to_string(),
+            dao_c: 0,
+            dao_ar: 0,
+            dao_s: 0,
+            dao_u: 0,
+            version: 0,
+            epoch_number: 0,
+            epoch_index: 0,
+            epoch_length: 0,
+            has_extension: false,
+            miner: None,
+        }
+        .into()
+    };
+
+    // Duplicated blocks replace the stored ones, the same as Postgres, so that re-crawling fills
+    // the columns added later
+    let mut sized_block = block(2);
+    if let Entry::Block(block) = &mut sized_block {
+        block.size = 100;
+    }
+    let entries = vec![block(1), block(2), block(3), sized_block];
+    assert!(sink.write(&entries).now_or_never().unwrap().is_empty());
+    let cursor = "SELECT number FROM ckb_dev.block ORDER BY time DESC LIMIT 1";
+    assert_eq!(sqlite.query_number(cursor).unwrap(), 3);
+    let size = "SELECT size FROM ckb_dev.block WHERE number = 2";
+    assert_eq!(sqlite.query_number(size).unwrap(), 100);
+
+    let rollback: Entry = Rollback {
+        network: "ckb_dev".to_string(),
+        topic: "ChainCrawler".to_string(),
+        fork_number: 1,
+    }
+    .into();
+    assert!(sink
+        .write(&[rollback.clone()])
+        .now_or_never()
+        .unwrap()
+        .is_empty());
+    assert_eq!(sqlite.query_number(cursor).unwrap(), 1);
+
+    // The resume cursor is written atomically with the entries of its unit
+    let analyzer_cursor = |cursor: i64| -> Entry {
+        AnalyzerCursor {
+            network: "ckb_dev".to_string(),
+            topic: "ChainCrawler".to_string(),
+            cursor,
+            block_hash: Some(format!("{:#x}", cursor)),
+            updated_at: chrono::NaiveDateTime::from_timestamp(cursor, 0),
+            n_entries: 1,
+        }
+        .into()
+    };
+    let resume = "SELECT cursor FROM ckb_dev.analyzer_cursor WHERE topic = 'ChainCrawler'";
+    let entries = vec![block(2), analyzer_cursor(2)];
+    assert!(sink.write(&entries).now_or_never().unwrap().is_empty());
+    assert_eq!(sqlite.query_number(resume).unwrap(), 2);
+    let mut bad_block = block(3);
+    if let Entry::Block(block) = &mut bad_block {
+        block.network = "ckb_missing".to_string();
+    }
+    let entries = vec![block(5), bad_block, analyzer_cursor(3)];
+    assert_eq!(sink.write(&entries).now_or_never().unwrap().len(), 2);
+    assert_eq!(sqlite.query_number(cursor).unwrap(), 5);
+    assert_eq!(sqlite.query_number(resume).unwrap(), 2);
+
+    // The rollback of a topic only moves back its own cursor
+    let mut cell_cursor = analyzer_cursor(5);
+    if let Entry::AnalyzerCursor(cursor) = &mut cell_cursor {
+        cursor.topic = "CellCrawler".to_string();
+        cursor.n_entries = 0;
+    }
+    assert!(sink
+        .write(&[cell_cursor])
+        .now_or_never()
+        .unwrap()
+        .is_empty());
+    assert!(sink.write(&[rollback]).now_or_never().unwrap().is_empty());
+    assert_eq!(sqlite.query_number(cursor).unwrap(), 1);
+    assert_eq!(sqlite.query_number(resume).unwrap(), 1);
+    let cell_resume = "SELECT cursor FROM ckb_dev.analyzer_cursor WHERE topic = 'CellCrawler'";
+    assert_eq!(sqlite.query_number(cell_resume).unwrap(), 5);
+
+    // The miners surviving a rollback keep their last blocks before the fork point
+    let mined = |number: i64, lock_hash: &str| -> Vec<Entry> {
+        let mut block = block(number);
+        if let Entry::Block(block) = &mut block {
+            block.miner_lock_hash = lock_hash.to_string();
+        }
+        let time = chrono::NaiveDateTime::from_timestamp(number, 0);
+        let miner = Miner {
+            network: "ckb_dev".to_string(),
+            miner_lock_hash: lock_hash.to_string(),
+            miner_lock_args: "-".to_string(),
+            miner: None,
+            first_number: number,
+            first_time: time,
+            last_number: number,
+            last_time: time,
+        };
+        vec![miner.into(), block]
+    };
+    let entries = [mined(2, "0xaa"), mined(3, "0xbb"), mined(4, "0xaa")].concat();
+    assert!(sink.write(&entries).now_or_never().unwrap().is_empty());
+    let last_number = |lock_hash: &str| {
+        sqlite.query_optional_number(&format!(
+            "SELECT last_number FROM ckb_dev.miner WHERE miner_lock_hash = '{}'",
+            lock_hash
+        ))
+    };
+    assert_eq!(last_number("0xaa").unwrap(), Some(4));
+    let rollback_to = |fork_number: i64| -> Entry {
+        Rollback {
+            network: "ckb_dev".to_string(),
+            topic: "ChainCrawler".to_string(),
+            fork_number,
+        }
+        .into()
+    };
+    assert!(sink
+        .write(&[rollback_to(3)])
+        .now_or_never()
+        .unwrap()
+        .is_empty());
+    assert_eq!(last_number("0xaa").unwrap(), Some(2));
+    assert_eq!(last_number("0xbb").unwrap(), Some(3));
+    assert!(sink
+        .write(&[rollback_to(2)])
+        .now_or_never()
+        .unwrap()
+        .is_empty());
+    assert_eq!(last_number("0xaa").unwrap(), Some(2));
+    assert_eq!(last_number("0xbb").unwrap(), None);
+
+    // Reopening does not migrate again
+    drop(sink);
+    drop(sqlite);
+    let sqlite = Sqlite::open(&path, "ckb_dev", "ckb_dev").unwrap();
+    assert_eq!(sqlite.query_number(cursor).unwrap(), 1);
+    let _ = ::std::fs::remove_file(&path);
+}
@@ -0,0 +1,246 @@
+use crate::entry::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Spool is an append-only file keeping the batches which could not be written into a sink.
+///
+/// Each line is a JSON-serialized batch. The batches are replayed in order once the sink is
+/// available again. The replayed batches are not removed one by one; the spool keeps the offset of
+/// the first unreplayed line in `<filename>.offset` instead, and the file is removed once drained.
+///
+/// The batches given up on are appended to the dead-letter file `<name>.rejected.jsonl` next to
+/// the spool, for manual inspection.
+pub struct Spool {
+    path: PathBuf,
+    // Byte offset of the first unreplayed line
+    offset: u64,
+    n_batches: usize,
+}
+
+impl Spool {
+    /// Open the spool `filename` under `data_dir`, creating the directory if it does not exist.
+    ///
+    /// A truncated last line, left by a crash during appending, is removed, so that the next
+    /// batch is appended to a line of its own.
+    pub fn open<P: AsRef<Path>>(data_dir: P, filename: &str) -> io::Result<Self> {
+        fs::create_dir_all(data_dir.as_ref())?;
+        let path = data_dir.as_ref().join(filename);
+        let mut spool = Self {
+            path,
+            offset: 0,
+            n_batches: 0,
+        };
+        let content = match fs::read(&spool.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let complete_len = content
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map(|position| position + 1)
+            .unwrap_or(0);
+        if complete_len < content.len() {
+            log::warn!(
+                "truncate the broken last line of spool \"{}\"",
+                spool.path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&spool.path)?
+                .set_len(complete_len as u64)?;
+        }
+
+        spool.offset = match fs::read_to_string(spool.offset_path()) {
+            Ok(offset) => offset.trim().parse().unwrap_or(0),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        if spool.offset > complete_len as u64 {
+            spool.offset = 0;
+        }
+        spool.n_batches = content[spool.offset as usize..complete_len]
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count();
+        if spool.n_batches > 0 {
+            log::info!(
+                "Spool \"{}\" contains {} batches to replay",
+                spool.path.display(),
+                spool.n_batches
+            );
+        } else {
+            spool.clear()?;
+        }
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_batches == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_batches
+    }
+
+    /// Append a batch to the end of the spool, and sync it to disk
+    pub fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        append_line(&self.path, entries)?;
+        self.n_batches += 1;
+        Ok(())
+    }
+
+    /// Return the first unreplayed batch, skipping the broken lines
+    pub fn front(&mut self) -> io::Result<Option<Vec<Entry>>> {
+        while let Some(line) = self.front_line()? {
+            match serde_json::from_str(line.trim_end()) {
+                Ok(entries) => return Ok(Some(entries)),
+                Err(err) => {
+                    log::error!(
+                        "skip broken line of spool \"{}\", error: {}",
+                        self.path.display(),
+                        err
+                    );
+                    self.advance(line.len() as u64)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Mark the first unreplayed batch as replayed
+    pub fn pop_front(&mut self) -> io::Result<()> {
+        if let Some(line) = self.front_line()? {
+            self.advance(line.len() as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the first unreplayed batch with `entries`, the part of it which is still unwritten.
+    ///
+    /// The spool is rewritten, which only happens when the sink fails in the middle of a batch.
+    pub fn replace_front(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut batches = self.load()?;
+        if batches.is_empty() {
+            return self.append(entries);
+        }
+        batches[0] = entries.to_vec();
+
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for entries in batches.iter() {
+                let mut line = serde_json::to_string(entries)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+            file.sync_data()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.offset = 0;
+        self.n_batches = batches.len();
+        self.write_offset()
+    }
+
+    /// Append a batch given up on to the dead-letter file
+    pub fn reject(&self, entries: &[Entry]) -> io::Result<()> {
+        append_line(&self.rejected_path(), entries)
+    }
+
+    pub fn rejected_path(&self) -> PathBuf {
+        self.path.with_extension("rejected.jsonl")
+    }
+
+    /// Load the unreplayed batches, in the order they were appended, skipping the broken lines
+    pub fn load(&self) -> io::Result<Vec<Vec<Entry>>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut batches = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entries) => batches.push(entries),
+                Err(err) => log::error!(
+                    "skip broken line of spool \"{}\", error: {}",
+                    self.path.display(),
+                    err
+                ),
+            }
+        }
+        Ok(batches)
+    }
+
+    fn offset_path(&self) -> PathBuf {
+        self.path.with_extension("jsonl.offset")
+    }
+
+    // Return the first unreplayed line, including its line break
+    fn front_line(&self) -> io::Result<Option<String>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+        if line.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(line))
+        }
+    }
+
+    fn advance(&mut self, line_len: u64) -> io::Result<()> {
+        self.offset += line_len;
+        self.n_batches -= 1;
+        if self.n_batches == 0 {
+            self.clear()
+        } else {
+            self.write_offset()
+        }
+    }
+
+    // Remove the drained spool and its offset
+    fn clear(&mut self) -> io::Result<()> {
+        for path in [self.path.clone(), self.offset_path()].iter() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        self.offset = 0;
+        self.n_batches = 0;
+        Ok(())
+    }
+
+    fn write_offset(&self) -> io::Result<()> {
+        let offset_path = self.offset_path();
+        let tmp_path = self.path.with_extension("jsonl.offset.tmp");
+        fs::write(&tmp_path, self.offset.to_string())?;
+        fs::rename(&tmp_path, &offset_path)
+    }
+}
+
+// Append `entries` as a line to `path` and sync it to disk. On failure, the partially written
+// line is truncated, so that it does not merge with the next one.
+fn append_line(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut line = serde_json::to_string(entries)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    if let Err(err) = file
+        .write_all(line.as_bytes())
+        .and_then(|_| file.sync_data())
+    {
+        let _ = file.set_len(len);
+        return Err(err);
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::migration::{self, migrate_sqlite};
+use rusqlite::{Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sqlite is a shared handle of the SQLite database, used by the sqlite sink and for querying the
+/// resume cursors.
+///
+/// The database file is attached as `network`, so that the tables are addressed as
+/// `{network}.{table}`, the same as the Postgres schema. It is migrated to the latest version on
+/// opening, and `chain_id` is recorded as its chain.
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+}
+
+impl Sqlite {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        network: &str,
+        chain_id: &str,
+    ) -> Result<Self, migration::Error> {
+        if let Some(parent) = path.as_ref().parent() {
+            let _ = ::std::fs::create_dir_all(parent);
+        }
+        let path = path.as_ref().to_string_lossy().to_string();
+        let mut conn = Connection::open_in_memory()?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute(&format!("ATTACH DATABASE ?1 AS {}", network), [&path])?;
+        conn.query_row(
+            &format!("PRAGMA {}.journal_mode = WAL", network),
+            [],
+            |_| Ok(()),
+        )?;
+        migrate_sqlite(&mut conn, network, chain_id)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn connection(&self) -> MutexGuard<Connection> {
+        self.conn.lock().expect("acquire lock")
+    }
+
+    /// Query the first column of the first row as a number, 0 if there is no row
+    pub fn query_number(&self, query: &str) -> Result<u64, rusqlite::Error> {
+        Ok(self.query_optional_number(query)?.unwrap_or(0))
+    }
+
+    /// Query the first column of the first row as a number, `None` if there is no row or the
+    /// column is NULL
+    pub fn query_optional_number(&self, query: &str) -> Result<Option<u64>, rusqlite::Error> {
+        let number = self
+            .connection()
+            .query_row(query, [], |row| row.get::<_, Option<i64>>(0))
+            .optional()?;
+        Ok(number.flatten().map(|number| number as u64))
+    }
+}
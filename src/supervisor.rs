@@ -0,0 +1,335 @@
+use crate::block_source::BlockSource;
+use crate::ckb_types::{packed, prelude::*, H256};
+use crate::config::Config;
+use crate::metrics;
+use crate::migration;
+use crate::postgres::Postgres;
+use crate::sink::{read_cursor, FileFormat, SinkKind};
+use crate::sqlite::Sqlite;
+use crate::topic::{EntrySender, Topic, TopicContext};
+use crate::util::network;
+use crate::verify::Store;
+use ckb_testkit::Node;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// A topic which has run this long is considered healthy, its backoff is reset
+const HEALTHY_RUNNING_DURATION: Duration = Duration::from_secs(10 * 60);
+
+// How long to wait for an aborted topic to stop. A topic blocking inside a synchronous call
+// cannot be aborted until the call returns.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Supervisor runs topics and restarts the terminated ones.
+///
+/// When a topic terminates, either it returns or panics, the supervisor logs the reason and
+/// restarts it from its resume cursor, with exponential backoff.
+///
+/// [`Supervisor::shutdown`] aborts the running topics, dropping their entry senders and p2p
+/// connectors.
+#[derive(Clone)]
+pub struct Supervisor {
+    node: Node,
+    blocks: Arc<BlockSource>,
+    // Only present when some topic writes into the postgres or sqlite sink
+    pg: Option<Arc<Postgres>>,
+    sqlite: Option<Arc<Sqlite>>,
+    entry_senders: HashMap<SinkKind, EntrySender>,
+    subscription_addr: Option<String>,
+    config: Arc<Config>,
+
+    // The supervising tasks
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    shutdown_sender: Arc<watch::Sender<bool>>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+impl Supervisor {
+    pub fn new(
+        node: Node,
+        blocks: Arc<BlockSource>,
+        pg: Option<Arc<Postgres>>,
+        sqlite: Option<Arc<Sqlite>>,
+        entry_senders: HashMap<SinkKind, EntrySender>,
+        subscription_addr: Option<String>,
+        config: Arc<Config>,
+    ) -> Self {
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        Self {
+            node,
+            blocks,
+            pg,
+            sqlite,
+            entry_senders,
+            subscription_addr,
+            config,
+            tasks: Default::default(),
+            shutdown_sender: Arc::new(shutdown_sender),
+            shutdown_receiver,
+        }
+    }
+
+    /// Spawn a task running the topic and restarting it whenever it terminates. The topic sends
+    /// its entries into `sink`.
+    pub fn supervise(&self, topic: Arc<dyn Topic>, sink: SinkKind) {
+        let supervisor = self.clone();
+        // Export the restart count from 0
+        metrics::TOPIC_RESTARTS.with_label_values(&[topic.name()]);
+        let task = tokio::spawn(async move {
+            let mut backoff = None;
+            loop {
+                let started_at = Instant::now();
+                let result = supervisor.run_once(&topic, sink).await;
+                if supervisor.is_shutdown() {
+                    log::info!("Topic \"{}\" stopped", topic.name());
+                    return;
+                }
+                match result {
+                    Ok(()) => log::error!("Topic \"{}\" exited", topic.name()),
+                    Err(reason) => log::error!("Topic \"{}\" {}", topic.name(), reason),
+                }
+
+                let delay = restart_backoff(backoff, started_at.elapsed());
+                backoff = Some(delay);
+                let restarts = metrics::TOPIC_RESTARTS.with_label_values(&[topic.name()]);
+                restarts.inc();
+                log::warn!(
+                    "Restart topic \"{}\" after {:?}, restarts: {}",
+                    topic.name(),
+                    delay,
+                    restarts.get()
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = supervisor.wait_shutdown() => {
+                        log::info!("Topic \"{}\" stopped", topic.name());
+                        return;
+                    }
+                }
+            }
+        });
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push(task);
+        }
+    }
+
+    /// Abort all the running topics and wait for them to stop. Stopped topics are not restarted.
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown_sender.send(true);
+        let tasks = self
+            .tasks
+            .lock()
+            .map(|mut tasks| tasks.drain(..).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        *self.shutdown_receiver.borrow()
+    }
+
+    // Resolve once `shutdown` is called
+    async fn wait_shutdown(&self) {
+        let mut shutdown_receiver = self.shutdown_receiver.clone();
+        while !*shutdown_receiver.borrow() {
+            if shutdown_receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Run the topic until it terminates or the supervisor shuts down
+    async fn run_once(&self, topic: &Arc<dyn Topic>, sink: SinkKind) -> Result<(), String> {
+        let entry_sender = self
+            .entry_senders
+            .get(&sink)
+            .cloned()
+            .ok_or_else(|| format!("miss the entry sender of sink \"{}\"", sink))?;
+        let (cursor, cursor_hash) = match FileFormat::from_kind(sink) {
+            Some(format) => self
+                .read_file_cursor(topic.as_ref(), format)
+                .map_err(|err| format!("failed to read resume cursor, error: {}", err))?,
+            None => self
+                .query_cursor(topic.as_ref(), sink)
+                .await
+                .map_err(|err| format!("failed to query resume cursor, error: {}", err))?,
+        };
+        let store = match (sink, self.pg.as_ref(), self.sqlite.as_ref()) {
+            (SinkKind::Postgres, Some(pg), _) => {
+                Some(Store::Postgres(pg.client().await.map_err(|err| {
+                    format!("failed to connect Postgres, error: {}", err)
+                })?))
+            }
+            (SinkKind::Sqlite, _, Some(sqlite)) => Some(Store::Sqlite(Arc::clone(sqlite))),
+            _ => None,
+        };
+        let context = TopicContext {
+            node: self.node.clone(),
+            blocks: Arc::clone(&self.blocks),
+            entry_sender,
+            subscription_addr: self.subscription_addr.clone(),
+            cursor,
+            cursor_hash,
+            store,
+            config: Arc::clone(&self.config),
+        };
+        log::info!("Start topic \"{}\" from {}", topic.name(), cursor);
+        let mut handle = tokio::spawn(topic.run(context));
+        let result = tokio::select! {
+            result = &mut handle => result,
+            _ = self.wait_shutdown() => {
+                handle.abort();
+                if tokio::time::timeout(ABORT_TIMEOUT, handle).await.is_err() {
+                    log::warn!("Topic \"{}\" does not stop in {:?}", topic.name(), ABORT_TIMEOUT);
+                }
+                return Ok(());
+            }
+        };
+        result.map_err(|err| {
+            if err.is_panic() {
+                let panic = err.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                format!("panicked, error: {}", message)
+            } else {
+                format!("cancelled, error: {}", err)
+            }
+        })
+    }
+
+    // Return the cursor committed into the files, and the hash of the cursor block if the block
+    // topic committed it
+    fn read_file_cursor(
+        &self,
+        topic: &dyn Topic,
+        format: FileFormat,
+    ) -> io::Result<(u64, Option<packed::Byte32>)> {
+        if topic.resume_cursor().is_none() {
+            return Ok((0, None));
+        }
+        let dir = Path::new(&self.config.sink.output_dir);
+        Ok(
+            match read_cursor(format, dir, &network(&self.node), topic.name())? {
+                Some(cursor) => (cursor.cursor, parse_block_hash(cursor.block_hash)),
+                None => (0, None),
+            },
+        )
+    }
+
+    // Return the cursor committed into the database, and the hash of the cursor block if the
+    // block topic committed it. Without a committed cursor, resume after the stored rows.
+    async fn query_cursor(
+        &self,
+        topic: &dyn Topic,
+        sink: SinkKind,
+    ) -> Result<(u64, Option<packed::Byte32>), migration::Error> {
+        let fallback = match topic.resume_cursor() {
+            None => return Ok((0, None)),
+            Some(cursor) => cursor,
+        };
+        let network = &network(&self.node);
+        let committed = format!(
+            "SELECT cursor, block_hash FROM {}.analyzer_cursor WHERE topic = '{}'",
+            network,
+            topic.name()
+        );
+        let committed: Option<(i64, Option<String>)> =
+            match (sink, self.pg.as_ref(), self.sqlite.as_ref()) {
+                (SinkKind::Postgres, Some(pg), _) => pg
+                    .client()
+                    .await?
+                    .query_opt(committed.as_str(), &[])
+                    .await?
+                    .map(|row| (row.get(0), row.get(1))),
+                (SinkKind::Sqlite, _, Some(sqlite)) => sqlite
+                    .connection()
+                    .query_row(&committed, [], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .optional()?,
+                _ => return Ok((0, None)),
+            };
+        if let Some((cursor, block_hash)) = committed {
+            return Ok((cursor as u64, parse_block_hash(block_hash)));
+        }
+
+        let query = format!(
+            "SELECT MAX({}) FROM {}.{}",
+            fallback.column, network, fallback.table
+        );
+        let cursor = match (sink, self.pg.as_ref(), self.sqlite.as_ref()) {
+            (SinkKind::Postgres, Some(pg), _) => pg
+                .client()
+                .await?
+                .query_opt(query.as_str(), &[])
+                .await?
+                .and_then(|row| row.get::<_, Option<i64>>(0))
+                .map(|number| number as u64),
+            (SinkKind::Sqlite, _, Some(sqlite)) => sqlite.query_optional_number(&query)?,
+            _ => None,
+        };
+        Ok((cursor.unwrap_or(0), None))
+    }
+}
+
+// Return the delay before restarting a topic which ran for `running`. The delay doubles on each
+// restart, from `MIN_RESTART_BACKOFF` after a healthy run up to `MAX_RESTART_BACKOFF`.
+fn restart_backoff(last: Option<Duration>, running: Duration) -> Duration {
+    match last {
+        Some(last) if running < HEALTHY_RUNNING_DURATION => {
+            ::std::cmp::min(last * 2, MAX_RESTART_BACKOFF)
+        }
+        _ => MIN_RESTART_BACKOFF,
+    }
+}
+
+fn parse_block_hash(block_hash: Option<String>) -> Option<packed::Byte32> {
+    let block_hash = block_hash?;
+    H256::from_str(block_hash.trim_start_matches("0x"))
+        .ok()
+        .map(|hash| hash.pack())
+}
+
+#[test]
+fn test_restart_backoff() {
+    let crashed = Duration::from_secs(1);
+    let mut backoff = None;
+    let mut delays = Vec::new();
+    for _ in 0..12 {
+        let delay = restart_backoff(backoff, crashed);
+        delays.push(delay.as_secs());
+        backoff = Some(delay);
+    }
+    assert_eq!(
+        delays,
+        vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300, 300, 300]
+    );
+    assert_eq!(
+        restart_backoff(backoff, HEALTHY_RUNNING_DURATION),
+        MIN_RESTART_BACKOFF
+    );
+}
+
+#[test]
+fn test_parse_block_hash() {
+    let hash = format!("0x{}", "ab".repeat(32));
+    assert_eq!(
+        parse_block_hash(Some(hash.clone())).map(|hash| format!("{:#x}", hash)),
+        Some(hash)
+    );
+    assert_eq!(parse_block_hash(Some("0x12".to_string())), None);
+    assert_eq!(parse_block_hash(None), None);
+}
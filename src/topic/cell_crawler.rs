@@ -1,116 +1,448 @@
+use crate::block_source::BlockSource;
 use crate::ckb_types::{
-    core::{BlockNumber, BlockView},
-    h256,
-    packed::OutPoint,
+    core::{BlockNumber, BlockView, Capacity, HeaderView},
+    packed,
     prelude::*,
 };
+use crate::config::BlockCrawlerConfig;
 use crate::entry;
+use crate::metrics;
+use crate::topic::reorg_window::ReorgWindow;
+use crate::topic::{with_block_cursor, BlockAnalyzer, ResumeCursor, Topic, TopicContext};
+use crate::util::network;
+use crate::util::scripts::script_catalogue;
+use crate::verify::Store;
 use ckb_testkit::Node;
+use crossbeam::channel::SendError;
+use futures::future::{BoxFuture, FutureExt};
+use lru::LruCache;
 use std::cmp::max;
-use std::convert::TryInto;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-const BLOCK_CONFIRMATION: BlockNumber = 10;
+// The number of recently created cells whose capacities are remembered, so that spending them
+// does not ask the node
+const CAPACITY_CACHE_SIZE: usize = 200_000;
 
 pub struct CellCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    blocks: Arc<BlockSource>,
+    store: Option<Store>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: BlockCrawlerConfig,
 }
 
 impl CellCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        blocks: Arc<BlockSource>,
+        store: Option<Store>,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: BlockCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            blocks,
+            store,
+            entry_sender,
+            config,
+        }
     }
 
-    pub async fn run(&self, last_cell_block_number: BlockNumber) {
+    pub async fn run(
+        &self,
+        last_cell_block_number: BlockNumber,
+        last_cell_block_hash: Option<packed::Byte32>,
+    ) {
         let mut current_number = max(1, last_cell_block_number + 1);
-        let mut tip_number = self.node.get_tip_block_number();
+        let mut tip_number = self.blocks.tip_number().await;
+        let mut capacities = LruCache::new(CAPACITY_CACHE_SIZE);
+        let mut window = ReorgWindow::new(self.config.reorg_window, self.store.clone());
+        if let Some(hash) = last_cell_block_hash {
+            window.push(last_cell_block_number, hash);
+        }
         loop {
-            // Keep `BLOCK_CONFIRMATION` distance with node's tip
-            if current_number >= tip_number - BLOCK_CONFIRMATION {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                tip_number = self.node.get_tip_block_number();
+            metrics::TOPIC_TIP
+                .with_label_values(&["CellCrawler"])
+                .set(tip_number as i64);
+            // Keep `block_confirmation` distance with node's tip
+            if current_number >= tip_number.saturating_sub(self.config.block_confirmation) {
+                tokio::time::sleep(self.config.poll_interval()).await;
+                tip_number = self.blocks.tip_number().await;
                 continue;
             }
 
-            let block = self.node.get_block_by_number(current_number);
-            self.analyze_block_cells(&block).await;
+            let block = self.blocks.get_block(current_number).await;
+            if let Some(fork) = window
+                .check("CellCrawler", &self.node, &self.blocks, &block)
+                .await
+            {
+                let rollback = fork.rollback(&network(&self.node), "CellCrawler");
+                if self.entry_sender.send(vec![rollback.into()]).is_err() {
+                    log::info!("CellCrawler stopped, the entry channel is closed");
+                    return;
+                }
+                current_number = fork.fork_number + 1;
+                continue;
+            }
+            if self
+                .analyze_block_cells(&block, &mut capacities)
+                .await
+                .is_err()
+            {
+                log::info!("CellCrawler stopped, the entry channel is closed");
+                return;
+            }
+            window.push(block.number(), block.hash());
 
+            metrics::TOPIC_CURSOR
+                .with_label_values(&["CellCrawler"])
+                .set(current_number as i64);
             current_number += 1;
         }
     }
 
-    async fn analyze_block_cells(&self, block: &BlockView) {
-        let time = chrono::NaiveDateTime::from_timestamp(
-            (block.timestamp() / 1000) as i64,
-            (block.timestamp() % 1000 * 1000) as u32,
-        );
-        let mut queries = Vec::new();
-        for (tx_index, tx) in block.transactions().iter().enumerate() {
-            let tx_hash = tx.hash();
-            if tx_index != 0 {
-                for input in tx.input_pts_iter() {
-                    let entry = entry::SpentCell {
-                        network: self.node.consensus().id.clone(),
-                        time,
-                        block_number: block.number(),
-                        out_point: input,
-                    };
-                    let raw_query = format!(
-                        "INSERT INTO {}.spent_cell (time, block_number, tx_hash, index) \
-                    VALUES ('{}', {}, '{:#x}', {})",
-                        entry.network,
-                        entry.time,
-                        entry.block_number,
-                        entry.out_point.tx_hash(),
-                        Unpack::<u32>::unpack(&entry.out_point.index()),
-                    );
-                    queries.push(raw_query);
+    async fn analyze_block_cells(
+        &self,
+        block: &BlockView,
+        capacities: &mut LruCache<packed::OutPoint, u64>,
+    ) -> Result<(), SendError<Vec<entry::Entry>>> {
+        let network = &network(&self.node);
+        for (out_point, capacity) in output_capacities(block) {
+            capacities.put(out_point, capacity);
+        }
+        let (mut spent, missing) = take_spent_capacities(block, capacities);
+        if !missing.is_empty() {
+            spent.extend(self.blocks.get_output_capacities(&missing).await);
+        }
+        let entries = cell_entries(network, block, |out_point| spent.get(out_point).copied());
+        let entries = with_block_cursor(network, "CellCrawler", block, entries);
+        self.entry_sender.send(entries)
+    }
+}
+
+/// Topic "CellCrawler"
+pub struct CellCrawlerTopic;
+
+impl Topic for CellCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "CellCrawler"
+    }
+
+    fn resume_cursor(&self) -> Option<ResumeCursor> {
+        Some(ResumeCursor {
+            table: "created_cell",
+            column: "block_number",
+        })
+    }
+
+    fn block_analyzer(&self) -> Option<&dyn BlockAnalyzer> {
+        Some(self)
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = CellCrawler::new(
+                context.node,
+                context.blocks,
+                context.store,
+                context.entry_sender,
+                context.config.cell_crawler.clone(),
+            );
+            handler.run(context.cursor, context.cursor_hash).await;
+        }
+        .boxed()
+    }
+}
+
+impl BlockAnalyzer for CellCrawlerTopic {
+    fn analyze(
+        &self,
+        network: &str,
+        block: &BlockView,
+        _parent: &HeaderView,
+        spent: &HashMap<packed::OutPoint, u64>,
+    ) -> Vec<entry::Entry> {
+        cell_entries(network, block, |out_point| spent.get(out_point).copied())
+    }
+
+    fn needs_spent_capacities(&self) -> bool {
+        true
+    }
+}
+
+/// Return the capacities of the cells spent by `chunk`, the consecutive blocks analyzed together.
+/// The cells created within the chunk are looked up in it, the others are fetched in batches.
+pub(crate) async fn spent_capacities(
+    blocks: &BlockSource,
+    chunk: &[BlockView],
+) -> HashMap<packed::OutPoint, u64> {
+    let mut capacities = LruCache::unbounded();
+    let mut spent = HashMap::new();
+    let mut missing = Vec::new();
+    for block in chunk {
+        for (out_point, capacity) in output_capacities(block) {
+            capacities.put(out_point, capacity);
+        }
+        let (block_spent, block_missing) = take_spent_capacities(block, &mut capacities);
+        spent.extend(block_spent);
+        missing.extend(block_missing);
+    }
+    if !missing.is_empty() {
+        spent.extend(blocks.get_output_capacities(&missing).await);
+    }
+    spent
+}
+
+// Return the capacities of the cells created by `block`
+fn output_capacities(block: &BlockView) -> impl Iterator<Item = (packed::OutPoint, u64)> + '_ {
+    block.transactions().into_iter().flat_map(|tx| {
+        let tx_hash = tx.hash();
+        tx.outputs()
+            .into_iter()
+            .enumerate()
+            .map(move |(index, output)| {
+                let out_point = packed::OutPoint::new(tx_hash.clone(), index as u32);
+                (out_point, Unpack::<u64>::unpack(&output.capacity()))
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+// Take the capacities of the cells spent by `block` out of `capacities`, a cell is spent once.
+// Return them with the spent cells not found.
+fn take_spent_capacities(
+    block: &BlockView,
+    capacities: &mut LruCache<packed::OutPoint, u64>,
+) -> (HashMap<packed::OutPoint, u64>, Vec<packed::OutPoint>) {
+    let mut spent = HashMap::new();
+    let mut missing = Vec::new();
+    for tx in block.transactions().iter().skip(1) {
+        for input in tx.input_pts_iter() {
+            match capacities.pop(&input) {
+                Some(capacity) => {
+                    spent.insert(input, capacity);
                 }
+                None => missing.push(input),
             }
+        }
+    }
+    (spent, missing)
+}
 
-            for (index, output) in tx.outputs().into_iter().enumerate() {
-                let out_point = OutPoint::new(tx_hash.clone(), index as u32);
-                let entry = entry::CreatedCell {
-                    network: self.node.consensus().id.clone(),
+// Return the cells created and spent by `block`. The capacity of a spent cell is told by
+// `get_capacity`.
+fn cell_entries<F>(network: &str, block: &BlockView, mut get_capacity: F) -> Vec<entry::Entry>
+where
+    F: FnMut(&packed::OutPoint) -> Option<u64>,
+{
+    let time = chrono::NaiveDateTime::from_timestamp(
+        (block.timestamp() / 1000) as i64,
+        (block.timestamp() % 1000 * 1000) as u32,
+    );
+    let catalogue = script_catalogue(network);
+    let mut entries: Vec<entry::Entry> = Vec::new();
+    for (tx_index, tx) in block.transactions().iter().enumerate() {
+        let tx_hash = tx.hash();
+        if tx_index != 0 {
+            for input in tx.input_pts_iter() {
+                let entry = entry::SpentCell {
+                    network: network.to_string(),
                     time,
-                    block_number: block.number(),
-                    tx_index,
-                    out_point,
-                    lock_hash_type: output.lock().hash_type().try_into().unwrap(),
-                    lock_code_hash: output.lock().code_hash(),
-                    lock_args: {
-                        if output.lock().code_hash() == h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack() {
-                            if output.lock().args().raw_data().len() <= 48 {
-                                Some(output.lock().args().raw_data())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    },
-                    type_hash_type: output
-                        .type_()
-                        .to_opt()
-                        .map(|script| script.hash_type().try_into().unwrap()),
-                    type_code_hash: output.type_().to_opt().map(|script| script.code_hash()),
+                    block_number: block.number() as i64,
+                    tx_hash: format!("{:#x}", input.tx_hash()),
+                    index: Unpack::<u32>::unpack(&input.index()) as i64,
+                    capacity: get_capacity(&input).map(|capacity| capacity as i64),
                 };
-                let raw_query = format!(
-                    "INSERT INTO {}.created_cell (time, block_number, tx_index, tx_hash, index, lock_hash_type, lock_code_hash, lock_args, type_hash_type, type_code_hash) \
-                    VALUES ('{}', {}, {}, '{:#x}', {}, {}, '{:#x}', '{}', {}, '{}')",
-                    entry.network, entry.time, entry.block_number, entry.tx_index, entry.out_point.tx_hash(), Unpack::<u32>::unpack(&entry.out_point.index()),
-                    Into::<u8>::into(entry.lock_hash_type),
-                    entry.lock_code_hash,
-                    entry.lock_args.map(|h| format!("{:#x}", h)).unwrap_or_default(),
-                    entry.type_hash_type.map(|t| Into::<u8>::into(t)).unwrap_or(u8::max_value()),
-                    entry.type_code_hash.map(|h| format!("{:#x}", h)).unwrap_or_default(),
-                );
-                queries.push(raw_query);
+                entries.push(entry.into());
             }
         }
 
-        let batch_query = queries.join(";");
-        self.query_sender.send(batch_query).unwrap();
+        for (index, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+            // The raw hash type bytes are recorded, including the ones unknown to us
+            let lock_hash_type: u8 = output.lock().hash_type().into();
+            let type_hash_type: Option<u8> = output
+                .type_()
+                .to_opt()
+                .map(|script| script.hash_type().into());
+            let entry = entry::CreatedCell {
+                network: network.to_string(),
+                time,
+                block_number: block.number() as i64,
+                tx_index: tx_index as i32,
+                tx_hash: format!("{:#x}", tx_hash),
+                index: index as i32,
+                lock_hash_type: lock_hash_type as i32,
+                lock_code_hash: format!("{:#x}", output.lock().code_hash()),
+                lock_args: format!("0x{:x}", output.lock().args().raw_data()),
+                lock_hash: format!("{:#x}", output.lock().calc_script_hash()),
+                type_hash_type: type_hash_type
+                    .map(|t| t as i32)
+                    .unwrap_or_else(|| u8::max_value() as i32),
+                type_code_hash: output
+                    .type_()
+                    .to_opt()
+                    .map(|script| format!("{:#x}", script.code_hash()))
+                    .unwrap_or_default(),
+                type_args: output
+                    .type_()
+                    .to_opt()
+                    .map(|script| format!("0x{:x}", script.args().raw_data())),
+                type_hash: output
+                    .type_()
+                    .to_opt()
+                    .map(|script| format!("{:#x}", script.calc_script_hash())),
+                capacity: Unpack::<u64>::unpack(&output.capacity()) as i64,
+                occupied_capacity: output
+                    .occupied_capacity(Capacity::bytes(data.len()).unwrap())
+                    .unwrap()
+                    .as_u64() as i64,
+                data_size: data.len() as i32,
+                script_kind: catalogue.cell_kind(&output, &data).map(ToString::to_string),
+            };
+            entries.push(entry.into());
+        }
+    }
+    entries
+}
+
+#[test]
+fn test_cell_entries() {
+    use crate::ckb_types::{
+        bytes::Bytes,
+        core::{BlockBuilder, TransactionBuilder},
+        h256,
+    };
+
+    let script = |code_hash: packed::Byte32, hash_type: u8, args: &[u8]| {
+        packed::Script::new_builder()
+            .code_hash(code_hash)
+            .hash_type(packed::Byte::new(hash_type))
+            .args(Bytes::from(args.to_vec()).pack())
+            .build()
+    };
+    let sighash =
+        h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack();
+    let lock = script(sighash, 1, &[0xab; 20]);
+    // A hash type unknown to us is valid on-chain input
+    let type_ = script(packed::Byte32::zero(), 0x7f, &[1, 2, 3]);
+    let spent = packed::OutPoint::new(packed::Byte32::zero(), 1);
+    let tx = TransactionBuilder::default()
+        .input(packed::CellInput::new(spent.clone(), 0))
+        .output(
+            packed::CellOutput::new_builder()
+                .capacity(Capacity::shannons(200_0000_0000).pack())
+                .lock(lock.clone())
+                .build(),
+        )
+        .output_data(Bytes::from(vec![0u8; 8]).pack())
+        .output(
+            packed::CellOutput::new_builder()
+                .capacity(Capacity::shannons(300_0000_0000).pack())
+                .lock(lock.clone())
+                .type_(Some(type_.clone()).pack())
+                .build(),
+        )
+        .output_data(Bytes::new().pack())
+        .build();
+    let block = BlockBuilder::default()
+        .number(1u64.pack())
+        .transaction(TransactionBuilder::default().build())
+        .transaction(tx)
+        .build();
+
+    let entries = cell_entries("ckb_dev", &block, |out_point| {
+        if out_point == &spent {
+            Some(100)
+        } else {
+            None
+        }
+    });
+    let (spent_cells, created_cells): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|entry| match entry {
+            entry::Entry::SpentCell(_) => true,
+            _ => false,
+        });
+    match &spent_cells[..] {
+        [entry::Entry::SpentCell(cell)] => {
+            assert_eq!(cell.index, 1);
+            assert_eq!(cell.capacity, Some(100));
+        }
+        entries => panic!("unexpected spent cells {:?}", entries),
+    }
+    let created_cells = created_cells
+        .into_iter()
+        .map(|entry| match entry {
+            entry::Entry::CreatedCell(cell) => cell,
+            entry => panic!("unexpected entry {:?}", entry),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(created_cells.len(), 2);
+    let lock_args = format!("0x{}", "ab".repeat(20));
+    let lock_hash = format!("{:#x}", lock.calc_script_hash());
+    for cell in created_cells.iter() {
+        assert_eq!(cell.lock_hash_type, 1);
+        assert_eq!(cell.lock_args, lock_args);
+        assert_eq!(cell.lock_hash, lock_hash);
     }
+
+    let plain = &created_cells[0];
+    assert_eq!(plain.type_hash_type, u8::max_value() as i32);
+    assert_eq!(plain.type_code_hash, "");
+    assert_eq!(plain.type_args, None);
+    assert_eq!(plain.type_hash, None);
+    assert_eq!(plain.capacity, 200_0000_0000);
+    assert_eq!(plain.data_size, 8);
+    assert_eq!(plain.script_kind.as_deref(), Some("sighash"));
+
+    let typed = &created_cells[1];
+    assert_eq!(typed.type_hash_type, 0x7f);
+    assert_eq!(
+        typed.type_code_hash,
+        format!("{:#x}", packed::Byte32::zero())
+    );
+    assert_eq!(typed.type_args.as_deref(), Some("0x010203"));
+    assert_eq!(
+        typed.type_hash,
+        Some(format!("{:#x}", type_.calc_script_hash()))
+    );
+    assert_eq!(typed.capacity, 300_0000_0000);
+    assert_eq!(typed.data_size, 0);
+    // Neither script is well-known, the cell falls back to the kind of its lock
+    assert_eq!(typed.script_kind.as_deref(), Some("sighash"));
+}
+
+#[test]
+fn test_take_spent_capacities() {
+    use crate::ckb_types::core::{BlockBuilder, TransactionBuilder};
+
+    let out_point =
+        |seed: u8, index: u32| packed::OutPoint::new(packed::Byte32::new([seed; 32]), index);
+    let spend = |out_points: &[packed::OutPoint]| {
+        TransactionBuilder::default()
+            .inputs(
+                out_points
+                    .iter()
+                    .map(|out_point| packed::CellInput::new(out_point.clone(), 0)),
+            )
+            .build()
+    };
+    let cellbase = spend(&[packed::OutPoint::null()]);
+    let cached = out_point(1, 0);
+    let unknown = out_point(2, 1);
+    let block = BlockBuilder::default()
+        .transaction(cellbase)
+        .transaction(spend(&[cached.clone(), unknown.clone()]))
+        .build();
+
+    let mut capacities = LruCache::new(4);
+    capacities.put(cached.clone(), 100);
+    capacities.put(out_point(3, 0), 300);
+    let (spent, missing) = take_spent_capacities(&block, &mut capacities);
+    assert_eq!(spent.get(&cached), Some(&100));
+    assert_eq!(spent.len(), 1);
+    assert_eq!(missing, vec![unknown]);
+    // A cell is spent once, the spent ones are forgotten
+    assert!(!capacities.contains(&cached));
+    assert_eq!(capacities.len(), 1);
 }
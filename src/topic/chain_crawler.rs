@@ -1,113 +1,131 @@
+use crate::block_source::BlockSource;
+use crate::ckb_jsonrpc_types::BlockEconomicState;
+use crate::ckb_types::utilities::compact_to_difficulty;
 use crate::ckb_types::{
-    core::{BlockNumber, BlockView},
+    core::{BlockNumber, BlockView, HeaderView},
     h256, packed,
     prelude::*,
 };
+use crate::config::ChainCrawlerConfig;
 use crate::entry;
+use crate::metrics;
+use crate::topic::proposal_tracker::ProposalTracker;
+use crate::topic::reorg_window::{Fork, ReorgWindow};
+use crate::topic::{with_block_cursor, BlockAnalyzer, ResumeCursor, Topic, TopicContext};
+use crate::util::miners::lookup_miner;
+use crate::util::network;
+use crate::verify::Store;
 use ckb_testkit::Node;
+use futures::future::{BoxFuture, FutureExt};
 use std::cmp::max;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::Duration;
 
-const BLOCK_CONFIRMATION: BlockNumber = 10;
-
 pub struct ChainCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    blocks: Arc<BlockSource>,
+    store: Option<Store>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: ChainCrawlerConfig,
 }
 
 impl ChainCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        blocks: Arc<BlockSource>,
+        store: Option<Store>,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: ChainCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            blocks,
+            store,
+            entry_sender,
+            config,
+        }
     }
 
-    pub async fn run(&self, last_block_number: BlockNumber) {
-        let mut current_number = max(1, last_block_number);
-        let mut tip_number = self.node.get_tip_block_number();
+    pub async fn run(
+        &self,
+        last_block_number: BlockNumber,
+        last_block_hash: Option<packed::Byte32>,
+    ) {
+        let mut current_number = max(1, last_block_number + 1);
+        let mut tip_number = self.blocks.tip_number().await;
+        let mut window = ReorgWindow::new(self.config.reorg_window, self.store.clone());
+        if let Some(hash) = last_block_hash {
+            window.push(last_block_number, hash);
+        }
+        let mut settlements = Settlements::new(self.node.clone(), Arc::clone(&self.blocks));
+        settlements.restore(current_number).await;
         loop {
-            // Keep `BLOCK_CONFIRMATION` distance with node's tip
-            if current_number >= tip_number - BLOCK_CONFIRMATION {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                tip_number = self.node.get_tip_block_number();
+            metrics::TOPIC_TIP
+                .with_label_values(&["ChainCrawler"])
+                .set(tip_number as i64);
+            // Keep `block_confirmation` distance with node's tip
+            if current_number >= tip_number.saturating_sub(self.config.block_confirmation) {
+                tokio::time::sleep(self.config.poll_interval()).await;
+                tip_number = self.blocks.tip_number().await;
+                continue;
+            }
+
+            let block = self.blocks.get_block(current_number).await;
+            if let Some(fork) = window
+                .check("ChainCrawler", &self.node, &self.blocks, &block)
+                .await
+            {
+                self.rollback(&fork, &block).await;
+                current_number = fork.fork_number + 1;
+                settlements.restore(current_number).await;
                 continue;
             }
 
-            let block = self.node.get_block_by_number(current_number);
-            self.analyze_block(&block).await;
+            self.analyze_block(&block, &mut settlements).await;
 
+            window.push(block.number(), block.hash());
+            metrics::TOPIC_CURSOR
+                .with_label_values(&["ChainCrawler"])
+                .set(current_number as i64);
             current_number += 1;
         }
     }
 
-    async fn analyze_block(&self, block: &BlockView) {
-        let time = chrono::NaiveDateTime::from_timestamp(
-            (block.timestamp() / 1000) as i64,
-            (block.timestamp() % 1000 * 1000) as u32,
-        );
-        let interval = {
-            let parent = self
-                .node
-                .rpc_client()
-                .get_header(block.parent_hash())
-                .unwrap();
-            block
-                .timestamp()
-                .saturating_sub(parent.inner.timestamp.value())
+    // Roll back the orphaned rows above the fork point and record the reorg event
+    async fn rollback(&self, fork: &Fork, block: &BlockView) {
+        let network = network(&self.node);
+        let reorg = entry::Reorg {
+            network: network.clone(),
+            time: chrono::Utc::now().naive_utc(),
+            fork_number: fork.fork_number as i64,
+            depth: fork.depth() as i64,
+            old_tip_hash: format!("{:#x}", fork.old_tip_hash),
+            new_tip_hash: format!("{:#x}", block.parent_hash()),
         };
-        let number = block.number();
-        let n_transactions = block.transactions().len() as u32;
-        let n_proposals = block.union_proposal_ids().len() as u32;
-        let n_uncles = block.uncles().hashes().len() as u32;
-        let miner_lock = extract_miner_lock_from_cellbase(block);
-        let miner_lock_args = if miner_lock.code_hash()
-            == h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack()
-        {
-            if miner_lock.args().len() <= 48 {
-                Some(miner_lock.args())
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        let (cellbase_client_version, cellbase_miner_source) = extract_cellbase_message(block);
-        let entry = entry::Block {
-            network: self.node.consensus().id.clone(),
-            time,
-            number: number as i64,
-            n_transactions: n_transactions as i32,
-            n_proposals: n_proposals as i32,
-            n_uncles: n_uncles as i32,
-            cellbase_client_version,
-            cellbase_miner_source,
-            miner_lock_args: miner_lock_args
-                .map(|arg| format!("{:#x}", arg))
-                .unwrap_or_else(|| "-".to_string()),
-            interval: interval as i64,
-            hash: block.hash(),
-        };
-        self.retry_send_entry_query(&entry).await;
-    }
-
-    async fn retry_send_entry_query(&self, entry: &entry::Block) {
-        let query =
-            format!(
-                "INSERT INTO {}.block(time, number, n_transactions, n_proposals, n_uncles, miner_lock_args, cellbase_client_version, cellbase_miner_source, interval, hash) \
-            VALUES ('{}', {}, {}, {}, {}, '{}', '{}', '{}', {}, '{:#x}') \
-            ON CONFLICT (number) DO NOTHING",
-                entry.network,
-                entry.time,
-                entry.number,
-                entry.n_transactions,
-                entry.n_proposals,
-                entry.n_uncles,
-                entry.miner_lock_args,
-                entry.cellbase_client_version,
-                entry.cellbase_miner_source,
-                entry.interval,
-                entry.hash,
-        );
+        let rollback = fork.rollback(&network, "ChainCrawler");
+        self.retry_send_entries(vec![rollback.into(), reorg.into()])
+            .await;
+    }
+
+    async fn analyze_block(&self, block: &BlockView, settlements: &mut Settlements) {
+        let parent = self.blocks.get_parent_header(block).await;
+        let cycles = self.blocks.get_block_cycles(block.number()).await;
+        let network = &network(&self.node);
+        let block_entry = block_entry(network, block, &parent, cycles);
+        let mut entries = vec![miner_entry(&block_entry).into(), block_entry.into()];
+        entries.extend(uncle_entries(network, block, |hash| {
+            self.node.rpc_client().get_block(hash).map(Into::into)
+        }));
+        entries.extend(settlements.settle(block).await);
+        let entries = with_block_cursor(network, "ChainCrawler", block, entries);
+        self.retry_send_entries(entries).await;
+    }
+
+    async fn retry_send_entries(&self, entries: Vec<entry::Entry>) {
         loop {
-            match self.query_sender.send(query.clone()) {
+            match self.entry_sender.send(entries.clone()) {
                 Ok(_) => return,
                 Err(_) => {
                     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -117,16 +135,293 @@ impl ChainCrawler {
     }
 }
 
+/// Settlements derives the entries settled across blocks, the proposals whose windows close at a
+/// block and the reward paid by its cellbase. Unlike [`BlockAnalyzer`], they cannot be derived from
+/// a block alone, so that `verify --repair` replays them over the repaired blocks.
+pub struct Settlements {
+    node: Node,
+    blocks: Arc<BlockSource>,
+    proposals: ProposalTracker,
+}
+
+impl Settlements {
+    pub fn new(node: Node, blocks: Arc<BlockSource>) -> Self {
+        let proposal_window = node.consensus().tx_proposal_window;
+        let proposals = ProposalTracker::new(proposal_window.closest(), proposal_window.farthest());
+        Self {
+            node,
+            blocks,
+            proposals,
+        }
+    }
+
+    /// The number of the following blocks whose settlements depend on a block
+    pub fn horizon(&self) -> BlockNumber {
+        let consensus = self.node.consensus();
+        max(
+            consensus.tx_proposal_window.farthest(),
+            consensus.finalization_delay_length(),
+        )
+    }
+
+    /// Track the blocks before `number` again, to restore the proposal windows which are still
+    /// open at `number`. The proposals settled by these blocks were written already.
+    pub async fn restore(&mut self, number: BlockNumber) {
+        self.proposals.reset();
+        let network = &network(&self.node);
+        for number in self.proposals.replay_from(number)..number {
+            let block = self.blocks.get_block(number).await;
+            let _ = self.proposals.track(network, &block);
+        }
+    }
+
+    /// Return the entries settled at `block`, the child of the last settled or restored block
+    pub async fn settle(&mut self, block: &BlockView) -> Vec<entry::Entry> {
+        let mut entries = self
+            .proposals
+            .track(&network(&self.node), block)
+            .into_iter()
+            .map(entry::Entry::from)
+            .collect::<Vec<_>>();
+        if let Some(reward) = self.block_reward(block).await {
+            entries.push(reward.into());
+        }
+        entries
+    }
+
+    // Return the reward paid by the cellbase of `block`. The reward is finalized
+    // `finalization_delay_length` blocks after the rewarded block, there is no reward before.
+    async fn block_reward(&self, block: &BlockView) -> Option<entry::BlockReward> {
+        let delay = self.node.consensus().finalization_delay_length();
+        if block.number() <= delay {
+            return None;
+        }
+        let target_number = block.number() - delay;
+        let target = self.blocks.get_block(target_number).await;
+        let state = self.blocks.get_block_economic_state(&target.hash()).await?;
+        if state.finalized_at.pack() != block.hash() {
+            log::warn!(
+                "the reward of block #{} is not finalized at block #{} {:#x}, but {:#x}",
+                target_number,
+                block.number(),
+                block.hash(),
+                state.finalized_at
+            );
+            return None;
+        }
+        block_reward_entry(&network(&self.node), block, target_number, &state)
+    }
+}
+
+/// Topic "ChainCrawler"
+pub struct ChainCrawlerTopic;
+
+impl Topic for ChainCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "ChainCrawler"
+    }
+
+    fn resume_cursor(&self) -> Option<ResumeCursor> {
+        Some(ResumeCursor {
+            table: "block",
+            column: "number",
+        })
+    }
+
+    fn block_analyzer(&self) -> Option<&dyn BlockAnalyzer> {
+        Some(self)
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = ChainCrawler::new(
+                context.node,
+                context.blocks,
+                context.store,
+                context.entry_sender,
+                context.config.chain_crawler.clone(),
+            );
+            handler.run(context.cursor, context.cursor_hash).await;
+        }
+        .boxed()
+    }
+}
+
+impl BlockAnalyzer for ChainCrawlerTopic {
+    fn analyze(
+        &self,
+        network: &str,
+        block: &BlockView,
+        parent: &HeaderView,
+        _spent: &HashMap<packed::OutPoint, u64>,
+    ) -> Vec<entry::Entry> {
+        // The cycles and the uncle blocks are fetched separately, they are not a part of the block.
+        // The proposals are tracked across blocks, which are analyzed in any order here. The
+        // rewards are fetched separately too.
+        let block_entry = block_entry(network, block, parent, None);
+        let mut entries = vec![miner_entry(&block_entry).into(), block_entry.into()];
+        entries.extend(uncle_entries(network, block, |_| None));
+        entries
+    }
+}
+
+fn block_entry(
+    network: &str,
+    block: &BlockView,
+    parent: &HeaderView,
+    cycles: Option<u64>,
+) -> entry::Block {
+    let time = chrono::NaiveDateTime::from_timestamp(
+        (block.timestamp() / 1000) as i64,
+        (block.timestamp() % 1000 * 1000) as u32,
+    );
+    let interval = block.timestamp().saturating_sub(parent.timestamp());
+    let number = block.number();
+    let n_transactions = block.transactions().len() as u32;
+    let n_proposals = block.union_proposal_ids().len() as u32;
+    let n_uncles = block.uncles().hashes().len() as u32;
+    let message = cellbase_message(block);
+    let (cellbase_client_version, cellbase_miner_source) = parse_cellbase_message(&message);
+    let miner_lock = extract_miner_lock_from_cellbase(block);
+    let miner = lookup_miner(&miner_lock, &message);
+    let header = block.header();
+    let [dao_c, dao_ar, dao_s, dao_u] = extract_dao(&header.dao());
+    let epoch = header.epoch();
+    entry::Block {
+        network: network.to_string(),
+        time,
+        number: number as i64,
+        n_transactions: n_transactions as i32,
+        n_proposals: n_proposals as i32,
+        n_uncles: n_uncles as i32,
+        cellbase_client_version,
+        cellbase_miner_source,
+        miner_lock_args: miner_lock_args(&miner_lock),
+        miner_lock_hash: format!("{:#x}", miner_lock.calc_script_hash()),
+        interval: interval as i64,
+        hash: format!("{:#x}", block.hash()),
+        size: block.data().serialized_size_without_uncle_proposals() as i64,
+        cycles: cycles.map(|cycles| cycles as i64),
+        compact_target: header.compact_target() as i64,
+        difficulty: compact_to_difficulty(header.compact_target()).to_string(),
+        dao_c: dao_c as i64,
+        dao_ar: dao_ar as i64,
+        dao_s: dao_s as i64,
+        dao_u: dao_u as i64,
+        version: header.version() as i32,
+        epoch_number: epoch.number() as i64,
+        epoch_index: epoch.index() as i32,
+        epoch_length: epoch.length() as i32,
+        has_extension: block.extension().is_some(),
+        miner,
+    }
+}
+
+fn miner_entry(block: &entry::Block) -> entry::Miner {
+    entry::Miner {
+        network: block.network.clone(),
+        miner_lock_hash: block.miner_lock_hash.clone(),
+        miner_lock_args: block.miner_lock_args.clone(),
+        miner: block.miner.clone(),
+        first_number: block.number,
+        first_time: block.time,
+        last_number: block.number,
+        last_time: block.time,
+    }
+}
+
+// Return the uncles included by `block`. The miner of an uncle is told by its cellbase, which is
+// known only if `get_uncle_block` returns the uncle block.
+fn uncle_entries<F>(network: &str, block: &BlockView, get_uncle_block: F) -> Vec<entry::Entry>
+where
+    F: Fn(packed::Byte32) -> Option<BlockView>,
+{
+    let mut entries: Vec<entry::Entry> = Vec::new();
+    for uncle in block.uncles().data().into_iter() {
+        let header = uncle.header().into_view();
+        let uncle_block = get_uncle_block(header.hash());
+        let message = uncle_block.as_ref().map(extract_cellbase_message);
+        let entry = entry::Uncle {
+            network: network.to_string(),
+            time: chrono::NaiveDateTime::from_timestamp(
+                (header.timestamp() / 1000) as i64,
+                (header.timestamp() % 1000 * 1000) as u32,
+            ),
+            number: header.number() as i64,
+            hash: format!("{:#x}", header.hash()),
+            block_number: block.number() as i64,
+            distance: block.number().saturating_sub(header.number()) as i64,
+            miner_lock_args: uncle_block
+                .as_ref()
+                .map(|uncle_block| miner_lock_args(&extract_miner_lock_from_cellbase(uncle_block))),
+            cellbase_client_version: message.as_ref().map(|(version, _)| version.clone()),
+            cellbase_miner_source: message.map(|(_, source)| source),
+        };
+        entries.push(entry.into());
+    }
+    entries
+}
+
+// Return the reward paid by the cellbase of `block` for block `target_number`, `None` if the
+// cellbase has no output
+fn block_reward_entry(
+    network: &str,
+    block: &BlockView,
+    target_number: BlockNumber,
+    state: &BlockEconomicState,
+) -> Option<entry::BlockReward> {
+    let output = block.transaction(0)?.outputs().get(0)?;
+    let reward = &state.miner_reward;
+    Some(entry::BlockReward {
+        network: network.to_string(),
+        time: chrono::NaiveDateTime::from_timestamp(
+            (block.timestamp() / 1000) as i64,
+            (block.timestamp() % 1000 * 1000) as u32,
+        ),
+        number: block.number() as i64,
+        target_number: target_number as i64,
+        lock_hash: format!("{:#x}", output.lock().calc_script_hash()),
+        primary_reward: reward.primary.value() as i64,
+        secondary_reward: reward.secondary.value() as i64,
+        commit_reward: reward.committed.value() as i64,
+        proposal_reward: reward.proposal.value() as i64,
+    })
+}
+
+// Return the args of the miner lock if it is the default secp256k1 lock, otherwise "-"
+fn miner_lock_args(miner_lock: &packed::Script) -> String {
+    let args = miner_lock.args().raw_data();
+    if miner_lock.code_hash()
+        == h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack()
+        && args.len() <= 48
+    {
+        format!("0x{:x}", args)
+    } else {
+        "-".to_string()
+    }
+}
+
+// Return C, AR, S and U of the DAO field, each a 64-bit little-endian integer
+fn extract_dao(dao: &packed::Byte32) -> [u64; 4] {
+    let mut fields = [0u64; 4];
+    for (field, bytes) in fields.iter_mut().zip(dao.as_slice().chunks(8)) {
+        *field = u64::from_le_bytes(bytes.try_into().expect("8 bytes"));
+    }
+    fields
+}
+
 // When cellbase message contains 1 vector, it indicates miner identifier;
 // When cellbase message contains more than one vectors, the first one indicates client version,
 // the second one indicates miner identifier.
 fn extract_cellbase_message(block: &BlockView) -> (String, String) {
+    parse_cellbase_message(&cellbase_message(block))
+}
+
+fn cellbase_message(block: &BlockView) -> String {
     let cellbase = block.transaction(0).unwrap();
     let witness = cellbase.witnesses().get(0).unwrap().raw_data();
     let cellbase_witness = packed::CellbaseWitness::from_slice(witness.as_ref()).unwrap();
-    let message =
-        String::from_utf8(cellbase_witness.message().raw_data().to_vec()).unwrap_or_default();
-    parse_cellbase_message(&message)
+    String::from_utf8(cellbase_witness.message().raw_data().to_vec()).unwrap_or_default()
 }
 
 fn extract_miner_lock_from_cellbase(block: &BlockView) -> packed::Script {
@@ -173,3 +468,13 @@ fn test_parse_cellbase_message() {
         );
     }
 }
+
+#[test]
+fn test_extract_dao() {
+    let mut dao = Vec::new();
+    for field in [1u64, 10_000_000_000_000_000, 3, u64::MAX].iter() {
+        dao.extend_from_slice(&field.to_le_bytes());
+    }
+    let dao = packed::Byte32::from_slice(&dao).unwrap();
+    assert_eq!(extract_dao(&dao), [1, 10_000_000_000_000_000, 3, u64::MAX]);
+}
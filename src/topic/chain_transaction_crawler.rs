@@ -1,84 +1,187 @@
-use crate::ckb_types::core::{BlockNumber, BlockView};
+use crate::block_source::BlockSource;
+use crate::ckb_types::{
+    core::{BlockNumber, BlockView, HeaderView},
+    packed,
+};
+use crate::config::BlockCrawlerConfig;
 use crate::entry;
+use crate::metrics;
+use crate::topic::reorg_window::ReorgWindow;
+use crate::topic::{with_block_cursor, BlockAnalyzer, ResumeCursor, Topic, TopicContext};
+use crate::util::network;
+use crate::util::scripts::script_catalogue;
+use crate::verify::Store;
 use ckb_testkit::Node;
+use crossbeam::channel::SendError;
+use futures::future::{BoxFuture, FutureExt};
 use std::cmp::max;
-use std::time::Duration;
-
-const BLOCK_CONFIRMATION: BlockNumber = 10;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 pub struct ChainTransactionCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    blocks: Arc<BlockSource>,
+    store: Option<Store>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: BlockCrawlerConfig,
 }
 
 impl ChainTransactionCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        blocks: Arc<BlockSource>,
+        store: Option<Store>,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: BlockCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            blocks,
+            store,
+            entry_sender,
+            config,
+        }
     }
 
-    pub async fn run(&self, last_block_number: BlockNumber) {
-        let mut current_number = max(1, last_block_number);
-        let mut tip_number = self.node.get_tip_block_number();
+    pub async fn run(
+        &self,
+        last_block_number: BlockNumber,
+        last_block_hash: Option<packed::Byte32>,
+    ) {
+        let mut current_number = max(1, last_block_number + 1);
+        let mut tip_number = self.blocks.tip_number().await;
+        let mut window = ReorgWindow::new(self.config.reorg_window, self.store.clone());
+        if let Some(hash) = last_block_hash {
+            window.push(last_block_number, hash);
+        }
         loop {
-            // Keep `BLOCK_CONFIRMATION` distance with node's tip
-            if current_number >= tip_number - BLOCK_CONFIRMATION {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                tip_number = self.node.get_tip_block_number();
+            metrics::TOPIC_TIP
+                .with_label_values(&["ChainTransactionCrawler"])
+                .set(tip_number as i64);
+            // Keep `block_confirmation` distance with node's tip
+            if current_number >= tip_number.saturating_sub(self.config.block_confirmation) {
+                tokio::time::sleep(self.config.poll_interval()).await;
+                tip_number = self.blocks.tip_number().await;
                 continue;
             }
 
-            let block = self.node.get_block_by_number(current_number);
-            self.analyze_block_transactions(&block).await;
+            let block = self.blocks.get_block(current_number).await;
+            if let Some(fork) = window
+                .check("ChainTransactionCrawler", &self.node, &self.blocks, &block)
+                .await
+            {
+                let rollback = fork.rollback(&network(&self.node), "ChainTransactionCrawler");
+                if self.entry_sender.send(vec![rollback.into()]).is_err() {
+                    log::info!("ChainTransactionCrawler stopped, the entry channel is closed");
+                    return;
+                }
+                current_number = fork.fork_number + 1;
+                continue;
+            }
+            if self.analyze_block_transactions(&block).await.is_err() {
+                log::info!("ChainTransactionCrawler stopped, the entry channel is closed");
+                return;
+            }
+            window.push(block.number(), block.hash());
 
+            metrics::TOPIC_CURSOR
+                .with_label_values(&["ChainTransactionCrawler"])
+                .set(current_number as i64);
             current_number += 1;
         }
     }
 
-    async fn analyze_block_transactions(&self, block: &BlockView) {
-        let mut raw_queries = Vec::with_capacity(block.transactions().len());
-        let time = chrono::NaiveDateTime::from_timestamp(
-            (block.timestamp() / 1000) as i64,
-            (block.timestamp() % 1000 * 1000) as u32,
+    async fn analyze_block_transactions(
+        &self,
+        block: &BlockView,
+    ) -> Result<(), SendError<Vec<entry::Entry>>> {
+        let network = &network(&self.node);
+        let entries = with_block_cursor(
+            network,
+            "ChainTransactionCrawler",
+            block,
+            transaction_entries(network, block),
         );
-        for tx in block.transactions() {
-            let size = tx.data().serialized_size_in_block();
-            let n_inputs = tx.inputs().len();
-            let n_outputs = tx.outputs().len();
-            let n_header_deps = tx.header_deps().len();
-            let n_cell_deps = tx.cell_deps().len();
-            let total_data_size = tx.outputs_data().total_size();
-            let entry = entry::BlockTransaction {
-                time,
-                network: self.node.consensus().id.clone(),
-                number: block.number() as i64,
-                size: size as i32,
-                n_inputs: n_inputs as i32,
-                n_outputs: n_outputs as i32,
-                n_cell_deps: n_cell_deps as i32,
-                n_header_deps: n_header_deps as i32,
-                total_data_size: total_data_size as i32,
-                proposal_id: format!("{:#x}", tx.proposal_short_id()),
-                hash: format!("{:#x}", tx.hash()),
-            };
-            let raw_query = format!(
-                "INSERT INTO {}.block_transaction(time, number, size, n_inputs, n_outputs, n_header_deps, n_cell_deps, total_data_size, proposal_id, hash) \
-                VALUES ('{}', {}, {}, {}, {}, {}, {}, {}, '{}', '{}') \
-                ON CONFLICT (number) DO NOTHING",
-                entry.network,
-                entry.time,
-                entry.number,
-                entry.size,
-                entry.n_inputs,
-                entry.n_outputs,
-                entry.n_header_deps,
-                entry.n_cell_deps,
-                entry.total_data_size,
-                entry.proposal_id,
-                entry.hash,
+        self.entry_sender.send(entries)
+    }
+}
+
+/// Topic "ChainTransactionCrawler"
+pub struct ChainTransactionCrawlerTopic;
+
+impl Topic for ChainTransactionCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "ChainTransactionCrawler"
+    }
+
+    fn resume_cursor(&self) -> Option<ResumeCursor> {
+        Some(ResumeCursor {
+            table: "block_transaction",
+            column: "number",
+        })
+    }
+
+    fn block_analyzer(&self) -> Option<&dyn BlockAnalyzer> {
+        Some(self)
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = ChainTransactionCrawler::new(
+                context.node,
+                context.blocks,
+                context.store,
+                context.entry_sender,
+                context.config.chain_transaction_crawler.clone(),
             );
-            raw_queries.push(raw_query);
+            handler.run(context.cursor, context.cursor_hash).await;
         }
+        .boxed()
+    }
+}
+
+impl BlockAnalyzer for ChainTransactionCrawlerTopic {
+    fn analyze(
+        &self,
+        network: &str,
+        block: &BlockView,
+        _parent: &HeaderView,
+        _spent: &HashMap<packed::OutPoint, u64>,
+    ) -> Vec<entry::Entry> {
+        transaction_entries(network, block)
+    }
+}
 
-        self.query_sender.send(raw_queries.join(";")).unwrap();
+fn transaction_entries(network: &str, block: &BlockView) -> Vec<entry::Entry> {
+    let mut entries: Vec<entry::Entry> = Vec::with_capacity(block.transactions().len());
+    let time = chrono::NaiveDateTime::from_timestamp(
+        (block.timestamp() / 1000) as i64,
+        (block.timestamp() % 1000 * 1000) as u32,
+    );
+    let catalogue = script_catalogue(network);
+    for (tx_index, tx) in block.transactions().iter().enumerate() {
+        let size = tx.data().serialized_size_in_block();
+        let n_inputs = tx.inputs().len();
+        let n_outputs = tx.outputs().len();
+        let n_header_deps = tx.header_deps().len();
+        let n_cell_deps = tx.cell_deps().len();
+        let total_data_size = tx.outputs_data().total_size();
+        let entry = entry::BlockTransaction {
+            time,
+            network: network.to_string(),
+            number: block.number() as i64,
+            tx_index: tx_index as i32,
+            size: size as i64,
+            n_inputs: n_inputs as i32,
+            n_outputs: n_outputs as i32,
+            n_cell_deps: n_cell_deps as i32,
+            n_header_deps: n_header_deps as i32,
+            total_data_size: total_data_size as i64,
+            proposal_id: format!("{:#x}", tx.proposal_short_id()),
+            hash: format!("{:#x}", tx.hash()),
+            script_kinds: catalogue.transaction_kinds(tx),
+        };
+        entries.push(entry.into());
     }
+    entries
 }
@@ -1,11 +1,15 @@
+use crate::config::CompactBlockCrawlerConfig;
+use crate::topic::{Connection, Topic, TopicContext};
+use crate::util::network;
 use crate::util::{bootnodes::bootnodes, ipinfo::lookup_ipinfo, multiaddr::addr_to_ip};
 use ckb_testkit::{
     ckb_types::{packed, prelude::*},
     compress,
     connector::message::{build_discovery_get_nodes, build_identify_message},
     connector::SharedState,
-    decompress, Node, SupportProtocols,
+    decompress, ConnectorBuilder, Node, SupportProtocols,
 };
+use futures::future::{BoxFuture, FutureExt};
 use lru::LruCache;
 use p2p::{
     builder::MetaBuilder as P2PMetaBuilder,
@@ -23,23 +27,22 @@ use p2p::{
     traits::ServiceProtocol as P2PServiceProtocol,
 };
 use rand::{thread_rng, Rng};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
 use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder};
 
 type Ip = String;
 
-const DIAL_ONLINE_ADDRESSES_INTERVAL: Duration = Duration::from_secs(1);
 const DIAL_ONLINE_ADDRESSES_TOKEN: u64 = 1;
 
 /// NOTE: CKB full node eviction mechanism only faces to outbound peers. We don't need to care
 /// about the mechanism evict us.
 pub struct CompactBlockCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    entry_sender: crossbeam::channel::Sender<Vec<crate::entry::Entry>>,
     shared: Arc<RwLock<SharedState>>,
+    config: CompactBlockCrawlerConfig,
 
     // RPC local_node_info.version
     client_version: String,
@@ -59,8 +62,9 @@ impl Clone for CompactBlockCrawler {
     fn clone(&self) -> Self {
         Self {
             node: self.node.clone(),
-            query_sender: self.query_sender.clone(),
+            entry_sender: self.entry_sender.clone(),
             shared: Arc::clone(&self.shared),
+            config: self.config.clone(),
             observed_addresses: Arc::clone(&self.observed_addresses),
             client_version: self.client_version.clone(),
             compact_blocks: None,
@@ -72,16 +76,19 @@ impl Clone for CompactBlockCrawler {
 impl CompactBlockCrawler {
     pub fn new(
         node: Node,
-        query_sender: crossbeam::channel::Sender<String>,
+        entry_sender: crossbeam::channel::Sender<Vec<crate::entry::Entry>>,
         shared: Arc<RwLock<SharedState>>,
+        config: CompactBlockCrawlerConfig,
+        configured_bootnodes: &HashMap<String, Vec<String>>,
     ) -> Self {
         #[allow(clippy::mutable_key_type)]
-        let bootnodes = bootnodes(&node);
+        let bootnodes = bootnodes(&node, configured_bootnodes);
         let client_version = node.rpc_client().local_node_info().version;
         Self {
             node,
-            query_sender,
+            entry_sender,
             shared,
+            config,
             observed_addresses: Arc::new(RwLock::new(bootnodes)),
             client_version,
             compact_blocks: Default::default(),
@@ -260,62 +267,79 @@ impl CompactBlockCrawler {
         }) = lookup_ipinfo(ip)
         {
             let entry = crate::entry::IpInfo {
-                network: self.node.consensus().id.clone(),
+                network: network(&self.node),
                 ip,
                 country,
                 city,
                 region,
                 company: company.map(|company| company.name).unwrap_or_default(),
             };
-            let raw_query = format!(
-                "INSERT INTO {}.ipinfo(ip, country, city, region, company) \
-                VALUES ('{}', '{}', '{}', '{}', '{}') ON CONFLICT DO NOTHING",
-                entry.network, entry.ip, entry.country, entry.city, entry.region, entry.company,
-            );
             self.known_ips.insert(entry.ip);
-            self.query_sender.send(raw_query).unwrap();
+            self.entry_sender.send(vec![entry.into()]).unwrap();
         }
     }
 
     fn insert_compact_block_first_seen(&mut self, ip: Ip, block: &packed::CompactBlock) {
-        let block_number = block.header().raw().number().unpack();
+        let block_number: u64 = block.header().raw().number().unpack();
         let block_hash = block.header().calc_header_hash();
         let compact_blocks = self.compact_blocks.as_mut().unwrap();
         if !compact_blocks.contains(&block_hash) {
             compact_blocks.put(block_hash, ip.clone());
 
             let entry = crate::entry::CompactBlockFirstSeen {
-                network: self.node.consensus().id.to_string(),
+                network: network(&self.node),
                 time: chrono::Utc::now().naive_utc(),
-                block_number,
+                block_number: block_number as i64,
                 ip,
             };
-            let raw_query = format!(
-                "INSERT INTO {}.compact_block_first_seen(time, block_number, ip) VALUES ('{}', {}, '{}')",
-                entry.network, entry.time, entry.block_number, entry.ip
-            );
-            self.query_sender.send(raw_query).unwrap();
+            self.entry_sender.send(vec![entry.into()]).unwrap();
         }
     }
 
     fn update_peer_last_compact_block(&self, ip: Ip, block: &packed::CompactBlock) {
-        let block_number = block.header().raw().number().unpack();
+        let block_number: u64 = block.header().raw().number().unpack();
         let block_hash = block.header().calc_header_hash();
         let entry = crate::entry::PeerLastCompactBlock {
-            network: self.node.consensus().id.clone(),
+            network: network(&self.node),
             ip,
-            block_number,
-            block_hash,
+            block_number: block_number as i64,
+            block_hash: format!("{:#x}", block_hash),
             time: chrono::Utc::now().naive_utc(),
         };
-        let raw_query = format!(
-            "INSERT INTO {}.peer_last_compact_block (ip, block_number, block_hash, time) \
-            VALUES ('{}', {}, '{:#x}', '{}') \
-            ON CONFLICT ( ip ) \
-            DO UPDATE SET (block_number, block_hash, time) = (EXCLUDED.block_number, EXCLUDED.block_hash, EXCLUDED.time)",
-            entry.network, entry.ip, entry.block_number, entry.block_hash, entry.time,
-        );
-        self.query_sender.send(raw_query).unwrap();
+        self.entry_sender.send(vec![entry.into()]).unwrap();
+    }
+}
+
+/// Topic "CompactBlockCrawler"
+pub struct CompactBlockCrawlerTopic;
+
+impl Topic for CompactBlockCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "CompactBlockCrawler"
+    }
+
+    fn connections(&self) -> &'static [Connection] {
+        &[Connection::Rpc, Connection::P2p]
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let shared = Arc::new(RwLock::new(SharedState::new()));
+            let crawler = CompactBlockCrawler::new(
+                context.node,
+                context.entry_sender,
+                Arc::clone(&shared),
+                context.config.compact_block_crawler.clone(),
+                &context.config.bootnodes,
+            );
+            // The connector stops once dropped, keep it alive as long as the topic runs
+            let _connector = ConnectorBuilder::new()
+                .protocol_metas(crawler.build_protocol_metas())
+                .listening_addresses(vec![])
+                .build(crawler, shared);
+            futures::future::pending::<()>().await;
+        }
+        .boxed()
     }
 }
 
@@ -325,7 +349,7 @@ impl P2PServiceProtocol for CompactBlockCrawler {
             context
                 .set_service_notify(
                     SupportProtocols::Sync.protocol_id(),
-                    DIAL_ONLINE_ADDRESSES_INTERVAL,
+                    self.config.dial_interval(),
                     DIAL_ONLINE_ADDRESSES_TOKEN,
                 )
                 .unwrap();
@@ -333,7 +357,7 @@ impl P2PServiceProtocol for CompactBlockCrawler {
         if context.proto_id == SupportProtocols::Relay.protocol_id()
             || context.proto_id == SupportProtocols::RelayV2.protocol_id()
         {
-            self.compact_blocks = Some(LruCache::new(2000));
+            self.compact_blocks = Some(LruCache::new(self.config.compact_block_cache_size));
         }
     }
 
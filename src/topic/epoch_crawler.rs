@@ -1,86 +1,180 @@
+use crate::block_source::BlockSource;
 use crate::ckb_types::core::EpochNumber;
 use crate::ckb_types::utilities::compact_to_difficulty;
+use crate::config::EpochCrawlerConfig;
 use crate::entry;
+use crate::metrics;
+use crate::topic::{with_cursor, ResumeCursor, Topic, TopicContext};
+use crate::util::network;
+use crate::verify::Store;
 use ckb_testkit::Node;
+use crossbeam::channel::SendError;
+use futures::future::{BoxFuture, FutureExt};
 use std::cmp::max;
-use std::time::Duration;
+use std::sync::Arc;
 
 pub struct EpochCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    blocks: Arc<BlockSource>,
+    store: Option<Store>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: EpochCrawlerConfig,
 }
 
 impl EpochCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        blocks: Arc<BlockSource>,
+        store: Option<Store>,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: EpochCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            blocks,
+            store,
+            entry_sender,
+            config,
+        }
     }
 
     pub async fn run(&self, last_epoch_number: EpochNumber) {
         let mut current_number = max(1, last_epoch_number + 1);
         let mut tip_epoch = self.node.rpc_client().get_current_epoch();
         loop {
+            metrics::TOPIC_TIP
+                .with_label_values(&["EpochCrawler"])
+                .set(tip_epoch.number.value() as i64);
             if current_number >= tip_epoch.number.value() {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                tokio::time::sleep(self.config.poll_interval()).await;
                 tip_epoch = self.node.rpc_client().get_current_epoch();
                 continue;
             }
 
-            self.process(&mut current_number).await;
+            match self.process(current_number).await {
+                Ok(true) => current_number += 1,
+                // The epoch has not ended on the node yet, or the requests failed
+                Ok(false) => tokio::time::sleep(self.config.poll_interval()).await,
+                Err(_) => {
+                    log::info!("EpochCrawler stopped, the entry channel is closed");
+                    return;
+                }
+            }
+            metrics::TOPIC_CURSOR
+                .with_label_values(&["EpochCrawler"])
+                .set(current_number as i64 - 1);
         }
     }
 
-    async fn process(&self, current_number: &mut EpochNumber) {
-        if let Some(epoch) = self.node.rpc_client().get_epoch_by_number(*current_number) {
-            if let Some(start_header) = self
-                .node
-                .rpc_client()
-                .get_header_by_number(epoch.start_number.value())
-            {
-                if let Some(end_header) = self
-                    .node
-                    .rpc_client()
-                    .get_header_by_number(epoch.start_number.value() + epoch.length.value() - 1)
-                {
-                    let n_uncles = (start_header.inner.number.value()
-                        ..=end_header.inner.number.value())
-                        .map(|number| self.node.get_block_by_number(number).uncles().data().len())
-                        .sum::<usize>();
-                    let difficulty = compact_to_difficulty(epoch.compact_target.value());
-                    let start_time = chrono::NaiveDateTime::from_timestamp(
-                        (start_header.inner.timestamp.value() / 1000) as i64,
-                        (start_header.inner.timestamp.value() % 1000 * 1000) as u32,
-                    );
-                    let end_time = chrono::NaiveDateTime::from_timestamp(
-                        (end_header.inner.timestamp.value() / 1000) as i64,
-                        (end_header.inner.timestamp.value() % 1000 * 1000) as u32,
-                    );
-                    let entry = entry::Epoch {
-                        network: self.node.consensus().id.clone(),
-                        number: epoch.number.value(),
-                        length: epoch.length.value(),
-                        start_number: epoch.start_number.value(),
-                        start_time,
-                        end_time,
-                        n_uncles: n_uncles as i32,
-                        difficulty: difficulty.to_string(),
-                    };
-                    let raw_query = format!(
-                        "INSERT INTO {}.epoch (start_time, end_time, number, length, start_number, n_uncles, difficulty)\
-                             VALUES ('{}', '{}', {}, {}, {}, {}, '{}')",
-                        entry.network,
-                        entry.start_time,
-                        entry.end_time,
-                        entry.number,
-                        entry.length,
-                        entry.start_number,
-                        entry.n_uncles,
-                        entry.difficulty,
-                    );
-                    self.query_sender.send(raw_query).unwrap();
+    // Return whether the epoch `number` was sent
+    async fn process(&self, number: EpochNumber) -> Result<bool, SendError<Vec<entry::Entry>>> {
+        let entry = match epoch_entry(&self.node, &self.blocks, self.store.as_ref(), number).await {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let entries = with_cursor(
+            &network(&self.node),
+            "EpochCrawler",
+            number,
+            vec![entry.into()],
+        );
+        self.entry_sender.send(entries)?;
+        Ok(true)
+    }
+}
+
+/// Topic "EpochCrawler"
+pub struct EpochCrawlerTopic;
 
-                    *current_number += 1;
-                }
-            }
+impl Topic for EpochCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "EpochCrawler"
+    }
+
+    fn resume_cursor(&self) -> Option<ResumeCursor> {
+        Some(ResumeCursor {
+            table: "epoch",
+            column: "number",
+        })
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = EpochCrawler::new(
+                context.node,
+                context.blocks,
+                context.store,
+                context.entry_sender,
+                context.config.epoch_crawler.clone(),
+            );
+            handler.run(context.cursor).await;
         }
+        .boxed()
     }
 }
+
+/// Return the entry of the epoch `number`, `None` if the epoch has not ended.
+///
+/// The uncles are summed from the blocks stored in `store` if it has all the blocks of the epoch,
+/// otherwise the blocks are fetched.
+pub(crate) async fn epoch_entry(
+    node: &Node,
+    blocks: &BlockSource,
+    store: Option<&Store>,
+    number: EpochNumber,
+) -> Option<entry::Epoch> {
+    let epoch = node.rpc_client().get_epoch_by_number(number)?;
+    let start_header = node
+        .rpc_client()
+        .get_header_by_number(epoch.start_number.value())?;
+    let end_header = node
+        .rpc_client()
+        .get_header_by_number(epoch.start_number.value() + epoch.length.value() - 1)?;
+    let (start_number, end_number) = (
+        start_header.inner.number.value(),
+        end_header.inner.number.value(),
+    );
+    let stored_uncles = match store {
+        Some(store) => store
+            .sum_uncles(&network(node), start_number, end_number)
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!(
+                    "query the uncles of epoch {} from the stored blocks, error: {}",
+                    number,
+                    err
+                );
+                None
+            }),
+        None => None,
+    };
+    let n_uncles = match stored_uncles {
+        Some(n_uncles) => n_uncles as usize,
+        None => {
+            let mut n_uncles = 0;
+            for number in start_number..=end_number {
+                n_uncles += blocks.get_block(number).await.uncles().data().len();
+            }
+            n_uncles
+        }
+    };
+    let difficulty = compact_to_difficulty(epoch.compact_target.value());
+    let start_time = chrono::NaiveDateTime::from_timestamp(
+        (start_header.inner.timestamp.value() / 1000) as i64,
+        (start_header.inner.timestamp.value() % 1000 * 1000) as u32,
+    );
+    let end_time = chrono::NaiveDateTime::from_timestamp(
+        (end_header.inner.timestamp.value() / 1000) as i64,
+        (end_header.inner.timestamp.value() % 1000 * 1000) as u32,
+    );
+    Some(entry::Epoch {
+        network: network(node),
+        number: epoch.number.value() as i64,
+        length: epoch.length.value() as i64,
+        start_number: epoch.start_number.value() as i64,
+        start_time,
+        end_time,
+        n_uncles: n_uncles as i32,
+        difficulty: difficulty.to_string(),
+    })
+}
@@ -1,3 +1,16 @@
+use crate::block_source::BlockSource;
+use crate::ckb_types::{
+    core::{BlockView, HeaderView},
+    packed,
+};
+use crate::config::Config;
+use crate::entry::{self, Entry};
+use crate::verify::Store;
+use ckb_testkit::Node;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+
 mod cell_crawler;
 mod chain_crawler;
 mod chain_transaction_crawler;
@@ -5,19 +18,197 @@ mod compact_block_crawler;
 mod epoch_crawler;
 mod network_crawler;
 mod pool_crawler;
+mod proposal_tracker;
+mod reorg_window;
 mod retention_transaction_crawler;
 mod subscribe_new_transaction;
 mod subscribe_proposed_transaction;
 mod subscribe_rejected_transaction;
 
-pub(crate) use cell_crawler::CellCrawler;
-pub(crate) use chain_crawler::ChainCrawler;
-pub(crate) use chain_transaction_crawler::ChainTransactionCrawler;
-pub(crate) use compact_block_crawler::CompactBlockCrawler;
-pub(crate) use epoch_crawler::EpochCrawler;
-pub(crate) use network_crawler::NetworkCrawler;
-pub(crate) use pool_crawler::PoolCrawler;
-pub(crate) use retention_transaction_crawler::RetentionTransactionCrawler;
-pub(crate) use subscribe_new_transaction::SubscribeNewTransaction;
-pub(crate) use subscribe_proposed_transaction::SubscribeProposedTransaction;
-pub(crate) use subscribe_rejected_transaction::SubscribeRejectedTransaction;
+pub(crate) use cell_crawler::spent_capacities;
+pub use cell_crawler::CellCrawlerTopic;
+pub use chain_crawler::ChainCrawlerTopic;
+pub(crate) use chain_crawler::Settlements;
+pub use chain_transaction_crawler::ChainTransactionCrawlerTopic;
+pub use compact_block_crawler::CompactBlockCrawlerTopic;
+pub(crate) use epoch_crawler::epoch_entry;
+pub use epoch_crawler::EpochCrawlerTopic;
+pub use network_crawler::NetworkCrawlerTopic;
+pub use pool_crawler::PoolCrawlerTopic;
+pub use retention_transaction_crawler::RetentionTransactionCrawlerTopic;
+pub use subscribe_new_transaction::SubscribeNewTransactionTopic;
+pub use subscribe_proposed_transaction::SubscribeProposedTransactionTopic;
+pub use subscribe_rejected_transaction::SubscribeRejectedTransactionTopic;
+
+pub type EntrySender = crossbeam::channel::Sender<Vec<Entry>>;
+
+/// The connections to CKB node a topic requires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connection {
+    /// JSON-RPC, `--ckb-rpc-url`
+    Rpc,
+    /// TCP subscription, `--ckb-subscription-addr`
+    Subscription,
+    /// P2P network, dialed via bootnodes
+    P2p,
+}
+
+/// A topic resumes right after the cursor it committed into `analyzer_cursor`, see
+/// [`with_cursor`]. Without a committed cursor, e.g. the data was written by an older
+/// CKBAnalyzer, it resumes after the greatest `column` of `table`.
+#[derive(Clone, Copy, Debug)]
+pub struct ResumeCursor {
+    pub table: &'static str,
+    pub column: &'static str,
+}
+
+/// Append the cursor of `topic` to the entries of a unit of work, so that the sink commits the
+/// cursor atomically with the entries.
+pub fn with_cursor(network: &str, topic: &str, cursor: u64, mut entries: Vec<Entry>) -> Vec<Entry> {
+    let cursor = entry::AnalyzerCursor {
+        network: network.to_string(),
+        topic: topic.to_string(),
+        cursor: cursor as i64,
+        block_hash: None,
+        updated_at: chrono::Utc::now().naive_utc(),
+        n_entries: entries.len(),
+    };
+    entries.push(cursor.into());
+    entries
+}
+
+/// [`with_cursor`] of the block topics, which also commits the hash of the cursor block, so that
+/// the reorgs happened while stopped are detected, see [`reorg_window::ReorgWindow`]
+pub fn with_block_cursor(
+    network: &str,
+    topic: &str,
+    block: &BlockView,
+    entries: Vec<Entry>,
+) -> Vec<Entry> {
+    let mut entries = with_cursor(network, topic, block.number(), entries);
+    if let Some(Entry::AnalyzerCursor(cursor)) = entries.last_mut() {
+        cursor.block_hash = Some(format!("{:#x}", block.hash()));
+    }
+    entries
+}
+
+/// The context passed to a topic when it starts
+#[derive(Clone)]
+pub struct TopicContext {
+    pub node: Node,
+    /// The blocks shared by the block topics
+    pub blocks: Arc<BlockSource>,
+    pub entry_sender: EntrySender,
+    pub subscription_addr: Option<String>,
+    /// The last number the topic committed, 0 if nothing committed
+    pub cursor: u64,
+    /// The hash of the cursor block, if the block topic committed it, see [`with_block_cursor`]
+    pub cursor_hash: Option<packed::Byte32>,
+    /// The database the topic writes into, `None` for the file sinks
+    pub store: Option<Store>,
+    pub config: Arc<Config>,
+}
+
+/// Topic is a kind of data CKBAnalyzer collects.
+pub trait Topic: Send + Sync {
+    /// The unique name used in `--topics`
+    fn name(&self) -> &'static str;
+
+    fn connections(&self) -> &'static [Connection] {
+        &[Connection::Rpc]
+    }
+
+    /// Return `None` if the topic does not resume from stored data
+    fn resume_cursor(&self) -> Option<ResumeCursor> {
+        None
+    }
+
+    /// Return `None` if the topic cannot be backfilled, see [`BlockAnalyzer`]
+    fn block_analyzer(&self) -> Option<&dyn BlockAnalyzer> {
+        None
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()>;
+}
+
+/// BlockAnalyzer derives entries from a block alone, so that the topic can be backfilled over a
+/// block range, see [`crate::backfill`].
+///
+/// The entries should be written idempotently, as a backfilled range may be analyzed again.
+pub trait BlockAnalyzer: Send + Sync {
+    /// `spent` has the capacities of the cells spent by `block`, resolved by the caller via
+    /// [`spent_capacities`] if the analyzer [`needs_spent_capacities`](Self::needs_spent_capacities)
+    fn analyze(
+        &self,
+        network: &str,
+        block: &BlockView,
+        parent: &HeaderView,
+        spent: &HashMap<packed::OutPoint, u64>,
+    ) -> Vec<Entry>;
+
+    fn needs_spent_capacities(&self) -> bool {
+        false
+    }
+}
+
+/// The registry of topics
+#[derive(Clone, Default)]
+pub struct TopicRegistry {
+    topics: Vec<Arc<dyn Topic>>,
+}
+
+impl TopicRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry with all the built-in topics
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(ChainCrawlerTopic)
+            .register(EpochCrawlerTopic)
+            .register(PoolCrawlerTopic)
+            .register(ChainTransactionCrawlerTopic)
+            .register(SubscribeNewTransactionTopic)
+            .register(SubscribeProposedTransactionTopic)
+            .register(SubscribeRejectedTransactionTopic)
+            .register(RetentionTransactionCrawlerTopic)
+            .register(CellCrawlerTopic)
+            .register(NetworkCrawlerTopic)
+            .register(CompactBlockCrawlerTopic);
+        registry
+    }
+
+    /// Register a topic. Panic if the name is already registered.
+    pub fn register<T: Topic + 'static>(&mut self, topic: T) -> &mut Self {
+        assert!(
+            self.get(topic.name()).is_none(),
+            "duplicated topic \"{}\"",
+            topic.name()
+        );
+        self.topics.push(Arc::new(topic));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Topic>> {
+        self.topics
+            .iter()
+            .find(|topic| topic.name() == name)
+            .cloned()
+    }
+
+    /// Return the names of registered topics, in registration order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.topics.iter().map(|topic| topic.name()).collect()
+    }
+
+    /// Return the names of registered topics which can be backfilled
+    pub fn backfill_names(&self) -> Vec<&'static str> {
+        self.topics
+            .iter()
+            .filter(|topic| topic.block_analyzer().is_some())
+            .map(|topic| topic.name())
+            .collect()
+    }
+}
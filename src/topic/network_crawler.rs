@@ -1,11 +1,16 @@
+use crate::config::NetworkCrawlerConfig;
+use crate::metrics;
+use crate::topic::{Connection, Topic, TopicContext};
+use crate::util::network;
 use crate::util::{bootnodes::bootnodes, ipinfo::lookup_ipinfo, multiaddr::addr_to_ip};
 use ckb_testkit::connector::message::build_discovery_get_nodes;
 use ckb_testkit::{
     ckb_types::{packed, prelude::*},
     compress,
     connector::SharedState,
-    decompress, Node, SupportProtocols,
+    decompress, ConnectorBuilder, Node, SupportProtocols,
 };
+use futures::future::{BoxFuture, FutureExt};
 use p2p::{
     builder::MetaBuilder as P2PMetaBuilder,
     bytes::{Bytes, BytesMut},
@@ -30,17 +35,12 @@ use std::time::{Duration, Instant};
 use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder};
 
 // TODO Adjust the parameters
-const DIAL_ONLINE_ADDRESSES_INTERVAL: Duration = Duration::from_secs(1);
 const PRUNE_OFFLINE_ADDRESSES_INTERVAL: Duration = Duration::from_secs(30 * 60);
-const DISCONNECT_TIMEOUT_SESSION_INTERVAL: Duration = Duration::from_secs(10);
-const POSTGRES_ONLINE_ADDRESS_INTERVAL: Duration = Duration::from_secs(60);
 const DIAL_ONLINE_ADDRESSES_TOKEN: u64 = 1;
 const PRUNE_OFFLINE_ADDRESSES_TOKEN: u64 = 2;
 const DISCONNECT_TIMEOUT_SESSION_TOKEN: u64 = 3;
 const POSTGRES_ONLINE_ADDRESSES_TOKEN: u64 = 4;
 
-const ADDRESS_TIMEOUT: Duration = Duration::from_secs(30);
-
 /// NetworkCrawler crawl the network reachability info.
 ///
 /// This service opens 2 protocols, Identify and Discovery:
@@ -54,8 +54,9 @@ const ADDRESS_TIMEOUT: Duration = Duration::from_secs(30);
 /// * When receiving inv `Nodes`, record into `self.reachable`
 pub struct NetworkCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    entry_sender: crossbeam::channel::Sender<Vec<crate::entry::Entry>>,
     shared: Arc<RwLock<SharedState>>,
+    config: NetworkCrawlerConfig,
 
     // all observed addresses
     observed_addresses: Arc<RwLock<HashSet<Multiaddr>>>,
@@ -81,8 +82,9 @@ impl Clone for NetworkCrawler {
     fn clone(&self) -> Self {
         Self {
             node: self.node.clone(),
-            query_sender: self.query_sender.clone(),
+            entry_sender: self.entry_sender.clone(),
             shared: Arc::clone(&self.shared),
+            config: self.config.clone(),
             observed_addresses: Arc::clone(&self.observed_addresses),
             online: Arc::clone(&self.online),
             known_ips: self.known_ips.clone(),
@@ -94,15 +96,18 @@ impl NetworkCrawler {
     /// Create a NetworkCrawler
     pub fn new(
         node: Node,
-        query_sender: crossbeam::channel::Sender<String>,
+        entry_sender: crossbeam::channel::Sender<Vec<crate::entry::Entry>>,
         shared: Arc<RwLock<SharedState>>,
+        config: NetworkCrawlerConfig,
+        configured_bootnodes: &HashMap<String, Vec<String>>,
     ) -> Self {
         #[allow(clippy::mutable_key_type)]
-        let bootnodes = bootnodes(&node);
+        let bootnodes = bootnodes(&node, configured_bootnodes);
         Self {
             node,
-            query_sender,
+            entry_sender,
             shared,
+            config,
             observed_addresses: Arc::new(RwLock::new(bootnodes.clone())),
             online: Arc::new(RwLock::new(
                 bootnodes
@@ -286,13 +291,46 @@ impl NetworkCrawler {
     }
 }
 
+/// Topic "NetworkCrawler"
+pub struct NetworkCrawlerTopic;
+
+impl Topic for NetworkCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "NetworkCrawler"
+    }
+
+    fn connections(&self) -> &'static [Connection] {
+        &[Connection::Rpc, Connection::P2p]
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let shared = Arc::new(RwLock::new(SharedState::new()));
+            let crawler = NetworkCrawler::new(
+                context.node,
+                context.entry_sender,
+                Arc::clone(&shared),
+                context.config.network_crawler.clone(),
+                &context.config.bootnodes,
+            );
+            // The connector stops once dropped, keep it alive as long as the topic runs
+            let _connector = ConnectorBuilder::new()
+                .protocol_metas(crawler.build_protocol_metas())
+                .listening_addresses(vec![])
+                .build(crawler, shared);
+            futures::future::pending::<()>().await;
+        }
+        .boxed()
+    }
+}
+
 impl P2PServiceProtocol for NetworkCrawler {
     fn init(&mut self, context: &mut P2PProtocolContext) {
         if context.proto_id == SupportProtocols::Sync.protocol_id() {
             context
                 .set_service_notify(
                     SupportProtocols::Sync.protocol_id(),
-                    DIAL_ONLINE_ADDRESSES_INTERVAL,
+                    self.config.dial_interval(),
                     DIAL_ONLINE_ADDRESSES_TOKEN,
                 )
                 .unwrap();
@@ -306,14 +344,14 @@ impl P2PServiceProtocol for NetworkCrawler {
             context
                 .set_service_notify(
                     SupportProtocols::Sync.protocol_id(),
-                    DISCONNECT_TIMEOUT_SESSION_INTERVAL,
+                    self.config.disconnect_timeout_session_interval(),
                     DISCONNECT_TIMEOUT_SESSION_TOKEN,
                 )
                 .unwrap();
             context
                 .set_service_notify(
                     SupportProtocols::Sync.protocol_id(),
-                    POSTGRES_ONLINE_ADDRESS_INTERVAL,
+                    self.config.store_online_addresses_interval(),
                     POSTGRES_ONLINE_ADDRESSES_TOKEN,
                 )
                 .unwrap();
@@ -359,11 +397,12 @@ impl P2PServiceProtocol for NetworkCrawler {
                         })
                         .unwrap_or_default()
                 };
+                metrics::NETWORK_CRAWLER_SESSIONS.set(sessions.len() as i64);
                 if let Ok(online) = self.online.read() {
                     for session in sessions {
                         if let Some(peer_info) = online.get(&addr_to_ip(&session.address)) {
                             if let Some(last_seen_time) = peer_info.last_seen_time {
-                                if last_seen_time.elapsed() > Duration::from_secs(10) {
+                                if last_seen_time.elapsed() > self.config.session_timeout() {
                                     let _ = context.disconnect(session.id);
                                 }
                             }
@@ -377,7 +416,7 @@ impl P2PServiceProtocol for NetworkCrawler {
                 if let Ok(online) = self.online.read() {
                     for (ip, peer_info) in online.iter() {
                         if let Some(last_seen_time) = peer_info.last_seen_time {
-                            if last_seen_time.elapsed() <= ADDRESS_TIMEOUT {
+                            if last_seen_time.elapsed() <= self.config.address_timeout() {
                                 // It's a online address
                                 let n_reachable = {
                                     peer_info
@@ -387,7 +426,7 @@ impl P2PServiceProtocol for NetworkCrawler {
                                         .count()
                                 };
                                 let entry = crate::entry::Peer {
-                                    network: self.node.consensus().id.clone(),
+                                    network: network(&self.node),
                                     time: now,
                                     version: peer_info.client_version.clone(),
                                     ip: ip.clone(),
@@ -399,14 +438,9 @@ impl P2PServiceProtocol for NetworkCrawler {
                     }
                 }
 
-                for entry in entries.iter() {
-                    let raw_query = format!(
-                        "INSERT INTO {}.peer(time, version, ip, n_reachable) \
-                             VALUES ('{}', '{}', '{}', {})",
-                        entry.network, entry.time, entry.version, entry.ip, entry.n_reachable,
-                    );
-                    self.query_sender.send(raw_query).unwrap();
-                }
+                self.entry_sender
+                    .send(entries.iter().cloned().map(Into::into).collect())
+                    .unwrap();
 
                 for entry in entries {
                     if !self.known_ips.contains(&entry.ip) {
@@ -427,18 +461,8 @@ impl P2PServiceProtocol for NetworkCrawler {
                                 region,
                                 company: company.map(|company| company.name).unwrap_or_default(),
                             };
-                            let raw_query = format!(
-                                "INSERT INTO {}.ipinfo(ip, country, city, region, company) \
-                                VALUES ('{}', '{}', '{}', '{}', '{}') ON CONFLICT DO NOTHING",
-                                entry.network,
-                                entry.ip,
-                                entry.country,
-                                entry.city,
-                                entry.region,
-                                entry.company,
-                            );
                             self.known_ips.insert(entry.ip);
-                            self.query_sender.send(raw_query).unwrap();
+                            self.entry_sender.send(vec![entry.into()]).unwrap();
                         }
                     }
                 }
@@ -1,22 +1,34 @@
+use crate::config::PoolCrawlerConfig;
 use crate::entry;
+use crate::topic::{Topic, TopicContext};
+use crate::util::network;
 use ckb_testkit::Node;
-use std::time::Duration;
+use futures::future::{BoxFuture, FutureExt};
 
 pub struct PoolCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: PoolCrawlerConfig,
 }
 
 impl PoolCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: PoolCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            entry_sender,
+            config,
+        }
     }
 
     pub async fn run(&self) {
         loop {
             let tx_pool_info = self.node.rpc_client().tx_pool_info();
             let entry = entry::TxPoolInfo {
-                network: self.node.consensus().id.clone(),
+                network: network(&self.node),
                 time: chrono::Utc::now().naive_utc(),
                 total_tx_cycles: tx_pool_info.total_tx_cycles.value() as i64,
                 total_tx_size: tx_pool_info.total_tx_size.value() as i64,
@@ -24,14 +36,30 @@ impl PoolCrawler {
                 proposed: tx_pool_info.proposed.value() as i64,
                 orphan: tx_pool_info.orphan.value() as i64,
             };
-            let raw_query = format!(
-                "INSERT INTO {}.tx_pool_info(time, total_tx_cycles, total_tx_size, pending, proposed, orphan) \
-            VALUES ('{}', {}, {}, {}, {}, {})",
-                entry.network, entry.time, entry.total_tx_cycles, entry.total_tx_size, entry.pending, entry.proposed, entry.orphan
-            );
-            self.query_sender.send(raw_query).unwrap();
+            self.entry_sender.send(vec![entry.into()]).unwrap();
+
+            tokio::time::sleep(self.config.poll_interval()).await;
+        }
+    }
+}
+
+/// Topic "PoolCrawler"
+pub struct PoolCrawlerTopic;
 
-            tokio::time::sleep(Duration::from_secs(5)).await;
+impl Topic for PoolCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "PoolCrawler"
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = PoolCrawler::new(
+                context.node,
+                context.entry_sender,
+                context.config.pool_crawler.clone(),
+            );
+            handler.run().await;
         }
+        .boxed()
     }
 }
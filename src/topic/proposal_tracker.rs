@@ -0,0 +1,168 @@
+use crate::ckb_types::{
+    core::{BlockNumber, BlockView},
+    packed,
+};
+use crate::entry;
+use std::collections::{HashMap, VecDeque};
+
+/// ProposalTracker follows the proposals of the recent blocks until their proposal windows close,
+/// then tells whether and when each of them was committed.
+///
+/// A transaction proposed in block `P` can be committed within blocks `[P + closest, P +
+/// farthest]`, so the proposals of `P` are settled at block `P + farthest`.
+pub struct ProposalTracker {
+    closest: BlockNumber,
+    farthest: BlockNumber,
+    // The blocks whose windows are open, ordered by number
+    window: VecDeque<ProposingBlock>,
+}
+
+struct ProposingBlock {
+    number: BlockNumber,
+    time: chrono::NaiveDateTime,
+    // #{ proposal id => committed block number }
+    proposals: HashMap<packed::ProposalShortId, Option<BlockNumber>>,
+}
+
+impl ProposalTracker {
+    pub fn new(closest: BlockNumber, farthest: BlockNumber) -> Self {
+        Self {
+            closest,
+            farthest,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// The number of the first block to replay for tracking from `number`, so that the windows
+    /// still open at `number` are restored
+    pub fn replay_from(&self, number: BlockNumber) -> BlockNumber {
+        ::std::cmp::max(1, number.saturating_sub(self.farthest))
+    }
+
+    /// Forget the tracked blocks, e.g. the chain was reorganized
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+
+    /// Track `block`, the child of the last tracked block. Return the proposals whose windows
+    /// close at `block`.
+    pub fn track(&mut self, network: &str, block: &BlockView) -> Vec<entry::Proposal> {
+        let number = block.number();
+        for tx in block.transactions().iter().skip(1) {
+            let proposal_id = tx.proposal_short_id();
+            for proposing in self.window.iter_mut().filter(|proposing| {
+                proposing.number + self.closest <= number
+                    && number <= proposing.number + self.farthest
+            }) {
+                if let Some(committed @ None) = proposing.proposals.get_mut(&proposal_id) {
+                    *committed = Some(number);
+                }
+            }
+        }
+
+        self.window.push_back(ProposingBlock {
+            number,
+            time: chrono::NaiveDateTime::from_timestamp(
+                (block.timestamp() / 1000) as i64,
+                (block.timestamp() % 1000 * 1000) as u32,
+            ),
+            proposals: block
+                .union_proposal_ids()
+                .into_iter()
+                .map(|proposal_id| (proposal_id, None))
+                .collect(),
+        });
+
+        let mut settled = Vec::new();
+        while let Some(proposing) = self.window.front() {
+            if proposing.number + self.farthest > number {
+                break;
+            }
+            let proposing = self.window.pop_front().expect("checked above");
+            for (proposal_id, committed) in proposing.proposals {
+                settled.push(entry::Proposal {
+                    network: network.to_string(),
+                    time: proposing.time,
+                    number: proposing.number as i64,
+                    proposal_id: format!("{:#x}", proposal_id),
+                    committed_number: committed.map(|committed| committed as i64),
+                    commit_distance: committed
+                        .map(|committed| (committed - proposing.number) as i64),
+                    settled_number: number as i64,
+                });
+            }
+        }
+        settled
+    }
+}
+
+#[test]
+fn test_proposal_tracker() {
+    use crate::ckb_types::{
+        bytes::Bytes,
+        core::{BlockBuilder, TransactionBuilder, TransactionView},
+        prelude::*,
+    };
+
+    let transaction = |seed: u8| {
+        TransactionBuilder::default()
+            .output_data(Bytes::from(vec![seed]).pack())
+            .build()
+    };
+    let block =
+        |number: BlockNumber, proposals: &[&TransactionView], commits: &[&TransactionView]| {
+            BlockBuilder::default()
+                .number(number.pack())
+                .timestamp((number * 1000).pack())
+                .transaction(transaction(0))
+                .transactions(commits.iter().map(|tx| (*tx).clone()).collect())
+                .proposals(proposals.iter().map(|tx| tx.proposal_short_id()).collect())
+                .build()
+        };
+    let settled = |proposals: Vec<entry::Proposal>| {
+        let mut settled = proposals
+            .into_iter()
+            .map(|proposal| (proposal.proposal_id, proposal.committed_number))
+            .collect::<Vec<_>>();
+        settled.sort();
+        settled
+    };
+    let id = |tx: &TransactionView| format!("{:#x}", tx.proposal_short_id());
+
+    let (a, b, c, d) = (
+        transaction(1),
+        transaction(2),
+        transaction(3),
+        transaction(4),
+    );
+    let mut tracker = ProposalTracker::new(2, 4);
+    assert_eq!(tracker.replay_from(3), 1);
+    assert_eq!(tracker.replay_from(10), 6);
+
+    // `a` is committed too early, `b` at the closest and `c` at the farthest distance, `d` one
+    // block after its window closes
+    assert!(tracker
+        .track("ckb", &block(1, &[&a, &b, &c], &[]))
+        .is_empty());
+    assert!(tracker.track("ckb", &block(2, &[&d], &[&a])).is_empty());
+    assert!(tracker.track("ckb", &block(3, &[], &[&b])).is_empty());
+    assert!(tracker.track("ckb", &block(4, &[], &[])).is_empty());
+    let proposals = tracker.track("ckb", &block(5, &[], &[&c]));
+    assert!(proposals
+        .iter()
+        .all(|proposal| proposal.number == 1 && proposal.settled_number == 5));
+    let mut expected = vec![(id(&a), None), (id(&b), Some(3)), (id(&c), Some(5))];
+    expected.sort();
+    assert_eq!(settled(proposals), expected);
+
+    let proposals = tracker.track("ckb", &block(6, &[], &[]));
+    assert_eq!(proposals[0].commit_distance, None);
+    assert_eq!(settled(proposals), vec![(id(&d), None)]);
+    assert!(tracker.track("ckb", &block(7, &[], &[&d])).is_empty());
+
+    // A reset tracker forgets the open windows, and tracks again from any block
+    tracker.reset();
+    assert!(tracker.track("ckb", &block(8, &[&a], &[])).is_empty());
+    let proposals = tracker.track("ckb", &block(12, &[], &[]));
+    assert_eq!(settled(proposals), vec![(id(&a), None)]);
+}
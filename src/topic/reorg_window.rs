@@ -0,0 +1,264 @@
+use crate::block_source::BlockSource;
+use crate::ckb_types::{
+    core::{BlockNumber, BlockView},
+    packed,
+    prelude::*,
+    H256,
+};
+use crate::entry;
+use crate::util::network;
+use crate::verify::Store;
+use ckb_testkit::Node;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+/// ReorgWindow keeps the hashes of the blocks a block topic recently committed, to detect the
+/// reorgs and locate their fork points.
+///
+/// A reorg is detected when the parent hash of the next block differs from the committed hash of
+/// its parent. Each block topic keeps its own window and rolls back its own rows, see
+/// [`entry::Rollback`]. The window is seeded with the committed cursor block on startup, so that a
+/// reorg happened while stopped is detected as well.
+///
+/// A fork point out of the window is looked up with the block hashes stored by ChainCrawler, down
+/// to `size` blocks below the old tip at most.
+pub struct ReorgWindow {
+    size: usize,
+    // `(number, hash)`, ordered by number
+    blocks: VecDeque<(BlockNumber, packed::Byte32)>,
+    store: Option<Store>,
+}
+
+/// A reorg of the committed blocks
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fork {
+    /// The highest block number which is still on the canonical chain
+    pub fork_number: BlockNumber,
+    pub old_tip_number: BlockNumber,
+    pub old_tip_hash: packed::Byte32,
+}
+
+impl ReorgWindow {
+    pub fn new(size: usize, store: Option<Store>) -> Self {
+        Self {
+            size,
+            blocks: VecDeque::with_capacity(size),
+            store,
+        }
+    }
+
+    /// Record the committed block, the child of the last recorded one
+    pub fn push(&mut self, number: BlockNumber, hash: packed::Byte32) {
+        self.blocks.push_back((number, hash));
+        if self.blocks.len() > self.size {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Return the fork if the block `number` with `parent_hash` does not extend the recorded
+    /// blocks. The blocks above the fork point are dropped, and `canonical_hash` looks up the
+    /// canonical hashes while walking back. Below the window, the walk goes on with the hashes of
+    /// `stored_hash`, and stops at `size` blocks below the old tip, which is rolled back to when
+    /// no stored block is canonical.
+    pub fn detect<F, G>(
+        &mut self,
+        number: BlockNumber,
+        parent_hash: &packed::Byte32,
+        mut canonical_hash: F,
+        mut stored_hash: G,
+    ) -> Option<Fork>
+    where
+        F: FnMut(BlockNumber) -> Option<packed::Byte32>,
+        G: FnMut(BlockNumber) -> Option<packed::Byte32>,
+    {
+        if !self.is_reorg(number, parent_hash) {
+            return None;
+        }
+        let (old_tip_number, old_tip_hash) = self.blocks.back().cloned()?;
+        let fork = |fork_number| {
+            Some(Fork {
+                fork_number,
+                old_tip_number,
+                old_tip_hash: old_tip_hash.clone(),
+            })
+        };
+
+        let mut lowest = old_tip_number;
+        while let Some((number, hash)) = self.blocks.back() {
+            if canonical_hash(*number).as_ref() == Some(hash) {
+                return fork(*number);
+            }
+            lowest = *number;
+            self.blocks.pop_back();
+        }
+
+        let floor = old_tip_number.saturating_sub(self.size as BlockNumber);
+        while lowest > floor {
+            lowest -= 1;
+            match stored_hash(lowest) {
+                Some(hash) if canonical_hash(lowest).as_ref() == Some(&hash) => {
+                    return fork(lowest)
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        log::error!(
+            "the fork point of the reorg at #{} is not found in the window or the stored blocks \
+            within {} blocks, roll back to #{}",
+            number,
+            self.size,
+            floor
+        );
+        fork(floor)
+    }
+
+    /// Detect whether `block` reorganizes the blocks committed by `topic`. On a reorg, the cached
+    /// blocks above the fork point are invalidated, and the caller rolls back its rows, see
+    /// [`Fork::rollback`].
+    pub async fn check(
+        &mut self,
+        topic: &str,
+        node: &Node,
+        blocks: &BlockSource,
+        block: &BlockView,
+    ) -> Option<Fork> {
+        let stored = if self.is_reorg(block.number(), &block.parent_hash()) {
+            self.stored_hashes(node).await
+        } else {
+            HashMap::new()
+        };
+        let fork = self.detect(
+            block.number(),
+            &block.parent_hash(),
+            |number| node.rpc_client().get_block_hash(number),
+            |number| stored.get(&number).cloned(),
+        )?;
+        log::warn!(
+            "{} detected a reorg, fork_number: {}, depth: {}, old_tip_hash: {:#x}, new_tip_hash: {:#x}",
+            topic,
+            fork.fork_number,
+            fork.depth(),
+            fork.old_tip_hash,
+            block.parent_hash(),
+        );
+        blocks.invalidate(fork.fork_number).await;
+        Some(fork)
+    }
+
+    fn is_reorg(&self, number: BlockNumber, parent_hash: &packed::Byte32) -> bool {
+        matches!(
+            self.blocks.back(),
+            Some((tip_number, hash)) if *tip_number + 1 == number && hash != parent_hash
+        )
+    }
+
+    // Return the stored hashes of the `size` blocks below the recorded tip
+    async fn stored_hashes(&self, node: &Node) -> HashMap<BlockNumber, packed::Byte32> {
+        let (store, tip_number) = match (self.store.as_ref(), self.blocks.back()) {
+            (Some(store), Some((tip_number, _))) => (store, *tip_number),
+            _ => return HashMap::new(),
+        };
+        let from = tip_number.saturating_sub(self.size as BlockNumber);
+        match store.block_hashes(&network(node), from, tip_number).await {
+            Ok(hashes) => hashes
+                .into_iter()
+                .filter_map(|(number, hash)| {
+                    let hash = H256::from_str(hash?.trim_start_matches("0x")).ok()?;
+                    Some((number, hash.pack()))
+                })
+                .collect(),
+            Err(err) => {
+                log::warn!("query the stored block hashes, error: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+}
+
+impl Fork {
+    pub fn depth(&self) -> BlockNumber {
+        self.old_tip_number - self.fork_number
+    }
+
+    /// Return the entry deleting the orphaned rows of `topic` and moving its cursor back
+    pub fn rollback(&self, network: &str, topic: &str) -> entry::Rollback {
+        entry::Rollback {
+            network: network.to_string(),
+            topic: topic.to_string(),
+            fork_number: self.fork_number as i64,
+        }
+    }
+}
+
+#[test]
+fn test_reorg_window() {
+    let hash = |fork: u8, number: BlockNumber| {
+        let mut bytes = [0u8; 32];
+        bytes[0] = fork;
+        bytes[1..9].copy_from_slice(&number.to_le_bytes());
+        packed::Byte32::from_slice(&bytes).unwrap()
+    };
+    // The chain forks after #5, the old chain is 0, the new one is 1
+    let canonical = (1..=10)
+        .map(|number| (number, hash(if number <= 5 { 0 } else { 1 }, number)))
+        .collect::<HashMap<_, _>>();
+
+    let mut window = ReorgWindow::new(4, None);
+    for number in 1..=7 {
+        assert_eq!(
+            window.detect(number, &hash(0, number - 1), |_| None, |_| None),
+            None
+        );
+        window.push(number, hash(0, number));
+    }
+    // #8 of the new chain does not extend #7 of the old chain
+    let fork = window
+        .detect(
+            8,
+            &hash(1, 7),
+            |number| canonical.get(&number).cloned(),
+            |_| None,
+        )
+        .unwrap();
+    assert_eq!(fork.fork_number, 5);
+    assert_eq!(fork.depth(), 2);
+    assert_eq!(fork.old_tip_hash, hash(0, 7));
+    let rollback = fork.rollback("ckb_dev", "CellCrawler");
+    assert_eq!(rollback.fork_number, 5);
+    assert_eq!(rollback.tables()[0], ("created_cell", "block_number"));
+
+    // Crawl the new chain from the fork point
+    assert_eq!(window.detect(6, &hash(0, 5), |_| None, |_| None), None);
+    window.push(6, hash(1, 6));
+    assert_eq!(window.detect(7, &hash(1, 6), |_| None, |_| None), None);
+
+    // Seeded with the cursor block only, the fork point of a reorg happened while stopped is
+    // looked up with the stored hashes of the old chain
+    let mut window = ReorgWindow::new(4, None);
+    window.push(7, hash(0, 7));
+    let fork = window
+        .detect(
+            8,
+            &hash(1, 7),
+            |number| canonical.get(&number).cloned(),
+            |number| Some(hash(0, number)),
+        )
+        .unwrap();
+    assert_eq!(fork.fork_number, 5);
+    assert_eq!(fork.depth(), 2);
+
+    // Without the stored hashes, roll back `size` blocks at most
+    let mut window = ReorgWindow::new(4, None);
+    window.push(7, hash(0, 7));
+    let fork = window
+        .detect(
+            8,
+            &hash(1, 7),
+            |number| canonical.get(&number).cloned(),
+            |_| None,
+        )
+        .unwrap();
+    assert_eq!(fork.fork_number, 3);
+    assert_eq!(window.detect(4, &hash(0, 3), |_| None, |_| None), None);
+}
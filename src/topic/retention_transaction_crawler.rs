@@ -1,18 +1,30 @@
 use crate::ckb_jsonrpc_types::{RawTxPool, TxPoolIds};
-use crate::ckb_types::{prelude::*, H256};
+use crate::ckb_types::H256;
+use crate::config::RetentionTransactionCrawlerConfig;
 use crate::entry;
+use crate::topic::{Topic, TopicContext};
+use crate::util::network;
 use ckb_testkit::Node;
+use futures::future::{BoxFuture, FutureExt};
 use std::collections::HashSet;
-use std::time::Duration;
 
 pub struct RetentionTransactionCrawler {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+    config: RetentionTransactionCrawlerConfig,
 }
 
 impl RetentionTransactionCrawler {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(
+        node: Node,
+        entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
+        config: RetentionTransactionCrawlerConfig,
+    ) -> Self {
+        Self {
+            node,
+            entry_sender,
+            config,
+        }
     }
 
     pub async fn run(&self) {
@@ -29,15 +41,11 @@ impl RetentionTransactionCrawler {
                         let now = chrono::Utc::now().naive_utc();
                         for hash in retention {
                             let entry = entry::RetentionTransaction {
-                                network: self.node.consensus().id.clone(),
+                                network: network(&self.node),
                                 time: now,
-                                hash: hash.pack(),
+                                hash: format!("{:#x}", hash),
                             };
-                            let raw_query = format!(
-                                    "INSERT INTO {}.retention_transaction (time, hash) VALUES ('{}', '{:#x}')",
-                                    entry.network, entry.time, entry.hash
-                                );
-                            self.query_sender.send(raw_query).unwrap();
+                            self.entry_sender.send(vec![entry.into()]).unwrap();
                         }
 
                         last_observation_pool = in_pool;
@@ -48,7 +56,28 @@ impl RetentionTransactionCrawler {
                 },
             }
 
-            tokio::time::sleep(Duration::from_secs(60 * 10)).await;
+            tokio::time::sleep(self.config.poll_interval()).await;
+        }
+    }
+}
+
+/// Topic "RetentionTransactionCrawler"
+pub struct RetentionTransactionCrawlerTopic;
+
+impl Topic for RetentionTransactionCrawlerTopic {
+    fn name(&self) -> &'static str {
+        "RetentionTransactionCrawler"
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let handler = RetentionTransactionCrawler::new(
+                context.node,
+                context.entry_sender,
+                context.config.retention_transaction_crawler.clone(),
+            );
+            handler.run().await;
         }
+        .boxed()
     }
 }
@@ -1,17 +1,20 @@
-use crate::ckb_types::{packed, prelude::Pack};
+use crate::ckb_types::packed;
 use crate::entry;
+use crate::topic::{Connection, Topic, TopicContext};
+use crate::util::network;
 use ckb_testkit::Node;
+use futures::future::{BoxFuture, FutureExt};
 use futures::stream::StreamExt;
 use tokio::net::ToSocketAddrs;
 
 pub struct SubscribeNewTransaction {
     node: Node,
-    query_sender: crossbeam::channel::Sender<String>,
+    entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>,
 }
 
 impl SubscribeNewTransaction {
-    pub fn new(node: Node, query_sender: crossbeam::channel::Sender<String>) -> Self {
-        Self { node, query_sender }
+    pub fn new(node: Node, entry_sender: crossbeam::channel::Sender<Vec<entry::Entry>>) -> Self {
+        Self { node, entry_sender }
     }
 
     pub async fn run<A: ToSocketAddrs>(&mut self, subscription_addr: A) {
@@ -21,25 +24,43 @@ impl SubscribeNewTransaction {
         {
             let packed_tx: packed::Transaction = pool_tx_entry.transaction.inner.clone().into();
             let entry = entry::SubscribedNewTransaction {
-                network: self.node.consensus().id.clone(),
+                network: network(&self.node),
                 time: chrono::Utc::now().naive_utc(),
-                size: pool_tx_entry.size.value(),
-                cycles: pool_tx_entry.cycles.value(),
-                fee: pool_tx_entry.fee.value(),
-                n_inputs: pool_tx_entry.transaction.inner.inputs.len(),
-                n_outputs: pool_tx_entry.transaction.inner.outputs.len(),
-                n_cell_deps: pool_tx_entry.transaction.inner.cell_deps.len(),
-                n_header_deps: pool_tx_entry.transaction.inner.header_deps.len(),
-                hash: pool_tx_entry.transaction.hash.pack(),
-                proposal_id: packed_tx.proposal_short_id(),
+                size: pool_tx_entry.size.value() as i64,
+                cycles: pool_tx_entry.cycles.value() as i64,
+                fee: pool_tx_entry.fee.value() as i64,
+                n_inputs: pool_tx_entry.transaction.inner.inputs.len() as i32,
+                n_outputs: pool_tx_entry.transaction.inner.outputs.len() as i32,
+                n_cell_deps: pool_tx_entry.transaction.inner.cell_deps.len() as i32,
+                n_header_deps: pool_tx_entry.transaction.inner.header_deps.len() as i32,
+                hash: format!("{:#x}", pool_tx_entry.transaction.hash),
+                proposal_id: format!("{:#x}", packed_tx.proposal_short_id()),
             };
-            let raw_query = format!(
-                "INSERT INTO {}.subscribed_new_transaction (time, size, cycles, fee, n_inputs, n_outputs, n_cell_deps, n_header_deps, hash, proposal_id) \
-                VALUES ('{}', {}, {}, {}, {}, {}, {}, {}, '{:#x}', '{:#x}')",
-                entry.network, entry.time, entry.size, entry.cycles, entry.fee, entry.n_inputs, entry.n_outputs, entry.n_cell_deps, entry.n_header_deps,
-                entry.hash, entry.proposal_id,
-            );
-            self.query_sender.send(raw_query).unwrap();
+            self.entry_sender.send(vec![entry.into()]).unwrap();
         }
     }
 }
+
+/// Topic "SubscribeNewTransaction"
+pub struct SubscribeNewTransactionTopic;
+
+impl Topic for SubscribeNewTransactionTopic {
+    fn name(&self) -> &'static str {
+        "SubscribeNewTransaction"
+    }
+
+    fn connections(&self) -> &'static [Connection] {
+        &[Connection::Rpc, Connection::Subscription]
+    }
+
+    fn run(&self, context: TopicContext) -> BoxFuture<'static, ()> {
+        async move {
+            let subscription_addr = context
+                .subscription_addr
+                .expect("SubscribeNewTransaction requires subscription address");
+            let mut handler = SubscribeNewTransaction::new(context.node, context.entry_sender);
+            handler.run(subscription_addr).await;
+        }
+        .boxed()
+    }
+}
@@ -1,9 +1,24 @@
 use ckb_testkit::Node;
 use p2p::multiaddr::Multiaddr;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+/// Return the bootnodes of the node's chain. In order of precedence:
+///
+/// * the bootnodes configured for the chain, see config "bootnodes"
+/// * the listening addresses of the node
+/// * the built-in bootnodes of "ckb" and "ckb_testnet"
 #[allow(clippy::mutable_key_type)]
-pub fn bootnodes(node: &Node) -> HashSet<Multiaddr> {
+pub fn bootnodes(node: &Node, configured: &HashMap<String, Vec<String>>) -> HashSet<Multiaddr> {
+    let network = node.consensus().id.as_str();
+    if let Some(addresses) = configured.get(network) {
+        if !addresses.is_empty() {
+            return addresses
+                .iter()
+                .map(|address| address.parse().expect("checked by config"))
+                .collect();
+        }
+    }
+
     let local_node_info = node.rpc_client().local_node_info();
     if !local_node_info.addresses.is_empty() {
         return local_node_info
@@ -13,12 +28,15 @@ pub fn bootnodes(node: &Node) -> HashSet<Multiaddr> {
             .collect();
     }
 
-    let bootnode = match node.consensus().id.as_str() {
+    let bootnode = match network {
         "ckb" => "/ip4/47.110.15.57/tcp/8114/p2p/QmXS4Kbc9HEeykHUTJCm2tNmqghbvWyYpUp6BtE5b6VrAU",
         "ckb_testnet" => {
             "/ip4/47.111.169.36/tcp/8111/p2p/QmNQ4jky6uVqLDrPU7snqxARuNGWNLgSrTnssbRuy3ij2W"
         }
-        _ => unreachable!(),
+        _ => panic!(
+            "No bootnodes for chain \"{}\", the node has no listening addresses, specify them via config \"bootnodes.{}\"",
+            network, network
+        ),
     };
     let mut bootnodes = HashSet::new();
     bootnodes.insert(bootnode.parse().unwrap());
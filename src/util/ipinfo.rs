@@ -1,14 +1,15 @@
 use ipinfo::{IpDetails, IpError, IpInfo};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 
 lazy_static! {
+    static ref IPINFO_IO_TOKEN: RwLock<Option<String>> = RwLock::new(None);
     static ref IPINFO: Mutex<IpInfo> = {
-        let ipinfo_io_token = match ::std::env::var("IPINFO_IO_TOKEN") {
-            Ok(token) if !token.is_empty() => Some(token),
+        let ipinfo_io_token = match IPINFO_IO_TOKEN.read().map(|token| token.clone()) {
+            Ok(Some(token)) if !token.is_empty() => Some(token),
             _ => {
-                log::warn!("Miss environment variable \"IPINFO_IO_TOKEN\", use empty value");
+                log::warn!("Miss ipinfo.io token via neither config \"ipinfo.token\" nor environment variable \"IPINFO_IO_TOKEN\", use empty value");
                 None
             }
         };
@@ -23,6 +24,13 @@ lazy_static! {
     static ref IPINFO_CACHE: Mutex<HashMap<String, IpDetails>> = Mutex::new(Default::default());
 }
 
+/// Set the ipinfo.io token. It takes effect only if called before the first lookup.
+pub fn set_ipinfo_io_token(token: String) {
+    if let Ok(mut ipinfo_io_token) = IPINFO_IO_TOKEN.write() {
+        *ipinfo_io_token = Some(token);
+    }
+}
+
 pub fn lookup_ipinfo(ip: &str) -> Result<IpDetails, IpError> {
     if let Ok(cache) = IPINFO_CACHE.lock() {
         if let Some(ipdetails) = cache.get(&ip.to_string()) {
@@ -50,7 +58,8 @@ pub mod test {
     #[test]
     #[ignore] // This case needs env var "IPINFO_IO_TOKEN"
     fn test_lookup_ipinfo_cache() {
-        use crate::util::ipinfo::{lookup_ipinfo, IPINFO_CACHE};
+        use crate::util::ipinfo::{lookup_ipinfo, set_ipinfo_io_token, IPINFO_CACHE};
+        set_ipinfo_io_token(::std::env::var("IPINFO_IO_TOKEN").unwrap_or_default());
         {
             let cache = IPINFO_CACHE.lock().unwrap();
             assert!(cache.get("8.8.8.8").is_none());
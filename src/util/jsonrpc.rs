@@ -0,0 +1,245 @@
+use crate::ckb_jsonrpc_types;
+use crate::ckb_types::core::{BlockNumber, BlockView};
+use crate::ckb_types::H256;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// BatchRpcClient sends batched JSON-RPC requests to CKB node, which the synchronous client of
+/// `ckb_testkit::Node` does not support. Only plain HTTP urls are supported.
+pub struct BatchRpcClient {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Http(hyper::Error),
+    Timeout,
+    Json(serde_json::Error),
+    /// The error object responded by CKB node
+    Rpc(serde_json::Value),
+    /// The responses do not match the requests of the batch
+    Incomplete,
+    /// The block does not exist, e.g. it is beyond the tip
+    NotFound(BlockNumber),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "{}", err),
+            Error::Timeout => write!(f, "timed out after {:?}", REQUEST_TIMEOUT),
+            Error::Json(err) => write!(f, "invalid response, {}", err),
+            Error::Rpc(err) => write!(f, "{}", err),
+            Error::Incomplete => write!(f, "the batch responses are incomplete"),
+            Error::NotFound(number) => write!(f, "block #{} not found", number),
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    #[serde(default)]
+    result: serde_json::Value,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+impl BatchRpcClient {
+    pub fn new(url: &str) -> Self {
+        let uri = url
+            .parse::<Uri>()
+            .unwrap_or_else(|err| panic!("Invalid CKB RPC url \"{}\", error: {}", url, err));
+        Self {
+            client: Client::new(),
+            uri,
+        }
+    }
+
+    /// Get the blocks of `numbers` in one request, in the same order
+    pub async fn get_blocks_by_number(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<Vec<BlockView>, Error> {
+        let blocks: Vec<Option<ckb_jsonrpc_types::BlockView>> =
+            self.batch("get_block_by_number", numbers, &[]).await?;
+        numbers
+            .iter()
+            .zip(blocks)
+            .map(|(number, block)| block.map(Into::into).ok_or(Error::NotFound(*number)))
+            .collect()
+    }
+
+    /// Get the canonical block hashes of `numbers` in one request, in the same order. The hash is
+    /// `None` if the number is beyond the tip.
+    pub async fn get_block_hashes(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<Vec<Option<H256>>, Error> {
+        self.batch("get_block_hash", numbers, &[]).await
+    }
+
+    /// Get the total cycles of the transactions of the blocks `numbers` in one request, in the
+    /// same order. The cycles are `None` if the node did not record them, e.g. the blocks were
+    /// synced by an older node.
+    ///
+    /// It requires CKB v0.106 or later, which returns the cycles with `get_block_by_number`.
+    pub async fn get_block_cycles(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<Vec<Option<u64>>, Error> {
+        #[derive(Deserialize)]
+        struct BlockWithCycles {
+            cycles: Option<Vec<ckb_jsonrpc_types::Uint64>>,
+        }
+
+        // Verbosity 0 returns the block serialized, which is not parsed
+        let blocks: Vec<Option<BlockWithCycles>> = self
+            .batch(
+                "get_block_by_number",
+                numbers,
+                &[serde_json::json!("0x0"), serde_json::json!(true)],
+            )
+            .await?;
+        numbers
+            .iter()
+            .zip(blocks)
+            .map(|(number, block)| {
+                let block = block.ok_or(Error::NotFound(*number))?;
+                Ok(block
+                    .cycles
+                    .map(|cycles| cycles.iter().map(|cycles| cycles.value()).sum()))
+            })
+            .collect()
+    }
+
+    /// Get the economic states of the blocks `hashes` in one request, in the same order. The state
+    /// is `None` if the block is not on the canonical chain or its reward is not finalized yet.
+    pub async fn get_block_economic_states(
+        &self,
+        hashes: &[H256],
+    ) -> Result<Vec<Option<ckb_jsonrpc_types::BlockEconomicState>>, Error> {
+        let calls = hashes
+            .iter()
+            .map(|hash| vec![serde_json::json!(hash)])
+            .collect::<Vec<_>>();
+        self.batch_calls("get_block_economic_state", &calls).await
+    }
+
+    /// Get the transactions `hashes` in one request, in the same order. The transaction is `None`
+    /// if the node does not have it.
+    pub async fn get_transactions(
+        &self,
+        hashes: &[H256],
+    ) -> Result<Vec<Option<ckb_jsonrpc_types::TransactionView>>, Error> {
+        // Newer nodes respond a null transaction with the status "unknown", rather than null
+        #[derive(Deserialize)]
+        struct TransactionWithStatus {
+            transaction: Option<ckb_jsonrpc_types::TransactionView>,
+        }
+
+        let calls = hashes
+            .iter()
+            .map(|hash| vec![serde_json::json!(hash)])
+            .collect::<Vec<_>>();
+        let txs: Vec<Option<TransactionWithStatus>> =
+            self.batch_calls("get_transaction", &calls).await?;
+        Ok(txs
+            .into_iter()
+            .map(|tx| tx.and_then(|tx| tx.transaction))
+            .collect())
+    }
+
+    // Call `method` once for each number, followed by `params`, return the results in the same
+    // order
+    async fn batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        numbers: &[BlockNumber],
+        params: &[serde_json::Value],
+    ) -> Result<Vec<T>, Error> {
+        let calls = numbers
+            .iter()
+            .map(|number| {
+                let mut call_params = vec![serde_json::json!(format!("{:#x}", number))];
+                call_params.extend_from_slice(params);
+                call_params
+            })
+            .collect::<Vec<_>>();
+        self.batch_calls(method, &calls).await
+    }
+
+    // Call `method` once for each of `calls`, the parameters of a call, return the results in the
+    // same order
+    async fn batch_calls<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        calls: &[Vec<serde_json::Value>],
+    ) -> Result<Vec<T>, Error> {
+        let calls = calls
+            .iter()
+            .enumerate()
+            .map(|(id, call_params)| {
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": method,
+                    "params": call_params,
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut responses = self.call(serde_json::to_vec(&calls)?).await?;
+        // The responses of a batch may arrive in any order
+        responses.sort_by_key(|response| response.id);
+        if responses
+            .iter()
+            .enumerate()
+            .any(|(id, response)| response.id != id as u64)
+            || responses.len() != calls.len()
+        {
+            return Err(Error::Incomplete);
+        }
+
+        let mut results = Vec::with_capacity(calls.len());
+        for response in responses {
+            if let Some(err) = response.error {
+                return Err(Error::Rpc(err));
+            }
+            results.push(serde_json::from_value(response.result)?);
+        }
+        Ok(results)
+    }
+
+    async fn call(&self, body: Vec<u8>) -> Result<Vec<Response>, Error> {
+        let request = Request::post(self.uri.clone())
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .expect("build JSON-RPC request");
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let response = self.client.request(request).await?;
+            hyper::body::to_bytes(response.into_body()).await
+        })
+        .await
+        .map_err(|_elapsed| Error::Timeout)??;
+        Ok(serde_json::from_slice(&response)?)
+    }
+}
@@ -0,0 +1,139 @@
+use crate::ckb_types::packed;
+use crate::config::MinerConfig;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+/// The bundled miner registry, see [`MinerRegistry`]
+pub const BUNDLED_MINERS: &str = include_str!("../miners.toml");
+
+lazy_static! {
+    static ref CONFIGURED_MINERS: RwLock<Vec<MinerConfig>> = RwLock::new(Vec::new());
+    static ref MINER_REGISTRY: MinerRegistry = {
+        let configured = CONFIGURED_MINERS
+            .read()
+            .map(|miners| miners.clone())
+            .unwrap_or_default();
+        MinerRegistry::new(configured.into_iter().chain(bundled_miners()))
+    };
+}
+
+/// Set the miners of config "miners". It takes effect only if called before the first lookup.
+pub fn set_configured_miners(miners: Vec<MinerConfig>) {
+    if let Ok(mut configured) = CONFIGURED_MINERS.write() {
+        *configured = miners;
+    }
+}
+
+/// Return the pool name of the miner, `None` if it is unknown
+pub fn lookup_miner(miner_lock: &packed::Script, cellbase_message: &str) -> Option<String> {
+    MINER_REGISTRY
+        .lookup(miner_lock, cellbase_message)
+        .map(ToString::to_string)
+}
+
+fn bundled_miners() -> Vec<MinerConfig> {
+    #[derive(Deserialize)]
+    struct Miners {
+        miners: Vec<MinerConfig>,
+    }
+
+    toml::from_str::<Miners>(BUNDLED_MINERS)
+        .expect("bundled miners.toml")
+        .miners
+}
+
+/// MinerRegistry names the mining pools by the args of the miner lock, of any lock script, or by
+/// the cellbase message.
+///
+/// A miner is attributed to the first pool listing its lock args, otherwise to the first pool
+/// whose pattern matches the cellbase message.
+pub struct MinerRegistry {
+    // [ (name, lock args in lowercase, cellbase message pattern) ]
+    miners: Vec<(String, HashSet<String>, Option<Regex>)>,
+}
+
+impl MinerRegistry {
+    pub fn new<I: IntoIterator<Item = MinerConfig>>(miners: I) -> Self {
+        let miners = miners
+            .into_iter()
+            .map(|miner| {
+                let lock_args = miner
+                    .lock_args
+                    .iter()
+                    .map(|args| args.to_lowercase())
+                    .collect();
+                let pattern = if miner.cellbase_message.is_empty() {
+                    None
+                } else {
+                    Some(Regex::new(&miner.cellbase_message).unwrap_or_else(|err| {
+                        panic!(
+                            "Invalid cellbase_message \"{}\" of miner \"{}\", error: {}",
+                            miner.cellbase_message, miner.name, err
+                        )
+                    }))
+                };
+                (miner.name, lock_args, pattern)
+            })
+            .collect();
+        Self { miners }
+    }
+
+    pub fn lookup(&self, miner_lock: &packed::Script, cellbase_message: &str) -> Option<&str> {
+        let lock_args = format!("0x{:x}", miner_lock.args().raw_data());
+        self.miners
+            .iter()
+            .find(|(_, miner_lock_args, _)| miner_lock_args.contains(&lock_args))
+            .or_else(|| {
+                self.miners.iter().find(|(_, _, pattern)| {
+                    pattern
+                        .as_ref()
+                        .map(|pattern| pattern.is_match(cellbase_message))
+                        .unwrap_or(false)
+                })
+            })
+            .map(|(name, _, _)| name.as_str())
+    }
+}
+
+#[test]
+fn test_miner_registry() {
+    use crate::ckb_types::{bytes::Bytes, h256, prelude::*};
+
+    let lock = |args: &[u8]| {
+        packed::Script::new_builder()
+            .code_hash(
+                h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack(),
+            )
+            .hash_type(crate::ckb_types::core::ScriptHashType::Type.into())
+            .args(Bytes::from(args.to_vec()).pack())
+            .build()
+    };
+    let pool_lock = lock(&[0xab; 20]);
+    let other_lock = lock(&[0x12; 20]);
+    let configured = MinerConfig {
+        name: "MyPool".to_string(),
+        lock_args: vec![format!("0x{}", "AB".repeat(20))],
+        cellbase_message: "^mypool$".to_string(),
+    };
+    let registry = MinerRegistry::new(::std::iter::once(configured).chain(bundled_miners()));
+    assert_eq!(registry.lookup(&pool_lock, "F2Pool"), Some("MyPool"));
+    assert_eq!(registry.lookup(&other_lock, "mypool"), Some("MyPool"));
+    assert_eq!(
+        registry.lookup(&other_lock, "0.102.0 F2Pool"),
+        Some("F2Pool")
+    );
+    assert_eq!(registry.lookup(&other_lock, "0.102.0"), None);
+
+    // The packed args, with the molecule length header, are not the configured args
+    let packed_args = format!("{:#x}", pool_lock.args());
+    let configured = MinerConfig {
+        name: "MyPool".to_string(),
+        lock_args: vec![packed_args],
+        cellbase_message: String::new(),
+    };
+    let registry = MinerRegistry::new(::std::iter::once(configured));
+    assert_eq!(registry.lookup(&pool_lock, ""), None);
+}
@@ -1,4 +1,15 @@
 pub mod bootnodes;
 pub mod crossbeam_channel_to_tokio_channel;
 pub mod ipinfo;
+pub mod jsonrpc;
+pub mod miners;
 pub mod multiaddr;
+pub mod scripts;
+
+use crate::migration::schema_name;
+use ckb_testkit::Node;
+
+/// Return the network of the node, i.e. the schema name of its chain id, see [`schema_name`]
+pub fn network(node: &Node) -> String {
+    schema_name(&node.consensus().id)
+}
@@ -0,0 +1,281 @@
+use crate::ckb_types::{
+    core::{ScriptHashType, TransactionView},
+    packed,
+    prelude::*,
+};
+use crate::config::ScriptConfig;
+use crate::migration::schema_name;
+use lazy_static::lazy_static;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex, RwLock};
+
+pub const SIGHASH: &str = "sighash";
+pub const MULTISIG: &str = "multisig";
+pub const NERVOS_DAO: &str = "nervos_dao";
+pub const SUDT: &str = "sudt";
+pub const XUDT: &str = "xudt";
+pub const ANYONE_CAN_PAY: &str = "anyone_can_pay";
+pub const CHEQUE: &str = "cheque";
+pub const OMNILOCK: &str = "omnilock";
+pub const TYPE_ID: &str = "type_id";
+
+/// The cell kinds of Nervos DAO cells, refined by the cell data
+pub const NERVOS_DAO_DEPOSIT: &str = "nervos_dao_deposit";
+pub const NERVOS_DAO_WITHDRAWING: &str = "nervos_dao_withdrawing";
+
+// [ (kind, code hash, hash type) ], deployed by the genesis blocks, the same on every chain
+const GENESIS_SCRIPTS: &[(&str, &str, &str)] = &[
+    (
+        SIGHASH,
+        "0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8",
+        "type",
+    ),
+    (
+        MULTISIG,
+        "0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8",
+        "type",
+    ),
+    (
+        NERVOS_DAO,
+        "0x82d76d1b75fe2fd9a27dfbaa65a039221a380d76c926f378d3f81cf3e7e13f2e",
+        "type",
+    ),
+    (
+        TYPE_ID,
+        "0x00000000000000000000000000000000000000000000000000545950455f4944",
+        "type",
+    ),
+];
+
+const MAINNET_SCRIPTS: &[(&str, &str, &str)] = &[
+    (
+        SUDT,
+        "0x5e7a36a77e68eecc013dfa2fe6a23f3b6c344b04005808694ae6dd45eea4cfd5",
+        "type",
+    ),
+    (
+        XUDT,
+        "0x50bd8d6680b8b9cf98b73f3c08faf8b2a21914311954118ad6609be6e78a1b95",
+        "data1",
+    ),
+    (
+        ANYONE_CAN_PAY,
+        "0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354",
+        "type",
+    ),
+    (
+        CHEQUE,
+        "0xe4d4ecc6e5f9a059bf2f7a82cca292083aebc0c421566a52484fe2ec51a9fb0c",
+        "type",
+    ),
+    (
+        OMNILOCK,
+        "0x9b819793a64463aed77c615d6cb226eea5487ccfc0783043a587254cda2b6f26",
+        "type",
+    ),
+];
+
+const TESTNET_SCRIPTS: &[(&str, &str, &str)] = &[
+    (
+        SUDT,
+        "0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4",
+        "type",
+    ),
+    (
+        XUDT,
+        "0x25c29dc317811a6f6f3985a7a9ebc4838bd388d19d0feeecf0bcd60f6c0975bb",
+        "type",
+    ),
+    (
+        ANYONE_CAN_PAY,
+        "0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356",
+        "type",
+    ),
+    (
+        CHEQUE,
+        "0x60d5f39efce409c587cb9ea359cefdead650ca128f0bd9cb3855348f98c70d5b",
+        "type",
+    ),
+    (
+        OMNILOCK,
+        "0xf329effd1c475a2978453c8600e1eaf0bc2087ee093c3ee64cc96ec6847752cb",
+        "type",
+    ),
+];
+
+lazy_static! {
+    static ref CONFIGURED_SCRIPTS: RwLock<HashMap<String, Vec<ScriptConfig>>> =
+        RwLock::new(HashMap::new());
+    static ref SCRIPT_CATALOGUES: Mutex<HashMap<String, Arc<ScriptCatalogue>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Set the scripts of config "scripts", keyed by chain id. It takes effect only if called before
+/// the first lookup.
+pub fn set_configured_scripts(scripts: HashMap<String, Vec<ScriptConfig>>) {
+    if let Ok(mut configured) = CONFIGURED_SCRIPTS.write() {
+        *configured = scripts
+            .into_iter()
+            .map(|(chain_id, scripts)| (schema_name(&chain_id), scripts))
+            .collect();
+    }
+}
+
+/// Return the script catalogue of the network, see [`crate::migration::schema_name`]
+pub fn script_catalogue(network: &str) -> Arc<ScriptCatalogue> {
+    let mut catalogues = SCRIPT_CATALOGUES.lock().expect("lock script catalogues");
+    let catalogue = catalogues.entry(network.to_string()).or_insert_with(|| {
+        let configured = CONFIGURED_SCRIPTS
+            .read()
+            .ok()
+            .and_then(|configured| configured.get(network).cloned())
+            .unwrap_or_default();
+        Arc::new(ScriptCatalogue::new(network, &configured))
+    });
+    Arc::clone(catalogue)
+}
+
+/// Parse the hash type of config "scripts"
+pub fn parse_hash_type(hash_type: &str) -> Option<u8> {
+    match hash_type {
+        "data" => Some(0),
+        "type" => Some(1),
+        "data1" => Some(2),
+        _ => None,
+    }
+}
+
+/// ScriptCatalogue tells the kinds of the well-known scripts, matched by code hash and hash type.
+///
+/// The built-in scripts of "ckb" and "ckb_testnet" are overridden by the scripts configured for
+/// the chain. The other chains only know the scripts deployed by the genesis block, unless
+/// configured.
+pub struct ScriptCatalogue {
+    // #{ (code hash, hash type) => kind }
+    kinds: HashMap<(String, u8), String>,
+}
+
+impl ScriptCatalogue {
+    pub fn new(network: &str, configured: &[ScriptConfig]) -> Self {
+        let builtin = match network {
+            "ckb" => MAINNET_SCRIPTS,
+            "ckb_testnet" => TESTNET_SCRIPTS,
+            _ => &[],
+        };
+        let mut kinds = HashMap::new();
+        for (kind, code_hash, hash_type) in GENESIS_SCRIPTS.iter().chain(builtin.iter()) {
+            let hash_type = parse_hash_type(hash_type).expect("built-in hash type");
+            kinds.insert((code_hash.to_string(), hash_type), kind.to_string());
+        }
+        for script in configured {
+            let hash_type = parse_hash_type(&script.hash_type).unwrap_or_else(|| {
+                panic!(
+                    "Invalid hash_type \"{}\" of script \"{}\"",
+                    script.hash_type, script.kind
+                )
+            });
+            kinds.insert(
+                (script.code_hash.to_lowercase(), hash_type),
+                script.kind.clone(),
+            );
+        }
+        Self { kinds }
+    }
+
+    /// Return the kind of the script, `None` if it is not well-known
+    pub fn script_kind(&self, script: &packed::Script) -> Option<&str> {
+        let hash_type: ScriptHashType = script.hash_type().try_into().ok()?;
+        self.kinds
+            .get(&(format!("{:#x}", script.code_hash()), hash_type.into()))
+            .map(String::as_str)
+    }
+
+    /// Return the kind of the cell, the kind of its type script, or the kind of its lock script
+    /// if the type script is absent or not well-known. Nervos DAO cells are told apart by the
+    /// data, deposits are filled with 8 zero bytes.
+    pub fn cell_kind(&self, output: &packed::CellOutput, data: &[u8]) -> Option<&str> {
+        match output.type_().to_opt() {
+            Some(script) => match self.script_kind(&script) {
+                Some(NERVOS_DAO) if data == &[0u8; 8][..] => Some(NERVOS_DAO_DEPOSIT),
+                Some(NERVOS_DAO) => Some(NERVOS_DAO_WITHDRAWING),
+                Some(kind) => Some(kind),
+                None => self.script_kind(&output.lock()),
+            },
+            None => self.script_kind(&output.lock()),
+        }
+    }
+
+    /// Return the kinds of the lock and type scripts of the transaction outputs, sorted and
+    /// separated by commas, e.g. "nervos_dao_deposit,sighash"
+    pub fn transaction_kinds(&self, tx: &TransactionView) -> String {
+        let mut kinds = BTreeSet::new();
+        for (output, data) in tx.outputs_with_data_iter() {
+            kinds.extend(self.script_kind(&output.lock()));
+            if output.type_().is_some() {
+                kinds.extend(self.cell_kind(&output, &data));
+            }
+        }
+        kinds.into_iter().collect::<Vec<_>>().join(",")
+    }
+}
+
+#[test]
+fn test_script_catalogue() {
+    use crate::ckb_types::h256;
+
+    let script = |code_hash: packed::Byte32, hash_type: ScriptHashType| {
+        packed::Script::new_builder()
+            .code_hash(code_hash)
+            .hash_type(hash_type.into())
+            .build()
+    };
+    let sighash = script(
+        h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack(),
+        ScriptHashType::Type,
+    );
+    let dao = script(
+        h256!("0x82d76d1b75fe2fd9a27dfbaa65a039221a380d76c926f378d3f81cf3e7e13f2e").pack(),
+        ScriptHashType::Type,
+    );
+    let sudt = script(
+        h256!("0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4").pack(),
+        ScriptHashType::Type,
+    );
+
+    let testnet = ScriptCatalogue::new("ckb_testnet", &[]);
+    assert_eq!(testnet.script_kind(&sighash), Some(SIGHASH));
+    assert_eq!(testnet.script_kind(&sudt), Some(SUDT));
+    let data_sighash = script(sighash.code_hash(), ScriptHashType::Data);
+    assert_eq!(testnet.script_kind(&data_sighash), None);
+
+    let output = |type_: Option<packed::Script>| {
+        packed::CellOutput::new_builder()
+            .lock(sighash.clone())
+            .type_(type_.pack())
+            .build()
+    };
+    assert_eq!(testnet.cell_kind(&output(None), &[]), Some(SIGHASH));
+    assert_eq!(
+        testnet.cell_kind(&output(Some(dao.clone())), &[0u8; 8]),
+        Some(NERVOS_DAO_DEPOSIT)
+    );
+    assert_eq!(
+        testnet.cell_kind(&output(Some(dao)), &1u64.to_le_bytes()),
+        Some(NERVOS_DAO_WITHDRAWING)
+    );
+
+    // sUDT of testnet is unknown on dev chains, unless configured
+    let dev = ScriptCatalogue::new("ckb_dev", &[]);
+    assert_eq!(
+        dev.cell_kind(&output(Some(sudt.clone())), &[]),
+        Some(SIGHASH)
+    );
+    let configured = ScriptConfig {
+        kind: SUDT.to_string(),
+        code_hash: format!("{:#x}", sudt.code_hash()),
+        hash_type: "type".to_string(),
+    };
+    let dev = ScriptCatalogue::new("ckb_dev", &[configured]);
+    assert_eq!(dev.cell_kind(&output(Some(sudt)), &[]), Some(SUDT));
+}
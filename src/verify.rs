@@ -0,0 +1,411 @@
+//! Verify the stored block and epoch tables against the chain.
+//!
+//! A table is checked for the missing numbers between its first and last stored numbers, or the
+//! range given via `--from` and `--to`. The `block` table is also checked for the rows whose hash
+//! is no longer on the canonical chain.
+
+use crate::ckb_types::core::BlockNumber;
+use crate::migration;
+use crate::sqlite::Sqlite;
+use crate::util::jsonrpc::BatchRpcClient;
+use std::fmt;
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+// The number of stored hashes compared per query
+const HASH_CHUNK_SIZE: u64 = 1000;
+
+/// A table written by a topic, keyed by the block or epoch number `column`
+pub struct CheckedTable {
+    pub topic: &'static str,
+    pub table: &'static str,
+    pub column: &'static str,
+    /// The other tables written by the topic, with their columns of the block number
+    pub dependents: &'static [(&'static str, &'static str)],
+    /// Whether `column` is an epoch number rather than a block number
+    pub is_epoch: bool,
+}
+
+pub const CHECKED_TABLES: &[CheckedTable] = &[
+    CheckedTable {
+        topic: "ChainCrawler",
+        table: "block",
+        column: "number",
+        dependents: &[("uncle", "block_number")],
+        is_epoch: false,
+    },
+    CheckedTable {
+        topic: "ChainTransactionCrawler",
+        table: "block_transaction",
+        column: "number",
+        dependents: &[],
+        is_epoch: false,
+    },
+    CheckedTable {
+        topic: "CellCrawler",
+        table: "created_cell",
+        column: "block_number",
+        dependents: &[("spent_cell", "block_number")],
+        is_epoch: false,
+    },
+    CheckedTable {
+        topic: "EpochCrawler",
+        table: "epoch",
+        column: "number",
+        dependents: &[],
+        is_epoch: true,
+    },
+];
+
+/// The tables of the entries settled across blocks, with their columns of the settling block
+/// number, see [`crate::topic::Settlements`]. They are deleted and settled again over the
+/// repaired blocks, rather than re-crawled.
+pub const SETTLED_TABLES: &[(&str, &str)] =
+    &[("proposal", "settled_number"), ("block_reward", "number")];
+
+/// The database a table is stored in
+#[derive(Clone)]
+pub enum Store {
+    Postgres(Arc<Client>),
+    Sqlite(Arc<Sqlite>),
+}
+
+/// The verification result of a table
+pub struct Report {
+    pub table: &'static str,
+    /// The verified range, `None` if the table is empty
+    pub range: Option<(u64, u64)>,
+    /// The missing ranges, both ends inclusive
+    pub gaps: Vec<(u64, u64)>,
+    /// The block numbers whose stored hash is not on the canonical chain
+    pub mismatched: Vec<BlockNumber>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.gaps.is_empty() && self.mismatched.is_empty()
+    }
+
+    pub fn n_missing(&self) -> u64 {
+        self.gaps.iter().map(|(from, to)| to - from + 1).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (from, to) = match self.range {
+            None => return write!(f, "{}: empty", self.table),
+            Some(range) => range,
+        };
+        write!(
+            f,
+            "{}: [{}, {}], {} missing in {} gaps, {} mismatched hashes",
+            self.table,
+            from,
+            to,
+            self.n_missing(),
+            self.gaps.len(),
+            self.mismatched.len()
+        )?;
+        for (from, to) in self.gaps.iter() {
+            write!(f, "\n  missing [{}, {}]", from, to)?;
+        }
+        for number in self.mismatched.iter() {
+            write!(f, "\n  mismatched hash #{}", number)?;
+        }
+        Ok(())
+    }
+}
+
+impl Store {
+    /// Check `table` for the missing numbers within `[from, to]`. The bounds default to the
+    /// first and last stored numbers.
+    pub async fn find_gaps(
+        &self,
+        network: &str,
+        table: &CheckedTable,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<Report, migration::Error> {
+        let stored = self
+            .query_range(&format!(
+                "SELECT MIN({column}), MAX({column}) FROM {network}.{table}",
+                column = table.column,
+                network = network,
+                table = table.table,
+            ))
+            .await?;
+        let range = match (stored, from, to) {
+            (Some((min, max)), from, to) => Some((from.unwrap_or(min), to.unwrap_or(max))),
+            (None, Some(from), Some(to)) => Some((from, to)),
+            (None, _, _) => None,
+        };
+        let gaps = match range {
+            Some((from, to)) if from <= to => {
+                // Bound the stored numbers with `from - 1` and `to + 1`, so that the missing head
+                // and tail are found as well
+                self.query_pairs(&format!(
+                    "SELECT prev + 1, number - 1 FROM ( \
+                        SELECT number, LAG(number) OVER (ORDER BY number) AS prev FROM ( \
+                            SELECT {column} AS number FROM {network}.{table} \
+                            WHERE {column} BETWEEN {from} AND {to} \
+                            UNION SELECT {head} UNION SELECT {tail} \
+                        ) AS numbers \
+                    ) AS pairs WHERE number > prev + 1 ORDER BY number",
+                    column = table.column,
+                    network = network,
+                    table = table.table,
+                    from = from,
+                    to = to,
+                    head = from as i64 - 1,
+                    tail = to + 1,
+                ))
+                .await?
+            }
+            _ => Vec::new(),
+        };
+        Ok(Report {
+            table: table.table,
+            range,
+            gaps,
+            mismatched: Vec::new(),
+        })
+    }
+
+    /// Return the block numbers within `[from, to]` whose stored hash is not on the canonical
+    /// chain
+    pub async fn find_mismatched_blocks(
+        &self,
+        network: &str,
+        rpc: &BatchRpcClient,
+        batch_size: usize,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockNumber>, String> {
+        let mut mismatched = Vec::new();
+        let mut start = from;
+        while start <= to {
+            let end = ::std::cmp::min(to, start + HASH_CHUNK_SIZE - 1);
+            let stored = self
+                .block_hashes(network, start, end)
+                .await
+                .map_err(|err| format!("query stored hashes, error: {}", err))?;
+            for batch in stored.chunks(batch_size) {
+                let numbers = batch.iter().map(|(number, _)| *number).collect::<Vec<_>>();
+                let canonical = rpc
+                    .get_block_hashes(&numbers)
+                    .await
+                    .map_err(|err| format!("fetch canonical hashes, error: {}", err))?;
+                for ((number, hash), canonical) in batch.iter().zip(canonical) {
+                    // The blocks crawled by old versions may have no hash, they are crawled again
+                    if hash.is_none()
+                        || canonical.map(|canonical| format!("{:#x}", canonical)) != *hash
+                    {
+                        mismatched.push(*number);
+                    }
+                }
+            }
+            log::info!("Verified the hashes of blocks #{} to #{}", start, end);
+            start = end + 1;
+        }
+        Ok(mismatched)
+    }
+
+    /// Delete the rows of `numbers` from `table` and its dependents
+    pub async fn delete_rows(
+        &self,
+        network: &str,
+        table: &CheckedTable,
+        numbers: &[u64],
+    ) -> Result<(), migration::Error> {
+        for chunk in numbers.chunks(HASH_CHUNK_SIZE as usize) {
+            let numbers = chunk
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            for (name, column) in
+                ::std::iter::once(&(table.table, table.column)).chain(table.dependents.iter())
+            {
+                self.execute(&format!(
+                    "DELETE FROM {}.{} WHERE {} IN ({})",
+                    network, name, column, numbers
+                ))
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the rows of `table` whose `column` is within `[from, to]`
+    pub async fn delete_range(
+        &self,
+        network: &str,
+        table: &str,
+        column: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<(), migration::Error> {
+        self.execute(&format!(
+            "DELETE FROM {}.{} WHERE {} BETWEEN {} AND {}",
+            network, table, column, from, to
+        ))
+        .await
+    }
+
+    /// Return the stored hashes of the blocks within `[from, to]`, ordered by number. The hash is
+    /// `None` for the blocks crawled by old versions.
+    pub async fn block_hashes(
+        &self,
+        network: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<(BlockNumber, Option<String>)>, migration::Error> {
+        self.query_hashes(&format!(
+            "SELECT number, hash FROM {}.block WHERE number BETWEEN {} AND {} ORDER BY number",
+            network, from, to
+        ))
+        .await
+    }
+
+    /// Return the total uncles of the stored blocks within `[from, to]`, `None` unless all of them
+    /// are stored
+    pub async fn sum_uncles(
+        &self,
+        network: &str,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Option<u64>, migration::Error> {
+        let query = format!(
+            "SELECT COUNT(*), SUM(n_uncles) FROM {}.block WHERE number BETWEEN {} AND {}",
+            network, from, to
+        );
+        match self.query_range(&query).await? {
+            Some((n_blocks, n_uncles)) if n_blocks == to - from + 1 => Ok(Some(n_uncles)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn query_range(&self, query: &str) -> Result<Option<(u64, u64)>, migration::Error> {
+        let range: (Option<i64>, Option<i64>) = match self {
+            Store::Postgres(client) => {
+                let row = client.query_one(query, &[]).await?;
+                (row.get(0), row.get(1))
+            }
+            Store::Sqlite(sqlite) => sqlite
+                .connection()
+                .query_row(query, [], |row| Ok((row.get(0)?, row.get(1)?)))?,
+        };
+        match range {
+            (Some(min), Some(max)) => Ok(Some((min as u64, max as u64))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn query_pairs(&self, query: &str) -> Result<Vec<(u64, u64)>, migration::Error> {
+        let pairs: Vec<(i64, i64)> = match self {
+            Store::Postgres(client) => client
+                .query(query, &[])
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect(),
+            Store::Sqlite(sqlite) => {
+                let conn = sqlite.connection();
+                let mut statement = conn.prepare(query)?;
+                let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<_, _>>()?
+            }
+        };
+        Ok(pairs
+            .into_iter()
+            .map(|(from, to)| (from as u64, to as u64))
+            .collect())
+    }
+
+    async fn query_hashes(
+        &self,
+        query: &str,
+    ) -> Result<Vec<(u64, Option<String>)>, migration::Error> {
+        let hashes: Vec<(i64, Option<String>)> = match self {
+            Store::Postgres(client) => client
+                .query(query, &[])
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect(),
+            Store::Sqlite(sqlite) => {
+                let conn = sqlite.connection();
+                let mut statement = conn.prepare(query)?;
+                let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<_, _>>()?
+            }
+        };
+        Ok(hashes
+            .into_iter()
+            .map(|(number, hash)| (number as u64, hash))
+            .collect())
+    }
+
+    async fn execute(&self, statement: &str) -> Result<(), migration::Error> {
+        match self {
+            Store::Postgres(client) => {
+                client.batch_execute(statement).await?;
+            }
+            Store::Sqlite(sqlite) => {
+                sqlite.connection().execute_batch(statement)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_find_gaps() {
+    use futures::FutureExt;
+
+    let path =
+        ::std::env::temp_dir().join(format!("ckb-analyzer-verify-{}.sqlite", std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let sqlite = Arc::new(Sqlite::open(&path, "ckb_dev", "ckb_dev").unwrap());
+    for number in [3, 4, 5, 8, 9, 12].iter() {
+        sqlite
+            .connection()
+            .execute(
+                "INSERT INTO ckb_dev.epoch(start_time, end_time, number, length, start_number, n_uncles, difficulty) \
+                VALUES ('', '', ?1, 0, 0, 0, '0')",
+                [number],
+            )
+            .unwrap();
+    }
+    let store = Store::Sqlite(Arc::clone(&sqlite));
+    let epoch = &CHECKED_TABLES[3];
+    let report = store
+        .find_gaps("ckb_dev", epoch, None, None)
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.range, Some((3, 12)));
+    assert_eq!(report.gaps, vec![(6, 7), (10, 11)]);
+    assert_eq!(report.n_missing(), 4);
+
+    let report = store
+        .find_gaps("ckb_dev", epoch, Some(1), Some(14))
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.gaps, vec![(1, 2), (6, 7), (10, 11), (13, 14)]);
+
+    store
+        .delete_rows("ckb_dev", epoch, &[3, 4])
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+    let report = store
+        .find_gaps("ckb_dev", epoch, Some(1), None)
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.gaps, vec![(1, 4), (6, 7), (10, 11)]);
+    let _ = ::std::fs::remove_file(&path);
+}